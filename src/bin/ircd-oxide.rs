@@ -5,6 +5,9 @@ extern crate log;
 
 use futures::Stream;
 
+/// The most clients that can be registered at once
+const MAX_CLIENTS: usize = 1024;
+
 mod logger {
     use log;
 
@@ -41,7 +44,10 @@ fn main() {
     let handle = core.handle();
     let addr = "127.0.0.1:6667".parse().unwrap();
     let port = tokio_core::net::TcpListener::bind(&addr, &handle).expect("failed to create listener");
-    let world = oxide::world::World::new(&handle);
-    let listener = oxide::irc::listener::Listener::new(&handle, world, port.incoming().map(|x| x.0));
+    let mut world = oxide::world::World::new(&handle, oxide::common::Sid::new("001"));
+    let pool = oxide::irc::pool::Pool::with_limit(MAX_CLIENTS);
+    pool.bind(&handle, &mut world);
+    let incoming = port.incoming().map(|x| x.0);
+    let listener = oxide::irc::listener::Listener::with_pool(&handle, world, pool, incoming);
     core.run(listener).expect("event loop exited");
 }
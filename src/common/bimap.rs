@@ -15,29 +15,28 @@ use std;
 use std::collections::HashMap;
 use std::hash::Hash;
 
-type Ti = u32;
 type Ai = u32;
 type Bi = u32;
 static NOTHING: [u32; 0] = [];
 
+// Keys are interned to small integers so that the pair table and the per-key pair lists don't
+// need their own copies of the keys. Values live directly in the pair table, so a lookup is one
+// hash per key plus one for the pair.
 pub struct Bimap<A: Eq + Hash, B: Eq + Hash, T> {
     aa: HashMap<A, Ai>,
     bb: HashMap<B, Bi>,
-    tt: HashMap<Ti, T>,
 
     apair: HashMap<Ai, Vec<Bi>>,
     bpair: HashMap<Bi, Vec<Ai>>,
-    pairs: HashMap<(Ai, Bi), Ti>,
+    pairs: HashMap<(Ai, Bi), T>,
 
     na: Ai,
     nb: Bi,
-    nt: Ti,
 }
 
 impl<A: Eq + Hash, B: Eq + Hash, T> Bimap<A, B, T> {
     pub fn new() -> Bimap<A, B, T> {
         Bimap {
-            tt: HashMap::new(),
             aa: HashMap::new(),
             bb: HashMap::new(),
 
@@ -47,7 +46,6 @@ impl<A: Eq + Hash, B: Eq + Hash, T> Bimap<A, B, T> {
 
             na: 0,
             nb: 0,
-            nt: 0,
         }
     }
 
@@ -56,44 +54,84 @@ impl<A: Eq + Hash, B: Eq + Hash, T> Bimap<A, B, T> {
         let na = &mut self.na;
         let nb = &mut self.nb;
 
-        let ai = self.aa.entry(a).or_insert_with(||{ *na += 1; *na });
-        let bi = self.bb.entry(b).or_insert_with(||{ *nb += 1; *nb });
+        let ai = *self.aa.entry(a).or_insert_with(||{ *na += 1; *na });
+        let bi = *self.bb.entry(b).or_insert_with(||{ *nb += 1; *nb });
 
-        let ti = { self.nt += 1; self.nt };
-
-        let apair = self.apair.entry(*ai).or_insert_with(|| Vec::new());
-        let bpair = self.bpair.entry(*bi).or_insert_with(|| Vec::new());
-
-        apair.push(*bi);
-        bpair.push(*ai);
-
-        self.pairs.insert((*ai, *bi), ti);
+        if !self.pairs.contains_key(&(ai, bi)) {
+            self.apair.entry(ai).or_insert_with(|| Vec::new()).push(bi);
+            self.bpair.entry(bi).or_insert_with(|| Vec::new()).push(ai);
+        }
 
-        self.tt.insert(ti, t);
-        self.tt.get_mut(&ti).unwrap()
+        self.pairs.insert((ai, bi), t);
+        self.pairs.get_mut(&(ai, bi)).unwrap()
     }
 
     // m(a, b)
     pub fn get(&self, a: &A, b: &B) -> Option<&T> {
-        let ai = self.aa.get(a).cloned().unwrap_or(0);
-        let bi = self.bb.get(b).cloned().unwrap_or(0);
-
-        if ai == 0 || bi == 0 {
-            None
-        } else {
-            self.tt.get(self.pairs.get(&(ai, bi)).unwrap_or(&0))
+        match (self.aa.get(a), self.bb.get(b)) {
+            (Some(ai), Some(bi)) => self.pairs.get(&(*ai, *bi)),
+            _ => None,
         }
     }
 
     // m(a, b)
     pub fn get_mut(&mut self, a: &A, b: &B) -> Option<&mut T> {
-        let ai = self.aa.get(a).cloned().unwrap_or(0);
-        let bi = self.bb.get(b).cloned().unwrap_or(0);
+        match (self.aa.get(a), self.bb.get(b)) {
+            (Some(ai), Some(bi)) => self.pairs.get_mut(&(*ai, *bi)),
+            _ => None,
+        }
+    }
+
+    // Removes m(a, b), returning the t it mapped to. Keys with no remaining pairs are
+    // forgotten entirely. Their indices are never handed out again, so nothing can come to
+    // refer to the wrong key.
+    pub fn remove(&mut self, a: &A, b: &B) -> Option<T> {
+        let ai = match self.aa.get(a) { Some(ai) => *ai, None => return None };
+        let bi = match self.bb.get(b) { Some(bi) => *bi, None => return None };
+
+        let t = match self.pairs.remove(&(ai, bi)) {
+            Some(t) => t,
+            None => return None,
+        };
+
+        let a_empty = self.apair.get_mut(&ai).map(|bb| {
+            bb.retain(|x| *x != bi);
+            bb.is_empty()
+        }).unwrap_or(true);
+
+        let b_empty = self.bpair.get_mut(&bi).map(|aa| {
+            aa.retain(|x| *x != ai);
+            aa.is_empty()
+        }).unwrap_or(true);
+
+        if a_empty {
+            self.apair.remove(&ai);
+            self.aa.remove(a);
+        }
+
+        if b_empty {
+            self.bpair.remove(&bi);
+            self.bb.remove(b);
+        }
 
-        if ai == 0 || bi == 0 {
-            None
-        } else {
-            self.tt.get_mut(self.pairs.get(&(ai, bi)).unwrap_or(&0))
+        Some(t)
+    }
+
+    // the number of pairs (a, b) for which m is defined
+    pub fn len(&self) -> usize {
+        self.pairs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pairs.is_empty()
+    }
+
+    // all (a, b, t) where m(a, b) = t, in no particular order
+    pub fn iter(&self) -> Iter<A, B, T> {
+        Iter {
+            aa: self.aa.iter().map(|(a, ai)| (*ai, a)).collect(),
+            bb: self.bb.iter().map(|(b, bi)| (*bi, b)).collect(),
+            pairs: self.pairs.iter(),
         }
     }
 
@@ -101,58 +139,72 @@ impl<A: Eq + Hash, B: Eq + Hash, T> Bimap<A, B, T> {
     pub fn all_a(&self, b: &B) -> AllA<T> {
         let bi = self.bb.get(b).cloned().unwrap_or(0);
         let iter = self.bpair.get(&bi).map(|v| v.iter()).unwrap_or(NOTHING.iter());
-        AllA::new(&self.pairs, &self.tt, bi, iter)
+        AllA::new(&self.pairs, bi, iter)
     }
 
     // all t where \exists b such that m(a, b) = t
     pub fn all_b(&self, a: &A) -> AllB<T> {
         let ai = self.aa.get(a).cloned().unwrap_or(0);
         let iter = self.apair.get(&ai).map(|v| v.iter()).unwrap_or(NOTHING.iter());
-        AllB::new(&self.pairs, &self.tt, ai, iter)
+        AllB::new(&self.pairs, ai, iter)
     }
 
     // any t where \exists a such that m(a, b) = t
     pub fn any_a(&self, b: &B) -> Option<&T> {
         let pairs = &self.pairs;
-        let tt = &self.tt;
 
         let bi = self.bb.get(b).cloned().unwrap_or(0);
 
         self.bpair.get(&bi)
             .and_then(|aa| aa.first())
             .and_then(|ai| pairs.get(&(*ai, bi)))
-            .and_then(|ti| tt.get(ti))
     }
 
     // any t where \exists b such that m(a, b) = t
     pub fn any_b(&self, a: &A) -> Option<&T> {
         let pairs = &self.pairs;
-        let tt = &self.tt;
 
         let ai = self.aa.get(a).cloned().unwrap_or(0);
 
         self.apair.get(&ai)
             .and_then(|bb| bb.first())
             .and_then(|bi| pairs.get(&(ai, *bi)))
-            .and_then(|ti| tt.get(ti))
+    }
+}
+
+pub struct Iter<'m, A: 'm, B: 'm, T: 'static> {
+    aa: HashMap<Ai, &'m A>,
+    bb: HashMap<Bi, &'m B>,
+    pairs: std::collections::hash_map::Iter<'m, (Ai, Bi), T>,
+}
+
+impl<'m, A, B, T> Iterator for Iter<'m, A, B, T> {
+    type Item = (&'m A, &'m B, &'m T);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for (&(ai, bi), t) in self.pairs.by_ref() {
+            match (self.aa.get(&ai), self.bb.get(&bi)) {
+                (Some(a), Some(b)) => return Some((*a, *b, t)),
+                _ => continue,
+            }
+        }
+        None
     }
 }
 
 pub struct AllA<'m, T: 'static> {
-    pairs: &'m HashMap<(Ai, Bi), Ti>,
-    tt: &'m HashMap<Ti, T>,
+    pairs: &'m HashMap<(Ai, Bi), T>,
     bi: Bi,
     iter: std::slice::Iter<'m, Ai>,
 }
 
 impl<'m, T> AllA<'m, T> {
     fn new(
-        pairs: &'m HashMap<(Ai, Bi), Ti>,
-        tt: &'m HashMap<Ti, T>,
+        pairs: &'m HashMap<(Ai, Bi), T>,
         bi: Bi,
         iter: std::slice::Iter<'m, Ai>,
     ) -> AllA<'m, T> {
-        AllA { pairs: pairs, tt: tt, bi: bi, iter: iter }
+        AllA { pairs: pairs, bi: bi, iter: iter }
     }
 }
 
@@ -161,29 +213,25 @@ impl<'m, T> Iterator for AllA<'m, T> {
 
     fn next(&mut self) -> Option<Self::Item> {
         let pairs = self.pairs;
-        let tt = self.tt;
         let bi = self.bi;
         self.iter.next()
             .and_then(|ai| pairs.get(&(*ai, bi)))
-            .and_then(|ti| tt.get(ti))
     }
 }
 
 pub struct AllB<'m, T: 'static> {
-    pairs: &'m HashMap<(Ai, Bi), Ti>,
-    tt: &'m HashMap<Ti, T>,
+    pairs: &'m HashMap<(Ai, Bi), T>,
     ai: Ai,
     iter: std::slice::Iter<'m, Bi>,
 }
 
 impl<'m, T> AllB<'m, T> {
     fn new(
-        pairs: &'m HashMap<(Ai, Bi), Ti>,
-        tt: &'m HashMap<Ti, T>,
+        pairs: &'m HashMap<(Ai, Bi), T>,
         ai: Ai,
         iter: std::slice::Iter<'m, Bi>,
     ) -> AllB<'m, T> {
-        AllB { pairs: pairs, tt: tt, ai: ai, iter: iter }
+        AllB { pairs: pairs, ai: ai, iter: iter }
     }
 }
 
@@ -192,11 +240,9 @@ impl<'m, T> Iterator for AllB<'m, T> {
 
     fn next(&mut self) -> Option<Self::Item> {
         let pairs = self.pairs;
-        let tt = self.tt;
         let ai = self.ai;
         self.iter.next()
             .and_then(|bi| pairs.get(&(ai, *bi)))
-            .and_then(|ti| tt.get(ti))
     }
 }
 
@@ -231,3 +277,65 @@ fn test_bimap_iters() {
     assert_eq!(btt.get(1), Some(&&15));
     assert_eq!(btt.get(2), None);
 }
+
+#[test]
+fn test_bimap_remove() {
+    let mut m: Bimap<u16, u32, u64> = Bimap::new();
+
+    m.insert(5, 4, 20);
+    m.insert(3, 4, 12);
+    m.insert(3, 5, 15);
+
+    assert_eq!(m.remove(&3, &4), Some(12));
+    assert_eq!(m.remove(&3, &4), None);
+    assert_eq!(m.remove(&7, &4), None);
+
+    assert_eq!(m.get(&3, &4), None);
+    assert_eq!(m.get(&5, &4), Some(&20));
+    assert_eq!(m.get(&3, &5), Some(&15));
+
+    assert_eq!(m.all_a(&4).collect::<Vec<_>>(), vec![&20]);
+    assert_eq!(m.all_b(&3).collect::<Vec<_>>(), vec![&15]);
+
+    assert_eq!(m.remove(&3, &5), Some(15));
+    assert!(!m.aa.contains_key(&3));
+    assert!(!m.bb.contains_key(&5));
+    assert_eq!(m.all_b(&3).next(), None);
+    assert_eq!(m.any_a(&5), None);
+
+    m.insert(3, 5, 16);
+    assert_eq!(m.get(&3, &5), Some(&16));
+    assert_eq!(m.all_a(&4).collect::<Vec<_>>(), vec![&20]);
+}
+
+#[test]
+fn test_bimap_iter() {
+    let mut m: Bimap<u16, u32, u64> = Bimap::new();
+    assert!(m.is_empty());
+
+    m.insert(5, 4, 20);
+    m.insert(3, 4, 12);
+    m.insert(3, 5, 15);
+    assert_eq!(m.len(), 3);
+
+    let mut all: Vec<(u16, u32, u64)> = m.iter().map(|(a, b, t)| (*a, *b, *t)).collect();
+    all.sort();
+    assert_eq!(all, vec![(3, 4, 12), (3, 5, 15), (5, 4, 20)]);
+}
+
+#[test]
+fn test_bimap_reinsert() {
+    let mut m: Bimap<u16, u32, u64> = Bimap::new();
+
+    m.insert(3, 4, 12);
+    *m.insert(3, 4, 13) += 1;
+
+    assert_eq!(m.len(), 1);
+    assert_eq!(m.get(&3, &4), Some(&14));
+    assert_eq!(m.all_a(&4).collect::<Vec<_>>(), vec![&14]);
+    assert_eq!(m.all_b(&3).collect::<Vec<_>>(), vec![&14]);
+
+    *m.get_mut(&3, &4).unwrap() = 15;
+    assert_eq!(m.any_a(&4), Some(&15));
+    assert_eq!(m.any_b(&3), Some(&15));
+}
@@ -8,11 +8,69 @@ pub struct ByteRing {
     head: usize,
     tail: usize,
     buf: Vec<u8>,
+    limit: usize,
 }
 
 impl ByteRing {
     pub fn with_capacity(n: usize) -> ByteRing {
-        ByteRing { head: 0, tail: 0, buf: vec![0; n] }
+        ByteRing::with_limit(n, n)
+    }
+
+    /// Creates a ring with an `n` byte buffer, which `reserve` can grow to at most `limit`
+    /// bytes.
+    pub fn with_limit(n: usize, limit: usize) -> ByteRing {
+        ByteRing { head: 0, tail: 0, buf: vec![0; n], limit: limit }
+    }
+
+    /// The size the underlying buffer is allowed to grow to
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Makes room for `n` more bytes, growing the buffer if needed. Returns false, leaving the
+    /// ring as it was, if that would take more than the limit.
+    pub fn reserve(&mut self, n: usize) -> bool {
+        let needed = self.remaining() + n;
+        if needed <= self.capacity() {
+            return true;
+        }
+        if needed >= self.limit {
+            return false;
+        }
+
+        let mut size = self.buf.len();
+        while size <= needed {
+            size *= 2;
+        }
+        let size = if size < self.limit { size } else { self.limit };
+        self.resize(size);
+        true
+    }
+
+    /// The number of bytes the ring can hold. One byte of the underlying buffer is always kept
+    /// free to tell a full ring from an empty one.
+    pub fn capacity(&self) -> usize {
+        self.buf.len() - 1
+    }
+
+    /// Moves the contents of the ring into a new buffer of `n` bytes. Panics if the contents
+    /// would not fit.
+    pub fn resize(&mut self, n: usize) {
+        let len = self.remaining();
+        assert!(len < n);
+
+        let mut buf = Vec::with_capacity(n);
+        if self.head >= self.tail {
+            buf.extend_from_slice(&self.buf[self.tail..self.head]);
+        } else {
+            buf.extend_from_slice(&self.buf[self.tail..]);
+            buf.extend_from_slice(&self.buf[..self.head]);
+        }
+        buf.resize(n, 0);
+
+        self.buf = buf;
+        self.tail = 0;
+        self.head = len;
     }
 }
 
@@ -101,3 +159,37 @@ impl BufMut for ByteRing {
         }
     }
 }
+
+#[test]
+fn resize_wrapped() {
+    let mut ring = ByteRing::with_capacity(8);
+
+    ring.put(&b"abcdef"[..]);
+    ring.advance(4);
+    ring.put(&b"ghij"[..]);
+    assert!(ring.head < ring.tail);
+
+    ring.resize(16);
+    assert_eq!(ring.capacity(), 15);
+    assert_eq!(ring.remaining(), 6);
+    assert_eq!(ring.bytes(), b"efghij");
+
+    ring.put(&b"klmnopqrs"[..]);
+    assert_eq!(ring.bytes(), b"efghijklmnopqrs");
+}
+
+#[test]
+fn reserve_grows_to_limit() {
+    let mut ring = ByteRing::with_limit(8, 32);
+
+    assert!(ring.reserve(7));
+    assert_eq!(ring.capacity(), 7);
+
+    assert!(ring.reserve(20));
+    assert_eq!(ring.capacity(), 31);
+    ring.put(&[b'x'; 20][..]);
+
+    assert!(ring.reserve(11));
+    assert!(!ring.reserve(12));
+    assert_eq!(ring.remaining(), 20);
+}
@@ -0,0 +1,52 @@
+// common/casemap.rs -- IRC case mapping
+// Copyright (C) 2015 Alex Iadicicco <http://ajitek.net>
+//
+// This file is part of ircd-oxide and is protected under the terms contained in
+// the COPYING file in the project root.
+
+//! Case-insensitive comparison of nicknames and channel names.
+//!
+//! IRC uses the `rfc1459` case mapping, a holdover from the Scandinavian origins of the
+//! protocol, in which `{`, `}`, `|`, and `^` are the lowercase forms of `[`, `]`, `\`, and `~`,
+//! in addition to the usual ASCII letters. Anything that keys nicknames or channels should do so
+//! by the folded name, so that `#FOO` and `#foo` are the same channel.
+
+/// Folds a single byte to its `rfc1459` lowercase form
+pub fn lower_byte(b: u8) -> u8 {
+    match b {
+        b'A'..=b'Z' => b + (b'a' - b'A'),
+        b'[' => b'{',
+        b']' => b'}',
+        b'\\' => b'|',
+        b'~' => b'^',
+        _ => b,
+    }
+}
+
+/// Folds a name to its `rfc1459` lowercase form, for use as a key
+pub fn irc_lower(s: &str) -> String {
+    // only ASCII bytes are changed, so the result is still valid UTF-8
+    s.chars().map(|c| if c.is_ascii() { lower_byte(c as u8) as char } else { c }).collect()
+}
+
+/// Compares two names under the `rfc1459` case mapping
+pub fn irc_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).all(|(x, y)| lower_byte(x) == lower_byte(y))
+}
+
+#[test]
+fn test_irc_lower() {
+    assert_eq!(irc_lower("#FOO"), "#foo");
+    assert_eq!(irc_lower("Nick[]\\~"), "nick{}|^");
+    assert_eq!(irc_lower("nick{}|^"), "nick{}|^");
+    assert_eq!(irc_lower("caf\u{c9}"), "caf\u{c9}");
+}
+
+#[test]
+fn test_irc_eq() {
+    assert!(irc_eq("#FOO", "#foo"));
+    assert!(irc_eq("[Away]", "{away}"));
+    assert!(irc_eq("a\\b~", "A|B^"));
+    assert!(!irc_eq("#foo", "#foo_"));
+    assert!(!irc_eq("a_b", "a-b"));
+}
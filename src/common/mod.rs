@@ -8,6 +8,7 @@
 
 pub mod bimap;
 pub mod byte_ring;
+pub mod casemap;
 pub mod sid;
 pub mod observe;
 pub mod table;
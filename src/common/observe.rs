@@ -17,7 +17,41 @@
 //! In general, callers are free to do what they want with the returned `Completion`, including
 //! ignoring it completely. The `Completion` is simply for the caller's benefit, as there may
 //! be nearby code that is processing updates and the caller wants some additional code that is
-//! only run when the associated observer has finished processing the update.
+//! only run when the associated observer has finished processing the update. An update sent
+//! when there are no observers is consumed on the spot, and its `Completion` is already resolved.
+//!
+//! # Slow observers
+//!
+//! By default, updates queue up for an observer until it gets around to them, no matter how
+//! many there are. An `Observable` created with `bounded` instead limits each observer's queue
+//! to a fixed depth, discarding the oldest update when an observer falls too far behind. The
+//! discarded update counts as consumed by that observer, so a lagging observer can't hold up
+//! `Completion`s forever. Observers can check `take_dropped` to learn that they missed updates.
+//!
+//! Observers that only care about the most recent update can be created with
+//! `coalescing_observer`. Such an observer only ever has one update queued, and any update it
+//! hasn't gotten to yet is discarded when a newer one arrives.
+//!
+//! Producers can also slow themselves down to match their observers with `put_backpressured`,
+//! which returns a future that resolves once few enough updates are still being processed.
+//!
+//! For tests and debugging, `recording_observer` keeps a copy of every update, which can be read
+//! synchronously with `drain`. It never holds up a `Completion`.
+//!
+//! # Ending a stream
+//!
+//! An observer's stream ends once the `Observable` is gone and every queued update has been
+//! delivered. A producer that finishes normally should end the stream with `Observable::close`,
+//! after which `Observer::is_closed` returns true. If the `Observable` is simply dropped, such
+//! as when its owner is torn down partway through, the stream still ends but `is_closed` returns
+//! false.
+//!
+//! # Transforming updates
+//!
+//! `Observer::map_updates` and `Observer::filter_updates` adapt an observer without breaking
+//! completion. A mapped `Observation` carries the same completion signal as the one it came
+//! from, so the original `Completion` waits for the mapped value to be dropped. Updates rejected
+//! by a filter are consumed on the spot.
 //!
 //! # Example
 //!
@@ -73,6 +107,10 @@ use futures::task;
 struct Dispatch<T> {
     pending: VecDeque<Observation<T>>,
     parked: Option<task::Task>,
+    dropped: usize,
+    coalesce: bool,
+    recorded: Option<Vec<Rc<T>>>,
+    closed: bool,
 }
 
 type DispatchCell<T> = RefCell<Dispatch<T>>;
@@ -83,6 +121,8 @@ type DispatchCell<T> = RefCell<Dispatch<T>>;
 /// that they are submitted. See the module-level documentation for more information.
 pub struct Observable<T> {
     dispatch: Vec<Weak<DispatchCell<T>>>,
+    depth: Option<usize>,
+    pressure: Rc<RefCell<Pressure>>,
 }
 
 /// A `Stream` of updates from a given observable.
@@ -90,18 +130,60 @@ pub struct Observer<T> {
     dispatch: Rc<DispatchCell<T>>,
 }
 
+/// An observer that keeps a copy of every update, created by
+/// `Observable::recording_observer`.
+pub struct RecordingObserver<T> {
+    dispatch: Rc<DispatchCell<T>>,
+}
+
 struct Shared {
     parked: Option<task::Task>,
 }
 
+/// The updates from an `Observable` that have not been fully consumed. Updates are only tracked
+/// once something has asked for backpressure, so plain `put`s don't pay for it.
+struct Pressure {
+    tracking: bool,
+    signals: Vec<Weak<()>>,
+    parked: Vec<task::Task>,
+}
+
+impl Pressure {
+    fn new() -> Rc<RefCell<Pressure>> {
+        let pressure = Pressure { tracking: false, signals: Vec::new(), parked: Vec::new() };
+        Rc::new(RefCell::new(pressure))
+    }
+
+    /// Wakes every task waiting for the backlog to shrink.
+    fn release(&mut self) {
+        for task in self.parked.drain(..) {
+            task.unpark();
+        }
+    }
+
+    fn in_flight(&mut self) -> usize {
+        self.signals.retain(|s| s.upgrade().is_some());
+        self.signals.len()
+    }
+}
+
 /// A `Future` created on submission of an update. The future will complete when all
 /// observers have dropped the observation. See the module-level documentation for more
 /// informatmion.
 pub struct Completion {
-    shared: Rc<RefCell<Shared>>,
+    // absent when the update had no observers, and so was consumed before it was even sent
+    shared: Option<Rc<RefCell<Shared>>>,
     signal: Weak<()>,
 }
 
+/// A `Future` returned by `Observable::put_backpressured`, which resolves to the update's
+/// `Completion` once the `Observable` is no longer backlogged.
+pub struct Backpressure {
+    completion: Option<Completion>,
+    pressure: Rc<RefCell<Pressure>>,
+    limit: usize,
+}
+
 /// An update from an `Observable`.
 ///
 /// The contained data cannot be moved out of the `Observation`. If storing data from an update
@@ -114,6 +196,9 @@ pub struct Completion {
 /// returned to the caller as a result.
 pub struct Observation<T> {
     shared: Weak<RefCell<Shared>>,
+    // held here rather than in `Shared`, so that producers are woken even once the `Completion`
+    // has been dropped
+    pressure: Weak<RefCell<Pressure>>,
     signal: Rc<()>,
     data: Rc<T>,
 }
@@ -121,61 +206,157 @@ pub struct Observation<T> {
 impl<T: fmt::Debug> Observable<T> {
     /// Creates a new `Observable`
     pub fn new() -> Observable<T> {
-        Observable { dispatch: Vec::new() }
+        Observable { dispatch: Vec::new(), depth: None, pressure: Pressure::new() }
+    }
+
+    /// Creates a new `Observable` that queues at most `depth` updates for each observer. See
+    /// the module-level documentation for more information.
+    pub fn bounded(depth: usize) -> Observable<T> {
+        Observable { dispatch: Vec::new(), depth: Some(depth), pressure: Pressure::new() }
     }
 
     /// Broadcasts an item to all observers. The returned `Completion` will be resolved when
-    /// all observers have dropped the resulting `Observation`.
+    /// all observers have dropped the resulting `Observation`. If there are no observers, the
+    /// update is dropped on the spot and the `Completion` is already resolved.
     pub fn put(&mut self, data: T) -> Completion {
-        let shared_inner = Shared { parked: None };
-        let shared = Rc::new(RefCell::new(shared_inner));
+        // dead observers are pruned by dispatch, so this usually stops at the first entry
+        if !self.dispatch.iter().any(|r| r.upgrade().is_some()) {
+            debug!("no observers for observation: {:?}", data);
+            return Completion { shared: None, signal: Weak::new() };
+        }
+
+        let tracking = self.pressure.borrow().tracking;
+        let pressure = if tracking { Rc::downgrade(&self.pressure) } else { Weak::new() };
+        let shared = Rc::new(RefCell::new(Shared { parked: None }));
 
         let observation = Observation {
             shared: Rc::downgrade(&shared),
+            pressure: pressure,
             signal: Rc::new(()),
             data: Rc::new(data),
         };
 
         let completion = Completion {
-            shared: shared,
+            shared: Some(shared),
             signal: Rc::downgrade(&observation.signal),
         };
 
+        if tracking {
+            let mut pressure = self.pressure.borrow_mut();
+            pressure.in_flight();
+            pressure.signals.push(Rc::downgrade(&observation.signal));
+        }
+
         self.dispatch(observation);
 
         completion
     }
 
+    /// Broadcasts an item like `put`, but returns a future that only resolves to the
+    /// `Completion` once at most `limit` updates, including this one, are still waiting to be
+    /// consumed. A producer that waits for this before sending its next update can't get more
+    /// than `limit` updates ahead of its slowest observer. Updates sent with `put` before the
+    /// first call to this are not counted.
+    pub fn put_backpressured(&mut self, data: T, limit: usize) -> Backpressure {
+        self.pressure.borrow_mut().tracking = true;
+        Backpressure {
+            completion: Some(self.put(data)),
+            pressure: self.pressure.clone(),
+            limit: limit,
+        }
+    }
+
     /// Creates a new observer for this update stream. The Observer will immediately begin
     /// receiving updates.
     pub fn observer(&mut self) -> Observer<T> {
-        let dispatch_inner = Dispatch { pending: VecDeque::new(), parked: None };
+        self.add_observer(false)
+    }
+
+    /// Creates a new observer that only receives the latest update. Updates that arrive before
+    /// the observer gets to the previous one replace it, and the replaced update is considered
+    /// consumed.
+    pub fn coalescing_observer(&mut self) -> Observer<T> {
+        self.add_observer(true)
+    }
+
+    /// Creates an observer that records every update as it is sent, for inspection with
+    /// `RecordingObserver::drain`. Recording an update does not hold up its `Completion`.
+    pub fn recording_observer(&mut self) -> RecordingObserver<T> where T: Clone {
+        RecordingObserver { dispatch: self.add_dispatch(false, Some(Vec::new())) }
+    }
+
+    fn add_observer(&mut self, coalesce: bool) -> Observer<T> {
+        Observer { dispatch: self.add_dispatch(coalesce, None) }
+    }
+
+    fn add_dispatch(&mut self, coalesce: bool, recorded: Option<Vec<Rc<T>>>)
+        -> Rc<DispatchCell<T>>
+    {
+        let dispatch_inner = Dispatch {
+            pending: VecDeque::new(),
+            parked: None,
+            dropped: 0,
+            coalesce: coalesce,
+            recorded: recorded,
+            closed: false,
+        };
         let dispatch = Rc::new(RefCell::new(dispatch_inner));
 
         self.dispatch.push(Rc::downgrade(&dispatch));
 
-        Observer { dispatch: dispatch }
+        dispatch
     }
 
-    fn dispatch(&mut self, obs: Observation<T>) {
-        // if this becomes a bottleneck, it can be made better by iterating over
-        // indices and using swap_remove to delete dropped weak pointers
+    /// Ends the update stream cleanly. Observers receive any updates still queued for them, then
+    /// the end of the stream, and report `is_closed` afterwards.
+    pub fn close(self) {
+        for r in self.dispatch.iter() {
+            if let Some(dispatch) = r.upgrade() {
+                dispatch.borrow_mut().closed = true;
+            }
+        }
+    }
+
+    /// Returns the number of observers still attached to this `Observable`.
+    pub fn observer_count(&self) -> usize {
+        self.dispatch.iter().filter(|r| r.upgrade().is_some()).count()
+    }
 
+    fn dispatch(&mut self, obs: Observation<T>) {
         debug!("dispatching observation: {:?}", obs);
 
-        let processed = self.dispatch
-            .drain(..)
-            .filter_map(|r| r.upgrade())
-            .map(|dispatch| {
-                let mut inner = dispatch.borrow_mut();
-                inner.pending.push_back(obs.clone());
-                inner.parked.as_ref().map(|t| t.unpark());
-                drop(inner);
-                Rc::downgrade(&dispatch)
-            })
-            .collect();
+        // observers that have gone away are swapped out of the list as they're found, so the
+        // list is only modified when something has actually changed
+        let mut i = 0;
+        while i < self.dispatch.len() {
+            let dispatch = match self.dispatch[i].upgrade() {
+                Some(dispatch) => dispatch,
+                None => {
+                    self.dispatch.swap_remove(i);
+                    continue;
+                },
+            };
+
+            let mut inner = dispatch.borrow_mut();
+            if let Some(ref mut recorded) = inner.recorded {
+                // only the data is kept, so the update is consumed as far as we're concerned
+                recorded.push(obs.data.clone());
+                i += 1;
+                continue;
+            }
+            if inner.coalesce {
+                inner.pending.clear();
+            }
+            if let Some(depth) = self.depth {
+                while inner.pending.len() >= depth && inner.pending.pop_front().is_some() {
+                    inner.dropped += 1;
+                }
+            }
+            inner.pending.push_back(obs.clone());
+            inner.parked.as_ref().map(|t| t.unpark());
 
-        self.dispatch = processed;
+            i += 1;
+        }
     }
 }
 
@@ -194,10 +375,32 @@ impl Future for Completion {
     type Error = ();
 
     fn poll(&mut self) -> Poll<(), ()> {
-        if let None = self.signal.upgrade() {
-            Ok(Async::Ready(()))
+        match self.shared {
+            Some(ref shared) if self.signal.upgrade().is_some() => {
+                shared.borrow_mut().parked = Some(task::park());
+                Ok(Async::NotReady)
+            },
+            _ => Ok(Async::Ready(())),
+        }
+    }
+}
+
+impl Future for Backpressure {
+    type Item = Completion;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Completion, ()> {
+        let mut pressure = self.pressure.borrow_mut();
+
+        if pressure.in_flight() <= self.limit {
+            match self.completion.take() {
+                Some(completion) => Ok(Async::Ready(completion)),
+                None => Err(()),
+            }
         } else {
-            self.shared.borrow_mut().parked = Some(task::park());
+            if !pressure.parked.iter().any(|t| t.will_notify_current()) {
+                pressure.parked.push(task::park());
+            }
             Ok(Async::NotReady)
         }
     }
@@ -225,12 +428,107 @@ impl<T: fmt::Debug> Stream for Observer<T> {
     }
 }
 
+/// A stream of transformed updates, created by `Observer::map_updates`
+pub struct Map<T, F> {
+    observer: Observer<T>,
+    f: F,
+}
+
+/// A stream of selected updates, created by `Observer::filter_updates`
+pub struct Filter<T, P> {
+    observer: Observer<T>,
+    pred: P,
+}
+
+impl<T> Observer<T> {
+    /// Transforms each update with the given function. The `Completion` for an update is not
+    /// resolved until the transformed `Observation` has been dropped.
+    pub fn map_updates<U, F>(self, f: F) -> Map<T, F> where F: FnMut(&T) -> U {
+        Map { observer: self, f: f }
+    }
+
+    /// Passes along only the updates for which the predicate returns true. The others are
+    /// dropped immediately.
+    pub fn filter_updates<P>(self, pred: P) -> Filter<T, P> where P: FnMut(&T) -> bool {
+        Filter { observer: self, pred: pred }
+    }
+
+    /// Detaches this observer from the `Observable`. Any updates that were still queued for it
+    /// are discarded, which counts as consuming them.
+    pub fn unsubscribe(self) {
+        let pending: Vec<Observation<T>> = self.dispatch.borrow_mut().pending.drain(..).collect();
+        drop(pending);
+    }
+
+    /// Indicates whether the `Observable` was ended with `close`, rather than dropped.
+    pub fn is_closed(&self) -> bool {
+        self.dispatch.borrow().closed
+    }
+
+    /// Returns the number of updates discarded because this observer fell behind a bounded
+    /// `Observable` since the last call, and resets the count.
+    pub fn take_dropped(&mut self) -> usize {
+        let mut dispatch = self.dispatch.borrow_mut();
+        let dropped = dispatch.dropped;
+        dispatch.dropped = 0;
+        dropped
+    }
+}
+
+impl<T: Clone> RecordingObserver<T> {
+    /// Returns all of the updates recorded since the last call, in the order they were sent.
+    pub fn drain(&mut self) -> Vec<T> {
+        let mut dispatch = self.dispatch.borrow_mut();
+        match dispatch.recorded {
+            Some(ref mut recorded) => recorded.drain(..).map(|data| (*data).clone()).collect(),
+            None => Vec::new(),
+        }
+    }
+}
+
 impl<T> Drop for Observer<T> {
     fn drop(&mut self) {
         debug!("(Observable) I am forgotten...");
     }
 }
 
+impl<T: fmt::Debug, U, F> Stream for Map<T, F> where F: FnMut(&T) -> U {
+    type Item = Observation<U>;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<Observation<U>>, ()> {
+        let obs = match try_ready!(self.observer.poll()) {
+            Some(obs) => obs,
+            None => return Ok(Async::Ready(None)),
+        };
+
+        let mapped = Observation {
+            shared: obs.shared.clone(),
+            pressure: obs.pressure.clone(),
+            signal: obs.signal.clone(),
+            data: Rc::new((self.f)(&*obs.data)),
+        };
+
+        Ok(Async::Ready(Some(mapped)))
+    }
+}
+
+impl<T: fmt::Debug, P> Stream for Filter<T, P> where P: FnMut(&T) -> bool {
+    type Item = Observation<T>;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<Observation<T>>, ()> {
+        loop {
+            match try_ready!(self.observer.poll()) {
+                Some(obs) => if (self.pred)(&*obs.data) {
+                    return Ok(Async::Ready(Some(obs)));
+                },
+                None => return Ok(Async::Ready(None)),
+            }
+        }
+    }
+}
+
 impl<T> Observation<T> {
     /// If the update needs to be kept around for a longer period of time, then the
     /// `Observation` can be converted directly into the underlying `Rc` wrapping the data.
@@ -245,6 +543,7 @@ impl<T> Clone for Observation<T> {
     fn clone(&self) -> Observation<T> {
         Observation {
             shared: self.shared.clone(),
+            pressure: self.pressure.clone(),
             signal: self.signal.clone(),
             data: self.data.clone()
         }
@@ -256,6 +555,9 @@ impl<T> Drop for Observation<T> {
         if let Some(shared) = self.shared.upgrade() {
             shared.borrow_mut().parked.take().map(|t| t.unpark());
         }
+        if let Some(pressure) = self.pressure.upgrade() {
+            pressure.borrow_mut().release();
+        }
     }
 }
 
@@ -283,3 +585,283 @@ impl<T> borrow::Borrow<T> for Observation<T> {
 impl<T> AsRef<T> for Observation<T> {
     fn as_ref(&self) -> &T { &*self.data }
 }
+
+#[test]
+fn bounded_drops_for_slow_observer() {
+    let mut updates: Observable<u32> = Observable::bounded(2);
+    let mut slow = updates.observer();
+    let mut fast = updates.observer().wait();
+
+    let mut received = Vec::new();
+    let completions: Vec<Completion> = (0..5).map(|i| {
+        let completion = updates.put(i);
+        received.push(*fast.next().unwrap().unwrap());
+        completion
+    }).collect();
+
+    assert_eq!(received, vec![0, 1, 2, 3, 4]);
+    assert_eq!(slow.dispatch.borrow().pending.len(), 2);
+    assert_eq!(slow.take_dropped(), 3);
+    assert_eq!(slow.take_dropped(), 0);
+
+    // completions can only be polled from within a task
+    let done: Vec<bool> = ::futures::future::lazy(move || {
+        Ok::<_, ()>(completions.into_iter()
+            .map(|mut c| c.poll().map(|a| a.is_ready()).unwrap_or(false))
+            .collect())
+    }).wait().unwrap();
+    assert_eq!(done, vec![true, true, true, false, false]);
+}
+
+#[test]
+fn unsubscribe_mid_stream() {
+    let mut updates: Observable<u32> = Observable::new();
+    let stays = updates.observer();
+    let leaves = updates.observer();
+    assert_eq!(updates.observer_count(), 2);
+
+    let first = updates.put(1);
+    leaves.unsubscribe();
+    assert_eq!(updates.observer_count(), 1);
+
+    let second = updates.put(2);
+    drop(updates);
+
+    let received: Vec<u32> = stays.map(|obs| *obs).collect().wait().unwrap();
+    assert_eq!(received, vec![1, 2]);
+
+    first.join(second).wait().unwrap();
+}
+
+#[test]
+fn map_preserves_completion() {
+    let mut updates: Observable<u32> = Observable::new();
+    let mut mapped = updates.observer().map_updates(|n| format!("#{}", n)).wait();
+
+    let completion = updates.put(7);
+    let obs = mapped.next().unwrap().unwrap();
+    assert_eq!(&*obs, "#7");
+
+    // completions can only be polled from within a task
+    let mut completion = Some(completion);
+    let mut poll = || ::futures::future::lazy(|| {
+        Ok::<_, ()>(completion.as_mut().unwrap().poll().unwrap().is_ready())
+    }).wait().unwrap();
+
+    assert!(!poll());
+    drop(obs);
+    assert!(poll());
+}
+
+#[test]
+fn filter_consumes_rejected() {
+    let mut updates: Observable<u32> = Observable::new();
+    let evens = updates.observer().filter_updates(|n| n % 2 == 0);
+
+    let completions: Vec<Completion> = (0..5).map(|i| updates.put(i)).collect();
+    drop(updates);
+
+    let received: Vec<u32> = evens.map(|obs| *obs).collect().wait().unwrap();
+    assert_eq!(received, vec![0, 2, 4]);
+
+    ::futures::future::join_all(completions).wait().unwrap();
+}
+
+#[test]
+fn coalescing_delivers_latest() {
+    let mut updates: Observable<u32> = Observable::new();
+    let latest = updates.coalescing_observer();
+
+    let completions: Vec<Completion> = (1..4).map(|i| updates.put(i)).collect();
+    drop(updates);
+
+    let received: Vec<u32> = latest.map(|obs| *obs).collect().wait().unwrap();
+    assert_eq!(received, vec![3]);
+
+    ::futures::future::join_all(completions).wait().unwrap();
+}
+
+#[test]
+fn dispatch_prunes_dropped_observers() {
+    let mut updates: Observable<u32> = Observable::new();
+    let first = updates.observer();
+    let second = updates.observer();
+    let third = updates.observer();
+
+    drop(first);
+    updates.put(1);
+    assert_eq!(updates.dispatch.len(), 2);
+
+    drop(third);
+    updates.put(2);
+    assert_eq!(updates.dispatch.len(), 1);
+    drop(updates);
+
+    let received: Vec<u32> = second.map(|obs| *obs).collect().wait().unwrap();
+    assert_eq!(received, vec![1, 2]);
+}
+
+#[test]
+fn recording_observer_keeps_order() {
+    let mut updates: Observable<u32> = Observable::new();
+    let mut log = updates.recording_observer();
+
+    // recording doesn't hold up completion, so these resolve right away
+    updates.put(1).wait().unwrap();
+    updates.put(2).wait().unwrap();
+    assert_eq!(log.drain(), vec![1, 2]);
+    assert_eq!(log.drain(), vec![]);
+
+    updates.put(3).wait().unwrap();
+    drop(updates);
+    assert_eq!(log.drain(), vec![3]);
+}
+
+#[test]
+fn backpressure_waits_for_backlog() {
+    use futures::future;
+
+    let mut updates: Observable<u32> = Observable::new();
+    let mut slow = updates.observer().wait();
+
+    let first = updates.put_backpressured(1, 1);
+    let mut second = updates.put_backpressured(2, 1);
+
+    future::lazy(|| {
+        // both updates are waiting on the observer
+        assert!(second.poll().unwrap().is_not_ready());
+
+        let held = slow.next().unwrap().unwrap();
+        assert!(second.poll().unwrap().is_not_ready());
+        drop(held);
+        assert!(second.poll().unwrap().is_ready());
+
+        Ok::<(), ()>(())
+    }).wait().unwrap();
+
+    // the first update has been consumed, so its completion is ready too
+    first.wait().unwrap().wait().unwrap();
+}
+
+#[test]
+fn backpressure_wakes_every_waiter() {
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering;
+    use futures::executor;
+    use futures::executor::Notify;
+
+    struct Count(AtomicUsize);
+
+    impl Notify for Count {
+        fn notify(&self, _: usize) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    let mut updates: Observable<u32> = Observable::new();
+
+    // plain puts aren't tracked until backpressure is asked for
+    let mut slow = updates.observer().wait();
+    updates.put(0);
+    assert!(updates.pressure.borrow().signals.is_empty());
+    drop(slow.next());
+
+    let mut first = executor::spawn(updates.put_backpressured(1, 0));
+    let mut second = executor::spawn(updates.put_backpressured(2, 0));
+    let notify = Arc::new(Count(AtomicUsize::new(0)));
+
+    assert!(first.poll_future_notify(&notify, 1).unwrap().is_not_ready());
+    assert!(second.poll_future_notify(&notify, 2).unwrap().is_not_ready());
+    assert_eq!(updates.pressure.borrow().parked.len(), 2);
+
+    // both waiters hear about the backlog clearing
+    drop(slow.next());
+    drop(slow.next());
+    assert_eq!(notify.0.load(Ordering::SeqCst), 2);
+    assert!(first.poll_future_notify(&notify, 1).unwrap().is_ready());
+    assert!(second.poll_future_notify(&notify, 2).unwrap().is_ready());
+}
+
+#[test]
+fn closed_and_dropped_observables() {
+    let mut closed: Observable<u32> = Observable::new();
+    let mut dropped: Observable<u32> = Observable::new();
+    let mut closed_obs = closed.observer().wait();
+    let mut dropped_obs = dropped.observer().wait();
+
+    closed.put(1);
+    dropped.put(1);
+    closed.close();
+    drop(dropped);
+
+    // queued updates are still delivered either way
+    assert_eq!(*closed_obs.next().unwrap().unwrap(), 1);
+    assert_eq!(*dropped_obs.next().unwrap().unwrap(), 1);
+    assert!(closed_obs.next().is_none());
+    assert!(dropped_obs.next().is_none());
+
+    assert!(closed_obs.into_inner().is_closed());
+    assert!(!dropped_obs.into_inner().is_closed());
+}
+
+#[test]
+fn put_without_observers() {
+    use futures::future;
+
+    let mut updates: Observable<u32> = Observable::new();
+    let mut completion = updates.put(1);
+    assert!(completion.shared.is_none());
+    assert_eq!(future::lazy(|| completion.poll()).wait(), Ok(Async::Ready(())));
+
+    // observers that have gone away don't count
+    drop(updates.observer());
+    let completion = updates.put(2);
+    assert!(completion.shared.is_none());
+    assert_eq!(updates.observer_count(), 0);
+    assert_eq!(updates.pressure.borrow_mut().in_flight(), 0);
+
+    // but a live one still holds up completion
+    let mut observer = updates.observer().wait();
+    let mut completion = updates.put(3);
+    assert!(completion.shared.is_some());
+    assert_eq!(future::lazy(|| completion.poll()).wait(), Ok(Async::NotReady));
+    drop(observer.next());
+    assert_eq!(future::lazy(|| completion.poll()).wait(), Ok(Async::Ready(())));
+}
+
+#[test]
+fn backpressure_without_completion() {
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering;
+    use futures::executor;
+    use futures::executor::Notify;
+
+    struct Count(AtomicUsize);
+
+    impl Notify for Count {
+        fn notify(&self, _: usize) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    let mut updates: Observable<u32> = Observable::new();
+    let mut slow = updates.observer().wait();
+    let notify = Arc::new(Count(AtomicUsize::new(0)));
+
+    // the producer doesn't care when its updates are consumed
+    let mut first = executor::spawn(updates.put_backpressured(1, 1));
+    match first.poll_future_notify(&notify, 1) {
+        Ok(Async::Ready(completion)) => drop(completion),
+        _ => panic!("first update should not wait"),
+    }
+
+    let mut second = executor::spawn(updates.put_backpressured(2, 1));
+    assert!(second.poll_future_notify(&notify, 2).unwrap().is_not_ready());
+
+    // consuming the first update still wakes the producer
+    drop(slow.next());
+    assert_eq!(notify.0.load(Ordering::SeqCst), 1);
+    assert!(second.poll_future_notify(&notify, 2).unwrap().is_ready());
+}
@@ -5,6 +5,9 @@
 // the COPYING file in the project root.
 
 //! Server IDs.
+//!
+//! A server ID is exactly three characters long, each of which is an ASCII
+//! letter, an ASCII digit, or an underscore, such as `0AA` or `A__`.
 
 extern crate time;
 
@@ -18,10 +21,24 @@ use std::fmt;
 pub struct Sid([u8; 3]);
 
 impl Sid {
-    /// Creates a new `Sid` from the given UTF-8 string.
+    /// Creates a new `Sid` from the given string. Panics if the string is not a
+    /// valid server ID, so this is only suitable for trusted input, such as
+    /// configuration. Use `try_from` for anything else.
     pub fn new(s: &str) -> Sid {
-        let s = s.as_bytes();
-        Sid([s[0], s[1], s[2]])
+        match Sid::try_from(s.as_bytes()) {
+            Some(sid) => sid,
+            None => panic!("invalid server ID {:?}", s),
+        }
+    }
+
+    /// Creates a new `Sid` from the given bytes, returning `None` if they are
+    /// not a valid server ID.
+    pub fn try_from(v: &[u8]) -> Option<Sid> {
+        if v.len() == 3 && v.iter().all(|c| is_sid_char(*c)) {
+            Some(Sid([v[0], v[1], v[2]]))
+        } else {
+            None
+        }
     }
 
     /// Creates some sort of default `Sid`.
@@ -30,6 +47,10 @@ impl Sid {
     }
 }
 
+fn is_sid_char(c: u8) -> bool {
+    (c as char).is_ascii_alphanumeric() || c == b'_'
+}
+
 impl<'a> From<&'a [u8]> for Sid {
     fn from(v: &[u8]) -> Sid {
         match v.len() {
@@ -58,3 +79,25 @@ impl fmt::Display for Sid {
         write!(f, "{}", String::from_utf8_lossy(&self.0[..]))
     }
 }
+
+#[test]
+fn test_valid_sids() {
+    assert_eq!(Sid::try_from(b"0N1"), Some(Sid::new("0N1")));
+    assert_eq!(Sid::try_from(b"A__"), Some(Sid::new("A__")));
+    assert_eq!(format!("{}", Sid::new("0AA")), "0AA");
+}
+
+#[test]
+fn test_invalid_sids() {
+    assert_eq!(Sid::try_from(b""), None);
+    assert_eq!(Sid::try_from(b"0A"), None);
+    assert_eq!(Sid::try_from(b"0AAA"), None);
+    assert_eq!(Sid::try_from(b"0 A"), None);
+    assert_eq!(Sid::try_from(b"0\x00A"), None);
+}
+
+#[test]
+#[should_panic]
+fn test_new_rejects_short() {
+    Sid::new("0A");
+}
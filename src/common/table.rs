@@ -43,4 +43,73 @@ impl<K, V> Table<K, V> where K: Hash + Eq {
     pub fn entry(&mut self, k1: K, k2: K) -> Entry<K, V> {
         self.rows.entry(k1).or_insert_with(|| HashMap::new()).entry(k2)
     }
+
+    /// Removes the value at the given row and column, returning it if it
+    /// existed.
+    pub fn remove(&mut self, k1: &K, k2: &K) -> Option<V> {
+        let (v, now_empty) = match self.rows.get_mut(k1) {
+            Some(r) => (r.remove(k2), r.is_empty()),
+            None => return None,
+        };
+        if now_empty {
+            self.rows.remove(k1);
+        }
+        v
+    }
+
+    /// Iterates over every row, column, and value in the table, in no
+    /// particular order.
+    pub fn iter<'a>(&'a self) -> Box<Iterator<Item=(&'a K, &'a K, &'a V)> + 'a> {
+        Box::new(self.rows.iter().flat_map(|(k1, r)| r.iter().map(move |(k2, v)| (k1, k2, v))))
+    }
+
+    /// Keeps only the entries for which the predicate returns true.
+    pub fn retain<F>(&mut self, mut f: F) where F: FnMut(&K, &K, &mut V) -> bool {
+        for (k1, r) in self.rows.iter_mut() {
+            r.retain(|k2, v| f(k1, k2, v));
+        }
+        self.rows.retain(|_, r| !r.is_empty());
+    }
+}
+
+#[test]
+fn test_table_put_get_remove() {
+    let mut t = Table::new();
+
+    t.put(1, 2, "a");
+    t.put(1, 3, "b");
+    t.put(2, 1, "c");
+    assert_eq!(t.get(&1, &2), Some(&"a"));
+    assert_eq!(t.get(&2, &1), Some(&"c"));
+    assert_eq!(t.get(&2, &2), None);
+
+    assert_eq!(t.remove(&1, &2), Some("a"));
+    assert_eq!(t.remove(&1, &2), None);
+    assert_eq!(t.remove(&3, &3), None);
+    assert_eq!(t.get(&1, &3), Some(&"b"));
+
+    assert_eq!(t.remove(&2, &1), Some("c"));
+    assert!(!t.rows.contains_key(&2));
+}
+
+#[test]
+fn test_table_iter_retain() {
+    let mut t = Table::new();
+
+    for a in 0..4 {
+        for b in 0..4 {
+            t.put(a, b, a * 10 + b);
+        }
+    }
+
+    t.retain(|a, b, _| a != b && *a != 3);
+
+    let mut all: Vec<(u32, u32, u32)> = t.iter().map(|(a, b, v)| (*a, *b, *v)).collect();
+    all.sort();
+    assert_eq!(all, vec![
+        (0, 1, 1), (0, 2, 2), (0, 3, 3),
+        (1, 0, 10), (1, 2, 12), (1, 3, 13),
+        (2, 0, 20), (2, 1, 21), (2, 3, 23),
+    ]);
+    assert!(!t.rows.contains_key(&3));
 }
@@ -70,15 +70,55 @@
 //!  * A set, where two sets are "merged" by only taking elements unique to the second set.
 //!    (This is neither idempotent, commutative, nor associative.)
 //!
+//! `testing::assert_crdt_laws` can be used to check a schema against these requirements.
+//!
 //! Refer to the [Wikipedia article about CRDTs][wiki] for more information about this idea.
 //!
 //! [wiki]: https://en.wikipedia.org/wiki/Conflict-free_replicated_data_type
+//!
+//! # Removing rows
+//!
+//! Simply forgetting a row does not work in an eventually consistent context, since a replica
+//! that has not yet seen the removal will happily send the row back. Instead, removals leave
+//! behind a **tombstone**. Every write and every removal is tagged with a `Stamp`, and the
+//! tombstone records the stamp of the newest removal of that row. A row is visible only if it
+//! has been written more recently than it was removed, so a write that races with a removal
+//! wins if and only if its stamp is newer.
+//!
+//! Underneath, a row is really the triple of its merged contents, the newest write stamp, and
+//! the newest removal stamp. Two rows are merged by merging the contents with the schema and
+//! taking the newest of each stamp, which keeps the merge idempotent, commutative, and
+//! associative. Note that this means removing a row only hides its contents: a later write
+//! is merged with whatever the row held before it was removed. As with claim expirations,
+//! tombstones are kept forever for now.
+//!
+//! A removal is always stamped after the newest write of the row the replica has seen, even if
+//! that write came from a replica whose clock is ahead, so removing a row hides everything the
+//! remover could see. Only writes it had not seen yet can win over the removal.
+//!
+//! A tombstone is stored on the row it hides, so a replica can only remove rows it has seen.
+//! Removing a key this replica has never seen does nothing, and a write to that key from a peer
+//! will then be visible even if it is older than the removal. Callers that need to remove a row
+//! they might not have received yet must wait until they have it.
+//!
+//! # Versioning records
+//!
+//! Records outlive the code that wrote them, both on disk and on replicas running older or newer
+//! code. A schema whose format may change should start each record with a version byte, using
+//! `Record::versioned` to encode and `Record::split_version` to decode. Decoding can then pick
+//! the right format for old records, and notice records from a newer format it cannot read.
+//! Since `Schema::decode` cannot fail, what to do with an unreadable record is up to the schema,
+//! but it should be something that loses any merge with a real record.
 
 use std::cell::RefCell;
 use std::cell::RefMut;
+use std::cmp;
 use std::collections::HashMap;
+use std::collections::HashSet;
+use std::collections::VecDeque;
 use std::fmt;
 use std::rc::Rc;
+use std::vec;
 
 use futures::Async;
 use futures::Future;
@@ -86,10 +126,15 @@ use futures::Poll;
 
 use rand::random;
 
+use time;
+
 use common::observe;
 use common::observe::Observable;
 use common::observe::Observer;
 
+pub mod schema;
+pub mod testing;
+
 #[cfg(test)]
 mod tests;
 
@@ -97,16 +142,79 @@ mod tests;
 #[derive(Clone, Eq, PartialEq, Hash)]
 pub struct Record(pub Vec<u8>);
 
+impl Record {
+    /// Creates a record holding data in the given version of a schema's format
+    pub fn versioned(version: u8, data: &[u8]) -> Record {
+        let mut v = Vec::with_capacity(data.len() + 1);
+        v.push(version);
+        v.extend_from_slice(data);
+        Record(v)
+    }
+
+    /// Splits a record created with `versioned` into its version and data. Versions newer than
+    /// `latest` are an error.
+    pub fn split_version(&self, latest: u8) -> Result<(u8, &[u8]), RecordError> {
+        match self.0.split_first() {
+            Some((&version, _)) if version > latest => Err(RecordError::SchemaVersion(version)),
+            Some((&version, data)) => Ok((version, data)),
+            None => Err(RecordError::Empty),
+        }
+    }
+}
+
+/// The ways a versioned record can fail to decode
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum RecordError {
+    /// The record is empty, and so has no version
+    Empty,
+    /// The record is in a version of the format newer than the schema knows about
+    SchemaVersion(u8),
+    /// The record ends before all of its fields
+    Truncated,
+}
+
 impl fmt::Debug for Record {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "Record({:?})", self.0)
     }
 }
 
+/// A totally ordered tag attached to writes and removals, used to decide whether a row has been
+/// removed. Ties on the wall clock time are broken using the ID of the transaction, which is
+/// chosen at random.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct Stamp {
+    time: time::Timespec,
+    txid: u64,
+}
+
+impl Stamp {
+    /// Creates a `Stamp` for the current moment in time, with a fresh transaction ID.
+    pub fn now() -> Stamp {
+        Stamp { time: time::get_time(), txid: random() }
+    }
+
+    /// Returns the ID of the transaction that created this stamp
+    pub fn txid(&self) -> u64 {
+        self.txid
+    }
+}
+
+/// The most rows held on to for tables that do not exist. Once there are more, the oldest
+/// deferred commits are discarded, so that a peer can't fill up memory by writing to tables
+/// that are never created.
+pub const MAX_PENDING_ROWS: usize = 10000;
+
+/// The items from a raw commit to a table that did not exist at the time: the table name, the
+/// transaction ID, and the rows by key
+type PendingCommit = (String, u64, HashMap<String, Vec<RawRow>>);
+
 /// An eventually consistent database. See module-level documentation for more information.
 pub struct CRDB {
     updates: Observable<RawUpdates>,
     tables: HashMap<String, Box<RawTable>>,
+    pending: VecDeque<PendingCommit>,
+    pending_rows: usize,
 }
 
 impl CRDB {
@@ -115,10 +223,14 @@ impl CRDB {
         CRDB {
             updates: Observable::new(),
             tables: HashMap::new(),
+            pending: VecDeque::new(),
+            pending_rows: 0,
         }
     }
 
-    /// Creates a table using the given schema.
+    /// Creates a table using the given schema. Any items from raw transactions that were
+    /// committed to this table name before it existed are applied to the new table, and are
+    /// published as raw updates under their original transaction IDs.
     ///
     /// # Panics
     ///
@@ -143,36 +255,129 @@ impl CRDB {
             panic!("table name reused");
         }
 
+        let pending = self.take_pending(name);
+        if !pending.is_empty() {
+            debug!("applying {} pending commits to {}", pending.len(), name);
+        }
+
+        for (_, txid, items) in pending.into_iter() {
+            let mut updates = Vec::new();
+            self.tables.get_mut(name).unwrap().commit_all_raw(txid, items, &mut updates);
+            self.publish(txid, updates);
+        }
+
         typed
     }
 
+    /// Drops the named table, along with any items being held on to for it. The name can then
+    /// be reused with `create_table`. Any `Table` handles for the dropped table are detached:
+    /// raw transactions no longer reach them, and are instead held on to for the next table
+    /// created with the same name, until that table is dropped too.
+    ///
+    /// Returns whether a table with that name existed.
+    pub fn drop_table(&mut self, name: &str) -> bool {
+        let discarded = self.take_pending(name);
+        if !discarded.is_empty() {
+            debug!("discarding {} pending commits to {}", discarded.len(), name);
+        }
+
+        self.tables.remove(name).is_some()
+    }
+
+    /// Removes the commits being held on to for the named table, oldest first.
+    fn take_pending(&mut self, name: &str) -> Vec<PendingCommit> {
+        let (taken, kept): (Vec<_>, Vec<_>) = self.pending.drain(..)
+            .partition(|commit| commit.0 == name);
+
+        self.pending = kept.into_iter().collect();
+        for commit in taken.iter() {
+            self.pending_rows -= pending_len(&commit.2);
+        }
+        taken
+    }
+
+    /// Holds on to a commit for a table that doesn't exist yet, discarding the oldest commits
+    /// if there are too many rows waiting.
+    fn defer(&mut self, table: String, txid: u64, items: HashMap<String, Vec<RawRow>>) {
+        debug!("deferring commit of {} items to {}", items.len(), table);
+
+        self.pending_rows += pending_len(&items);
+        self.pending.push_back((table, txid, items));
+
+        while self.pending_rows > MAX_PENDING_ROWS {
+            let (table, txid, items) = match self.pending.pop_front() {
+                Some(commit) => commit,
+                None => break,
+            };
+            warn!("too many pending rows; discarding commit {} to {}", txid, table);
+            self.pending_rows -= pending_len(&items);
+        }
+    }
+
+    /// Broadcasts a raw update, unless there is nothing in it.
+    fn publish(&mut self, txid: u64, updates: Vec<RawUpdate>) -> Option<observe::Completion> {
+        if updates.is_empty() {
+            return None;
+        }
+
+        Some(self.updates.put(RawUpdates { txid: txid, updates: updates }))
+    }
+
+    /// Returns the number of rows being held on to for tables that do not exist yet
+    pub fn pending_rows(&self) -> usize {
+        self.pending_rows
+    }
+
     /// Returns an `Observer` for the stream of raw updates across all tables
     pub fn updates(&mut self) -> Observer<RawUpdates> {
         self.updates.observer()
     }
 
-    /// Commits a raw transaction
+    /// Returns the current contents of every table as raw updates, including removed rows and
+    /// any items being held on to for tables that do not exist yet. Committing all of them to a
+    /// fresh replica with `commit_raw`, using `RawTransaction::add_stamped`, brings it up to the
+    /// same state as this one. The `prev` field of every update is `None`.
+    pub fn snapshot_raw(&self) -> Vec<RawUpdate> {
+        let mut updates = Vec::new();
+
+        for table in self.tables.values() {
+            table.snapshot_raw(&mut updates);
+        }
+
+        for (table_name, _, items) in self.pending.iter() {
+            for (key, rows) in items.iter() {
+                updates.extend(rows.iter().map(|row| RawUpdate {
+                    table: table_name.clone(),
+                    key: key.clone(),
+                    prev: None,
+                    item: row.data.clone(),
+                    written: row.written,
+                    removed: row.removed,
+                }));
+            }
+        }
+
+        updates
+    }
+
+    /// Commits a raw transaction. Items for tables that do not exist yet are held on to, and are
+    /// applied when the table is created. Replication can therefore safely deliver items for a
+    /// table before this replica has gotten around to creating it. At most `MAX_PENDING_ROWS`
+    /// rows are held on to in this way. No raw update is published for items that are held on
+    /// to until they are applied.
     pub fn commit_raw(&mut self, tx: RawTransaction) -> Completion {
         let mut completions = Vec::new();
         let mut updates = Vec::new();
-        let txid = tx.txid;
+        let txid = tx.txid();
 
         for (table_name, items) in tx.items.into_iter() {
-            let mut table = match self.tables.get_mut(&table_name) {
-                Some(table) => table,
-                None => {
-                    warn!("discarding commit of {} items to {}", items.len(), table_name);
-                    continue;
-                }
-            };
-
-            completions.push(table.commit_all_raw(txid, items, &mut updates));
+            match self.tables.get_mut(&table_name) {
+                Some(table) => completions.push(table.commit_all_raw(txid, items, &mut updates)),
+                None => self.defer(table_name, txid, items),
+            }
         }
 
-        completions.push(self.updates.put(RawUpdates {
-            txid: txid,
-            updates: updates
-        }));
+        completions.extend(self.publish(txid, updates));
 
         Completion { inner: Some(completions) }
     }
@@ -180,15 +385,11 @@ impl CRDB {
     /// Commits a typed transaction
     pub fn commit<S: Schema>(&mut self, tx: Transaction<S>) -> Completion {
         let mut completions = Vec::with_capacity(2);
-        let mut updates = Vec::with_capacity(tx.next.len());
-        let txid = tx.txid;
+        let mut updates = Vec::with_capacity(tx.next.len() + tx.removed.len());
+        let txid = tx.txid();
 
         completions.push(tx.commit(&mut updates));
-
-        completions.push(self.updates.put(RawUpdates {
-            txid: txid,
-            updates: updates
-        }));
+        completions.extend(self.publish(txid, updates));
 
         Completion { inner: Some(completions) }
     }
@@ -198,38 +399,76 @@ trait RawTable {
     fn commit_all_raw(
         &mut self,
         txid: u64,
-        items: HashMap<String, Vec<Record>>,
+        items: HashMap<String, Vec<RawRow>>,
         raw_updates: &mut Vec<RawUpdate>
     ) -> observe::Completion;
+
+    fn snapshot_raw(&self, raw_updates: &mut Vec<RawUpdate>);
+}
+
+struct RawRow {
+    data: Record,
+    written: Stamp,
+    removed: Option<Stamp>,
+}
+
+/// Counts the rows in a set of items from a raw transaction.
+fn pending_len(items: &HashMap<String, Vec<RawRow>>) -> usize {
+    items.values().map(|rows| rows.len()).sum()
 }
 
 /// A raw transaction
 pub struct RawTransaction {
-    txid: u64,
-    items: HashMap<String, HashMap<String, Vec<Record>>>,
+    stamp: Stamp,
+    items: HashMap<String, HashMap<String, Vec<RawRow>>>,
 }
 
 impl RawTransaction {
     /// Creates a new raw transaction
     pub fn new() -> RawTransaction {
         RawTransaction {
-            txid: random(),
+            stamp: Stamp::now(),
             items: HashMap::new()
         }
     }
 
+    /// Creates a raw transaction containing the given updates, such as those received from
+    /// another replica or returned by `CRDB::snapshot_raw`. Each update keeps its own write and
+    /// removal stamps, and updates to the same row are merged when the transaction is committed.
+    pub fn from_updates(updates: Vec<RawUpdate>) -> RawTransaction {
+        let mut tx = RawTransaction::new();
+        for u in updates.into_iter() {
+            tx.add_stamped(u.table, u.key, u.item, u.written, u.removed);
+        }
+        tx
+    }
+
     /// Returns the ID of this transaction
     pub fn txid(&self) -> u64 {
-        self.txid
+        self.stamp.txid
     }
 
     /// Adds an item to this transaction. If an item with the given key already exists in this
     /// transaction, then the items will be merged when the transaction is committed.
     pub fn add(&mut self, table: String, k: String, data: Record) {
+        let written = self.stamp;
+        self.add_stamped(table, k, data, written, None);
+    }
+
+    /// Adds an item to this transaction with explicit write and removal stamps, such as those
+    /// carried by a `RawUpdate` from another replica.
+    pub fn add_stamped(
+        &mut self,
+        table: String,
+        k: String,
+        data: Record,
+        written: Stamp,
+        removed: Option<Stamp>
+    ) {
         self.items
             .entry(table).or_insert_with(|| HashMap::new())
             .entry(k).or_insert_with(|| Vec::new())
-            .push(data);
+            .push(RawRow { data: data, written: written, removed: removed });
     }
 }
 
@@ -248,10 +487,40 @@ struct TableInner<S: Schema> {
 
     name: String,
     schema: S,
-    rows: HashMap<String, S::Item>,
+    rows: HashMap<String, Row<S::Item>>,
     updates: Observable<Updates<S>>,
 }
 
+/// A row as it is actually stored, including rows that have been removed.
+#[derive(Clone)]
+struct Row<T> {
+    item: T,
+    written: Stamp,
+    removed: Option<Stamp>,
+}
+
+impl<T: Clone> Row<T> {
+    fn new(item: T, written: Stamp) -> Row<T> {
+        Row { item: item, written: written, removed: None }
+    }
+
+    fn visible(&self) -> bool {
+        self.removed.map(|r| self.written > r).unwrap_or(true)
+    }
+
+    fn into_visible(self) -> Option<T> {
+        if self.visible() { Some(self.item) } else { None }
+    }
+
+    fn merge<S: Schema<Item=T>>(self, schema: &S, other: Row<T>) -> Row<T> {
+        Row {
+            item: schema.merge(self.item, other.item),
+            written: cmp::max(self.written, other.written),
+            removed: cmp::max(self.removed, other.removed),
+        }
+    }
+}
+
 /// Schemas are the secret sauce that allow CRDB to function in an eventually consistent context.
 /// Critically, schemas implement the `merge` operation that determines how two possibly divergent
 /// states are merged together. See the module-level documentation for more information.
@@ -269,6 +538,14 @@ pub trait Schema {
     /// This operation ***MUST*** be idempotent, associative, and commutative. See the module-level
     /// documentation for more information.
     fn merge(&self, a: Self::Item, b: Self::Item) -> Self::Item;
+
+    /// Decides whether an item from a raw transaction, such as one received from another
+    /// replica, may be applied to the table. Rejected items are dropped before they are merged.
+    /// This is the place to check anything that depends on the local replica, like its clock,
+    /// since `merge` must give the same answer everywhere. Accepts everything by default.
+    fn accept(&self, _item: &Self::Item) -> bool {
+        true
+    }
 }
 
 impl<S: Schema> Table<S> {
@@ -277,23 +554,60 @@ impl<S: Schema> Table<S> {
         self.inner.borrow_mut().updates.observer()
     }
 
-    /// Returns a copy of the data with the given key
+    /// Returns a copy of the current contents of the table along with an `Observer` for all
+    /// updates after that point. Since both are taken at the same moment, every update is either
+    /// reflected in the snapshot or seen by the observer, but never both. Removed rows are not
+    /// included in the snapshot.
+    pub fn updates_with_snapshot(&mut self) -> (Vec<(String, S::Item)>, Observer<Updates<S>>) {
+        let mut inner = self.inner.borrow_mut();
+
+        let rows = inner.rows.iter()
+            .filter(|&(_, row)| row.visible())
+            .map(|(k, row)| (k.clone(), row.item.clone()))
+            .collect();
+
+        (rows, inner.updates.observer())
+    }
+
+    /// Returns a copy of the data with the given key, unless the row has been removed
     pub fn get<'t>(&'t self, k: &str) -> Option<S::Item> {
-        self.inner.borrow().rows.get(k).cloned()
+        self.inner.borrow().rows.get(k).cloned().and_then(Row::into_visible)
+    }
+
+    /// Returns the keys of all rows in the table, in no particular order. Removed rows are
+    /// not included.
+    pub fn keys(&self) -> Vec<String> {
+        self.inner.borrow().rows.iter()
+            .filter(|&(_, row)| row.visible())
+            .map(|(k, _)| k.clone())
+            .collect()
+    }
+
+    /// Returns an iterator over a copy of all the rows in the table, in no particular order.
+    /// Removed rows are not included. Since the table is copied, it can be freely modified
+    /// while the iterator is alive.
+    pub fn iter(&self) -> vec::IntoIter<(String, S::Item)> {
+        let rows: Vec<(String, S::Item)> = self.inner.borrow().rows.iter()
+            .filter(|&(_, row)| row.visible())
+            .map(|(k, row)| (k.clone(), row.item.clone()))
+            .collect();
+
+        rows.into_iter()
     }
 
     /// Creates a new typed transaction on this table.
     pub fn open<'t>(&'t mut self) -> Transaction<'t, S> {
         Transaction {
-            txid: random(),
+            stamp: Stamp::now(),
             inner: self.inner.borrow_mut(),
             next: HashMap::new(),
+            removed: HashSet::new(),
         }
     }
 
     #[cfg(test)]
     fn snapshot(self) -> HashMap<String, S::Item> {
-        self.inner.borrow().rows.clone()
+        self.iter().collect()
     }
 }
 
@@ -301,61 +615,86 @@ impl<S: Schema> RawTable for Table<S> {
     fn commit_all_raw(
         &mut self,
         txid: u64,
-        items: HashMap<String, Vec<Record>>,
+        items: HashMap<String, Vec<RawRow>>,
         raw_updates: &mut Vec<RawUpdate>
     ) -> observe::Completion {
         self.inner.borrow_mut().commit_all_raw(txid, items, raw_updates)
     }
+
+    fn snapshot_raw(&self, raw_updates: &mut Vec<RawUpdate>) {
+        self.inner.borrow().snapshot_raw(raw_updates)
+    }
 }
 
 impl<S: Schema> TableInner<S> {
-    fn typed_update_as_raw(&self, update: &Update<S>) -> RawUpdate {
+    fn typed_update_as_raw(&self, update: &Update<S>, row: &Row<S::Item>) -> RawUpdate {
         RawUpdate {
             table: self.name.clone(),
             key: update.key.clone(),
             prev: update.prev.as_ref().map(|p| self.schema.encode(p)),
             item: self.schema.encode(&update.item),
+            written: row.written,
+            removed: row.removed,
         }
     }
 
-    fn coalesce_raw(&self, rows: Vec<Record>) -> S::Item {
-        assert!(rows.len() > 0);
+    fn snapshot_raw(&self, raw_updates: &mut Vec<RawUpdate>) {
+        raw_updates.extend(self.rows.iter().map(|(key, row)| RawUpdate {
+            table: self.name.clone(),
+            key: key.clone(),
+            prev: None,
+            item: self.schema.encode(&row.item),
+            written: row.written,
+            removed: row.removed,
+        }));
+    }
 
-        let item = rows.into_iter().fold(None, |cur, record| {
-            let b = self.schema.decode(&record);
+    /// Merges the rows for a single key from a raw transaction, leaving out any the schema does
+    /// not accept. Returns `None` if no rows are left.
+    fn coalesce_raw(&self, rows: Vec<RawRow>) -> Option<Row<S::Item>> {
+        rows.into_iter().fold(None, |cur: Option<Row<S::Item>>, raw| {
+            let b = Row {
+                item: self.schema.decode(&raw.data),
+                written: raw.written,
+                removed: raw.removed,
+            };
+
+            if !self.schema.accept(&b.item) {
+                warn!("{}: rejecting record {:?}", self.name, b.item);
+                return cur;
+            }
 
             if let Some(a) = cur {
-                Some(self.schema.merge(a, b))
+                Some(a.merge(&self.schema, b))
             } else {
                 Some(b)
             }
-        });
-
-        item.unwrap()
+        })
     }
 
     fn commit_one(
         &mut self,
         key: String,
-        item: S::Item,
+        row: Row<S::Item>,
         typed_updates: &mut Vec<Update<S>>,
         raw_updates: &mut Vec<RawUpdate>,
     ) {
         let prev = self.rows.remove(&key);
         let next = match prev {
-            Some(ref prev) => self.schema.merge(prev.clone(), item),
-            None => item,
+            Some(ref prev) => prev.clone().merge(&self.schema, row),
+            None => row,
         };
 
         self.rows.insert(key.clone(), next.clone());
 
         let typed_update = Update {
             key: key.clone(),
-            prev: prev,
-            item: next,
+            prev: prev.and_then(Row::into_visible),
+            item: next.item.clone(),
+            removed: !next.visible(),
         };
 
-        let raw_update = self.typed_update_as_raw(&typed_update);
+        let raw_update = self.typed_update_as_raw(&typed_update, &next);
 
         typed_updates.push(typed_update);
         raw_updates.push(raw_update);
@@ -364,15 +703,14 @@ impl<S: Schema> TableInner<S> {
     fn commit_all_raw(
         &mut self,
         txid: u64,
-        items: HashMap<String, Vec<Record>>,
+        items: HashMap<String, Vec<RawRow>>,
         raw_updates: &mut Vec<RawUpdate>
     ) -> observe::Completion {
         let mut typed_updates = Vec::with_capacity(items.len());
 
         for (key, rows) in items.into_iter() {
-            if rows.len() > 0 {
-                let item = self.coalesce_raw(rows);
-                self.commit_one(key, item, &mut typed_updates, raw_updates);
+            if let Some(row) = self.coalesce_raw(rows) {
+                self.commit_one(key, row, &mut typed_updates, raw_updates);
             }
         }
 
@@ -384,18 +722,38 @@ impl<S: Schema> TableInner<S> {
 
     fn commit_all_typed(
         &mut self,
-        txid: u64,
+        stamp: Stamp,
         items: HashMap<String, S::Item>,
+        removed: HashSet<String>,
         raw_updates: &mut Vec<RawUpdate>,
     ) -> observe::Completion {
-        let mut typed_updates = Vec::with_capacity(items.len());
+        let mut typed_updates = Vec::with_capacity(items.len() + removed.len());
 
         for (key, item) in items.into_iter() {
-            self.commit_one(key, item, &mut typed_updates, raw_updates);
+            self.commit_one(key, Row::new(item, stamp), &mut typed_updates, raw_updates);
+        }
+
+        for key in removed.into_iter() {
+            // removing a row we have never seen has nothing to hide
+            let row = match self.rows.get(&key) {
+                Some(row) => row.clone(),
+                None => continue,
+            };
+
+            // the removal must hide what we've seen, even a write stamped ahead of our clock
+            let removed = if stamp > row.written {
+                stamp
+            } else {
+                Stamp { time: row.written.time + time::Duration::nanoseconds(1), txid: stamp.txid }
+            };
+
+            let tombstone = Row { removed: Some(removed), .. row };
+
+            self.commit_one(key, tombstone, &mut typed_updates, raw_updates);
         }
 
         self.updates.put(Updates {
-            txid: txid,
+            txid: stamp.txid,
             updates: typed_updates
         })
     }
@@ -403,33 +761,41 @@ impl<S: Schema> TableInner<S> {
 
 /// A typed transaction on a single table
 pub struct Transaction<'t, S: 'static + Schema> {
-    txid: u64,
+    stamp: Stamp,
     inner: RefMut<'t, TableInner<S>>,
     next: HashMap<String, S::Item>,
+    removed: HashSet<String>,
 }
 
 impl<'t, S: 'static + Schema> Transaction<'t, S> {
     /// Returns the ID of this transaction
     pub fn txid(&self) -> u64 {
-        self.txid
+        self.stamp.txid
     }
 
-    /// Reads an item from the table. This will behave as if any items added to the transaction
-    /// have already been committed.
+    /// Reads an item from the table. This will behave as if any items added to or removed from
+    /// the transaction have already been committed.
     pub fn get(&self, key: &str) -> Option<S::Item> {
-        if let Some(prev) = self.inner.rows.get(key) {
-            if let Some(next) = self.next.get(key) {
-                Some(self.inner.schema.merge(prev.clone(), next.clone()))
-            } else {
-                Some(prev.clone())
-            }
-        } else {
-            self.next.get(key).cloned()
+        if self.removed.contains(key) {
+            return None;
         }
+
+        let row = match (self.inner.rows.get(key), self.next.get(key)) {
+            (Some(prev), Some(next)) =>
+                prev.clone().merge(&self.inner.schema, Row::new(next.clone(), self.stamp)),
+            (Some(prev), None) => prev.clone(),
+            (None, Some(next)) => Row::new(next.clone(), self.stamp),
+            (None, None) => return None,
+        };
+
+        row.into_visible()
     }
 
-    /// Adds an item to be merged when the transaction is complete
+    /// Adds an item to be merged when the transaction is complete. This cancels any earlier
+    /// removal of the same key in this transaction.
     pub fn add(&mut self, key: String, item: S::Item) {
+        self.removed.remove(&key);
+
         let next = match self.next.remove(&key) {
             Some(prev) => self.inner.schema.merge(prev, item),
             None => item,
@@ -438,13 +804,23 @@ impl<'t, S: 'static + Schema> Transaction<'t, S> {
         self.next.insert(key, next);
     }
 
+    /// Removes the row with the given key when the transaction is complete, discarding any items
+    /// added for that key earlier in this transaction. The removal leaves a tombstone, which
+    /// hides every write of this row the table has seen, and any other write with an older
+    /// stamp. Removing a key the table has never seen leaves no tombstone. See the module-level
+    /// documentation for more information.
+    pub fn remove(&mut self, key: String) {
+        self.next.remove(&key);
+        self.removed.insert(key);
+    }
+
     /// Rolls back the transaction, discarding any updates that were added. The table is unchanged.
     pub fn rollback(self) {
-        debug!("transaction {} rolled back", self.txid);
+        debug!("transaction {} rolled back", self.stamp.txid);
     }
 
     fn commit(mut self, raw_updates: &mut Vec<RawUpdate>) -> observe::Completion {
-        self.inner.commit_all_typed(self.txid, self.next, raw_updates)
+        self.inner.commit_all_typed(self.stamp, self.next, self.removed, raw_updates)
     }
 }
 
@@ -463,7 +839,9 @@ pub struct Update<S: Schema> {
     /// The item that was replaced, if such an item exists
     pub prev: Option<S::Item>,
     /// The new item
-    pub item: S::Item
+    pub item: S::Item,
+    /// Whether the row is removed as of this update. The item is then the removed contents.
+    pub removed: bool,
 }
 
 impl<S: Schema> fmt::Debug for Updates<S> {
@@ -474,7 +852,8 @@ impl<S: Schema> fmt::Debug for Updates<S> {
 
 impl<S: Schema> fmt::Debug for Update<S> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Update {{ key: {}, prev: {:?}, item: {:?} }}", self.key, self.prev, self.item)
+        write!(f, "Update {{ key: {}, prev: {:?}, item: {:?}, removed: {} }}",
+            self.key, self.prev, self.item, self.removed)
     }
 }
 
@@ -499,6 +878,10 @@ pub struct RawUpdate {
     pub prev: Option<Record>,
     /// The new item
     pub item: Record,
+    /// The stamp of the newest write to the item
+    pub written: Stamp,
+    /// The stamp of the newest removal of the item, if it has ever been removed
+    pub removed: Option<Stamp>,
 }
 
 /// A future that completes when a committed transaction has been observed by all observers.
@@ -506,6 +889,16 @@ pub struct Completion {
     inner: Option<Vec<observe::Completion>>
 }
 
+impl Completion {
+    /// Combines this completion with another, producing a completion that finishes when both
+    /// transactions have been fully observed.
+    pub fn and(self, other: Completion) -> Completion {
+        let mut inner = self.inner.unwrap_or_else(|| Vec::new());
+        inner.extend(other.inner.unwrap_or_else(|| Vec::new()));
+        Completion { inner: Some(inner) }
+    }
+}
+
 impl Future for Completion {
     type Item = ();
     type Error = ();
@@ -0,0 +1,558 @@
+//! Reusable schemas for common CRDTs
+//!
+//! Most tables want one of a small number of merge behaviors. Rather than implementing `Schema`
+//! by hand every time, the schemas in this module can be used directly:
+//!
+//!  * `LwwRegister`, a single value where the most recent write wins.
+//!
+//!  * `GCounter`, a counter that can only be incremented.
+//!
+//!  * `OrSet`, a set that supports both adding and removing elements. When an element is added on
+//!    one replica and concurrently removed on another, the add wins.
+//!
+//! `LwwRegister` and `OrSet` are generic over the values they hold, and are constructed with a
+//! schema for those values. The wrapped schema is only used to encode and decode values, and its
+//! `merge` is never called. `Text` is provided as a simple value schema for strings.
+//!
+//! Records from other replicas can't be trusted to be well formed. A record that is cut short
+//! decodes to a value that loses every merge: an empty counter or set, or a register value
+//! written at the oldest possible stamp.
+
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+
+use rand::random;
+
+use time;
+
+use common::Sid;
+use crdb::Record;
+use crdb::RecordError;
+use crdb::Schema;
+use crdb::Stamp;
+
+/// Strings, merged by taking the lexicographically greatest.
+pub struct Text;
+
+impl Schema for Text {
+    type Item = String;
+
+    fn encode(&self, item: &String) -> Record {
+        Record(item.clone().into_bytes())
+    }
+
+    fn decode(&self, data: &Record) -> String {
+        String::from_utf8_lossy(&data.0[..]).into_owned()
+    }
+
+    fn merge(&self, a: String, b: String) -> String {
+        if a > b { a } else { b }
+    }
+}
+
+/// A value tagged with the time it was written. See `LwwRegister`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LwwItem<T> {
+    stamp: Stamp,
+    value: T,
+}
+
+impl<T> LwwItem<T> {
+    /// Creates an item written at the current moment in time.
+    pub fn now(value: T) -> LwwItem<T> {
+        LwwItem::at(Stamp::now(), value)
+    }
+
+    /// Creates an item written at the given stamp.
+    pub fn at(stamp: Stamp, value: T) -> LwwItem<T> {
+        LwwItem { stamp: stamp, value: value }
+    }
+
+    /// Returns the stamp the value was written at.
+    pub fn stamp(&self) -> Stamp {
+        self.stamp
+    }
+
+    /// Returns a reference to the value.
+    pub fn value(&self) -> &T {
+        &self.value
+    }
+
+    /// Unwraps the value.
+    pub fn into_value(self) -> T {
+        self.value
+    }
+}
+
+/// A last-writer-wins register. Items are merged by keeping the one with the newest stamp. In the
+/// unlikely event that two different values carry the same stamp, the value with the greater
+/// encoding is kept.
+pub struct LwwRegister<S> {
+    inner: S,
+}
+
+impl<S: Schema> LwwRegister<S> {
+    /// Creates a register holding values encoded by the given schema.
+    pub fn new(inner: S) -> LwwRegister<S> {
+        LwwRegister { inner: inner }
+    }
+
+    fn read(&self, data: &Record) -> Result<LwwItem<S::Item>, RecordError> {
+        let mut r = Reader::new(data);
+        let stamp = try!(r.stamp());
+        let value = self.inner.decode(&Record(try!(r.bytes())));
+        Ok(LwwItem { stamp: stamp, value: value })
+    }
+}
+
+impl<S: Schema> Schema for LwwRegister<S> {
+    type Item = LwwItem<S::Item>;
+
+    fn encode(&self, item: &LwwItem<S::Item>) -> Record {
+        let mut w = Writer::new();
+        w.stamp(&item.stamp);
+        w.bytes(&self.inner.encode(&item.value).0[..]);
+        w.finish()
+    }
+
+    fn decode(&self, data: &Record) -> LwwItem<S::Item> {
+        self.read(data).unwrap_or_else(|e| {
+            warn!("unreadable register: {:?}", e);
+            let oldest = Stamp { time: time::Timespec::new(i64::min_value(), 0), txid: 0 };
+            LwwItem { stamp: oldest, value: self.inner.decode(&Record(Vec::new())) }
+        })
+    }
+
+    fn merge(&self, a: LwwItem<S::Item>, b: LwwItem<S::Item>) -> LwwItem<S::Item> {
+        match a.stamp.cmp(&b.stamp) {
+            Ordering::Greater => a,
+            Ordering::Less => b,
+            Ordering::Equal => {
+                if self.inner.encode(&a.value).0 >= self.inner.encode(&b.value).0 { a } else { b }
+            },
+        }
+    }
+}
+
+/// The per-server counts making up a grow-only counter. See `GCounter`.
+#[derive(Clone, Debug, PartialEq, Eq, Default)]
+pub struct GCounterItem {
+    counts: BTreeMap<Sid, u64>,
+}
+
+impl GCounterItem {
+    /// Creates a counter with a value of zero.
+    pub fn new() -> GCounterItem {
+        GCounterItem { counts: BTreeMap::new() }
+    }
+
+    /// Adds `n` to the counter on behalf of the given server.
+    pub fn increment(&mut self, sid: Sid, n: u64) {
+        *self.counts.entry(sid).or_insert(0) += n;
+    }
+
+    /// Returns the value of the counter.
+    pub fn value(&self) -> u64 {
+        self.counts.values().sum()
+    }
+}
+
+/// A grow-only counter. Each server only increments its own count, and counters are merged by
+/// taking the largest count seen for each server.
+pub struct GCounter;
+
+impl GCounter {
+    fn read(&self, data: &Record) -> Result<GCounterItem, RecordError> {
+        let mut r = Reader::new(data);
+        let mut item = GCounterItem::new();
+        for _ in 0..try!(r.u32()) {
+            let sid = Sid::try_from(&try!(r.bytes())[..]);
+            let count = try!(r.u64());
+            match sid {
+                Some(sid) => { item.counts.insert(sid, count); },
+                None => warn!("discarding count for invalid server ID"),
+            }
+        }
+        Ok(item)
+    }
+}
+
+impl Schema for GCounter {
+    type Item = GCounterItem;
+
+    fn encode(&self, item: &GCounterItem) -> Record {
+        let mut w = Writer::new();
+        w.u32(item.counts.len() as u32);
+        for (sid, count) in item.counts.iter() {
+            w.bytes(&Vec::from(*sid)[..]);
+            w.u64(*count);
+        }
+        w.finish()
+    }
+
+    fn decode(&self, data: &Record) -> GCounterItem {
+        self.read(data).unwrap_or_else(|e| {
+            warn!("unreadable counter: {:?}", e);
+            GCounterItem::new()
+        })
+    }
+
+    fn merge(&self, a: GCounterItem, b: GCounterItem) -> GCounterItem {
+        let mut counts = a.counts;
+        for (sid, count) in b.counts.into_iter() {
+            let c = counts.entry(sid).or_insert(0);
+            if *c < count {
+                *c = count;
+            }
+        }
+        GCounterItem { counts: counts }
+    }
+}
+
+/// The elements of an observed-remove set, along with the bookkeeping needed to merge them. See
+/// `OrSet`.
+#[derive(Clone, Debug, PartialEq, Eq, Default)]
+pub struct OrSetItem<T: Ord> {
+    added: BTreeMap<T, BTreeSet<u64>>,
+    removed: BTreeSet<u64>,
+}
+
+impl<T: Ord + Clone> OrSetItem<T> {
+    /// Creates an empty set.
+    pub fn new() -> OrSetItem<T> {
+        OrSetItem { added: BTreeMap::new(), removed: BTreeSet::new() }
+    }
+
+    /// Adds an element to the set. Every add is tagged uniquely, so a concurrent removal of the
+    /// same element elsewhere will not cancel it.
+    pub fn insert(&mut self, elem: T) {
+        self.added.entry(elem).or_default().insert(random());
+    }
+
+    /// Removes an element from the set. Only the adds of the element that this set has seen are
+    /// canceled.
+    pub fn remove(&mut self, elem: &T) {
+        if let Some(tags) = self.added.remove(elem) {
+            self.removed.extend(tags);
+        }
+    }
+
+    /// Determines whether the element is in the set.
+    pub fn contains(&self, elem: &T) -> bool {
+        self.added.contains_key(elem)
+    }
+
+    /// Returns the elements of the set, in order.
+    pub fn elements(&self) -> Vec<&T> {
+        self.added.keys().collect()
+    }
+}
+
+/// An observed-remove set. A removal only cancels the adds it has seen, so when an element is
+/// concurrently added and removed, the element stays in the set.
+pub struct OrSet<S> {
+    inner: S,
+}
+
+impl<S: Schema> OrSet<S> {
+    /// Creates a set of elements encoded by the given schema.
+    pub fn new(inner: S) -> OrSet<S> {
+        OrSet { inner: inner }
+    }
+}
+
+impl<S: Schema> OrSet<S> where S::Item: Ord {
+    fn read(&self, data: &Record) -> Result<OrSetItem<S::Item>, RecordError> {
+        let mut r = Reader::new(data);
+        let mut item = OrSetItem::new();
+        for _ in 0..try!(r.u32()) {
+            let elem = self.inner.decode(&Record(try!(r.bytes())));
+            let tags = try!(r.tags());
+            item.added.insert(elem, tags);
+        }
+        item.removed = try!(r.tags());
+        Ok(item)
+    }
+}
+
+impl<S: Schema> Schema for OrSet<S> where S::Item: Ord {
+    type Item = OrSetItem<S::Item>;
+
+    fn encode(&self, item: &OrSetItem<S::Item>) -> Record {
+        let mut w = Writer::new();
+        w.u32(item.added.len() as u32);
+        for (elem, tags) in item.added.iter() {
+            w.bytes(&self.inner.encode(elem).0[..]);
+            w.tags(tags);
+        }
+        w.tags(&item.removed);
+        w.finish()
+    }
+
+    fn decode(&self, data: &Record) -> OrSetItem<S::Item> {
+        self.read(data).unwrap_or_else(|e| {
+            warn!("unreadable set: {:?}", e);
+            OrSetItem::new()
+        })
+    }
+
+    fn merge(&self, a: OrSetItem<S::Item>, b: OrSetItem<S::Item>) -> OrSetItem<S::Item> {
+        let mut removed = a.removed;
+        removed.extend(b.removed);
+
+        let mut added = a.added;
+        for (elem, tags) in b.added.into_iter() {
+            added.entry(elem).or_default().extend(tags);
+        }
+
+        let added = added.into_iter()
+            .map(|(elem, tags)| (elem, &tags - &removed))
+            .filter(|(_, tags)| !tags.is_empty())
+            .collect();
+
+        OrSetItem { added: added, removed: removed }
+    }
+}
+
+struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    fn new() -> Writer {
+        Writer { buf: Vec::new() }
+    }
+
+    fn u32(&mut self, x: u32) {
+        for i in (0..4).rev() {
+            self.buf.push((x >> (i * 8)) as u8);
+        }
+    }
+
+    fn u64(&mut self, x: u64) {
+        for i in (0..8).rev() {
+            self.buf.push((x >> (i * 8)) as u8);
+        }
+    }
+
+    fn bytes(&mut self, b: &[u8]) {
+        self.u32(b.len() as u32);
+        self.buf.extend_from_slice(b);
+    }
+
+    fn stamp(&mut self, stamp: &Stamp) {
+        self.u64(stamp.time.sec as u64);
+        self.u32(stamp.time.nsec as u32);
+        self.u64(stamp.txid);
+    }
+
+    fn tags(&mut self, tags: &BTreeSet<u64>) {
+        self.u32(tags.len() as u32);
+        for tag in tags.iter() {
+            self.u64(*tag);
+        }
+    }
+
+    fn finish(self) -> Record {
+        Record(self.buf)
+    }
+}
+
+struct Reader<'r> {
+    data: &'r [u8],
+}
+
+impl<'r> Reader<'r> {
+    fn new(record: &'r Record) -> Reader<'r> {
+        Reader { data: &record.0[..] }
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'r [u8], RecordError> {
+        if self.data.len() < n {
+            return Err(RecordError::Truncated);
+        }
+
+        let (head, tail) = self.data.split_at(n);
+        self.data = tail;
+        Ok(head)
+    }
+
+    fn u32(&mut self) -> Result<u32, RecordError> {
+        Ok(try!(self.take(4)).iter().fold(0, |x, b| (x << 8) | (*b as u32)))
+    }
+
+    fn u64(&mut self) -> Result<u64, RecordError> {
+        Ok(try!(self.take(8)).iter().fold(0, |x, b| (x << 8) | (*b as u64)))
+    }
+
+    fn bytes(&mut self) -> Result<Vec<u8>, RecordError> {
+        let n = try!(self.u32()) as usize;
+        Ok(try!(self.take(n)).to_vec())
+    }
+
+    fn stamp(&mut self) -> Result<Stamp, RecordError> {
+        let sec = try!(self.u64()) as i64;
+        let nsec = try!(self.u32()) as i32;
+        let txid = try!(self.u64());
+        Ok(Stamp { time: time::Timespec { sec: sec, nsec: nsec }, txid: txid })
+    }
+
+    fn tags(&mut self) -> Result<BTreeSet<u64>, RecordError> {
+        let n = try!(self.u32());
+        (0..n).map(|_| self.u64()).collect()
+    }
+}
+
+#[cfg(test)]
+fn stamp_at(sec: i64, txid: u64) -> Stamp {
+    Stamp { time: time::Timespec { sec: sec, nsec: 0 }, txid: txid }
+}
+
+#[cfg(test)]
+fn permutations(n: usize) -> Vec<Vec<usize>> {
+    if n == 0 {
+        return vec![Vec::new()];
+    }
+
+    let mut all = Vec::new();
+    for perm in permutations(n - 1) {
+        for i in 0..n {
+            let mut p = perm.clone();
+            p.insert(i, n - 1);
+            all.push(p);
+        }
+    }
+    all
+}
+
+#[cfg(test)]
+fn assert_converges<S: Schema>(schema: &S, items: Vec<S::Item>) where S::Item: PartialEq {
+    let mut results = Vec::new();
+
+    for order in permutations(items.len()) {
+        // merge in this order from the left, round-tripping through the encoding as we go
+        let left = order.iter().map(|i| items[*i].clone()).fold(None, |acc, x| match acc {
+            None => Some(x),
+            Some(acc) => Some(schema.decode(&schema.encode(&schema.merge(acc, x)))),
+        });
+
+        // and from the right, to shake out any dependence on which side is which
+        let right = order.iter().rev().map(|i| items[*i].clone()).fold(None, |acc, x| match acc {
+            None => Some(x),
+            Some(acc) => Some(schema.merge(x, acc)),
+        });
+
+        results.push(left.unwrap());
+        results.push(right.unwrap());
+    }
+
+    for r in results.iter() {
+        assert_eq!(*r, results[0]);
+    }
+}
+
+#[test]
+fn lww_newest_wins() {
+    let reg = LwwRegister::new(Text);
+    let old = LwwItem::at(stamp_at(1, 5), "old".to_string());
+    let new = LwwItem::at(stamp_at(2, 1), "new".to_string());
+
+    assert_eq!(reg.merge(old.clone(), new.clone()).value(), "new");
+    assert_eq!(reg.merge(new.clone(), old.clone()).value(), "new");
+    assert_eq!(reg.decode(&reg.encode(&new)), new);
+}
+
+#[test]
+fn lww_converges() {
+    assert_converges(&LwwRegister::new(Text), vec![
+        LwwItem::at(stamp_at(1, 0), "a".to_string()),
+        LwwItem::at(stamp_at(3, 0), "b".to_string()),
+        LwwItem::at(stamp_at(3, 0), "c".to_string()),
+        LwwItem::at(stamp_at(2, 9), "d".to_string()),
+    ]);
+}
+
+#[test]
+fn gcounter_counts() {
+    let a_sid = Sid::new("0AA");
+    let b_sid = Sid::new("0BB");
+
+    let mut a = GCounterItem::new();
+    a.increment(a_sid, 3);
+    let mut b = a.clone();
+    a.increment(a_sid, 2);
+    b.increment(b_sid, 4);
+
+    let merged = GCounter.merge(a.clone(), b.clone());
+    assert_eq!(merged.value(), 9);
+    assert_eq!(GCounter.decode(&GCounter.encode(&merged)), merged);
+
+    assert_converges(&GCounter, vec![a, b, merged, GCounterItem::new()]);
+}
+
+#[test]
+fn or_set_add_wins() {
+    let set = OrSet::new(Text);
+    let x = "x".to_string();
+    let y = "y".to_string();
+
+    let mut base = OrSetItem::new();
+    base.insert(x.clone());
+    base.insert(y.clone());
+
+    // a removes x, and concurrently b adds x again
+    let mut a = base.clone();
+    a.remove(&x);
+    let mut b = base.clone();
+    b.insert(x.clone());
+
+    let merged = set.merge(a.clone(), b.clone());
+    assert!(merged.contains(&x));
+    assert!(merged.contains(&y));
+
+    // removing y is seen by everybody
+    let mut c = base.clone();
+    c.remove(&y);
+    let merged = set.merge(merged, c.clone());
+    assert!(!merged.contains(&y));
+    assert_eq!(merged.elements(), vec![&x]);
+    assert_eq!(set.decode(&set.encode(&merged)), merged);
+
+    assert_converges(&set, vec![base, a, b, c]);
+}
+
+#[test]
+fn truncated_records() {
+    let reg = LwwRegister::new(Text);
+    let item = LwwItem::at(stamp_at(1, 5), "value".to_string());
+    let data = reg.encode(&item).0;
+    for n in 0..data.len() {
+        let cut = reg.decode(&Record(data[..n].to_vec()));
+        assert_eq!(reg.merge(cut.clone(), item.clone()), item);
+        assert_eq!(reg.merge(item.clone(), cut), item);
+    }
+
+    let mut counter = GCounterItem::new();
+    counter.increment(Sid::new("0AA"), 3);
+    let data = GCounter.encode(&counter).0;
+    for n in 0..data.len() {
+        assert_eq!(GCounter.decode(&Record(data[..n].to_vec())), GCounterItem::new());
+    }
+
+    let set = OrSet::new(Text);
+    let mut elems = OrSetItem::new();
+    elems.insert("x".to_string());
+    elems.remove(&"x".to_string());
+    elems.insert("y".to_string());
+    let data = set.encode(&elems).0;
+    for n in 0..data.len() {
+        assert_eq!(set.decode(&Record(data[..n].to_vec())), OrSetItem::new());
+    }
+
+    // a count promising far more than the record holds stops at the end of the record
+    let mut huge = vec![0xff; 4];
+    huge.extend(GCounter.encode(&counter).0);
+    assert_eq!(GCounter.decode(&Record(huge)), GCounterItem::new());
+    assert_eq!(reg.decode(&Record(Vec::new())).value(), "");
+}
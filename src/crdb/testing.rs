@@ -0,0 +1,43 @@
+//! Helpers for testing `Schema` implementations
+//!
+//! A `merge` that is not idempotent, commutative, and associative will cause replicas to quietly
+//! diverge, and such bugs are very hard to track down after the fact. `assert_crdt_laws` checks
+//! these properties directly, and is meant to be called from the tests of every schema.
+
+use std::fmt::Debug;
+
+use crdb::Schema;
+
+/// Checks that `schema`'s merge function obeys the CRDT laws over every combination of the given
+/// samples, panicking with a description of the first violation found. The checks are exhaustive,
+/// so the number of merges performed grows with the cube of the number of samples. A handful of
+/// carefully chosen samples is better than a large number of random ones.
+pub fn assert_crdt_laws<S: Schema>(schema: &S, samples: &[S::Item]) where S::Item: Eq {
+    for a in samples.iter() {
+        check("idempotent", &[a], schema.merge(a.clone(), a.clone()), a.clone());
+    }
+
+    for a in samples.iter() {
+        for b in samples.iter() {
+            let ab = schema.merge(a.clone(), b.clone());
+            let ba = schema.merge(b.clone(), a.clone());
+            check("commutative", &[a, b], ab, ba);
+        }
+    }
+
+    for a in samples.iter() {
+        for b in samples.iter() {
+            for c in samples.iter() {
+                let a_bc = schema.merge(a.clone(), schema.merge(b.clone(), c.clone()));
+                let ab_c = schema.merge(schema.merge(a.clone(), b.clone()), c.clone());
+                check("associative", &[a, b, c], a_bc, ab_c);
+            }
+        }
+    }
+}
+
+fn check<T: Eq + Debug>(law: &str, inputs: &[&T], left: T, right: T) {
+    if left != right {
+        panic!("merge is not {}: inputs {:?} gave {:?} and {:?}", law, inputs, left, right);
+    }
+}
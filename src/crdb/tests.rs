@@ -27,6 +27,27 @@ impl Schema for Max {
     fn merge(&self, a: u8, b: u8) -> u8 { if a > b { a } else { b } }
 }
 
+// not a CRDT at all, for making sure the law checks catch it
+struct Subtract;
+
+impl Schema for Subtract {
+    type Item = u8;
+    fn encode(&self, item: &u8) -> Record { Record(Vec::from(&[*item][..])) }
+    fn decode(&self, data: &Record) -> u8 { data.0[0] }
+    fn merge(&self, a: u8, b: u8) -> u8 { a.wrapping_sub(b) }
+}
+
+// only accepts small items from raw transactions
+struct Small;
+
+impl Schema for Small {
+    type Item = u8;
+    fn encode(&self, item: &u8) -> Record { Record(Vec::from(&[*item][..])) }
+    fn decode(&self, data: &Record) -> u8 { data.0[0] }
+    fn merge(&self, a: u8, b: u8) -> u8 { if a > b { a } else { b } }
+    fn accept(&self, item: &u8) -> bool { *item < 100 }
+}
+
 struct Finish {
     raw_updates: Vec<Rc<RawUpdates>>,
     min_updates: Vec<Rc<Updates<Min>>>,
@@ -362,3 +383,489 @@ fn test_completion() {
     assert!(order_data[0] == "raw update" || order_data[0] == "min update");
     assert!(order_data[1] == "raw update" || order_data[1] == "min update");
 }
+
+fn replicate(updates: &RawUpdates, db: &mut CRDB) {
+    let mut tx = RawTransaction::new();
+    for u in updates.updates.iter() {
+        tx.add_stamped(u.table.clone(), u.key.clone(), u.item.clone(), u.written, u.removed);
+    }
+    db.commit_raw(tx);
+}
+
+#[test]
+fn remove_row() {
+    let fin = with_test_crdb(|db, min, _max| {
+        {
+            let mut tx = min.open();
+            tx.add("a".to_string(), 10);
+            tx.add("b".to_string(), 15);
+            db.commit(tx);
+        }
+
+        {
+            let mut tx = min.open();
+            tx.remove("a".to_string());
+            assert_eq!(tx.get("a"), None);
+            assert_eq!(tx.get("b"), Some(15));
+            db.commit(tx);
+        }
+
+        assert_eq!(min.get("a"), None);
+    });
+
+    assert_eq!(fin.raw_updates.len(), 2);
+    assert_eq!(fin.raw_updates[1].updates.len(), 1);
+    assert!(fin.raw_updates[1].updates[0].removed.is_some());
+
+    assert_eq!(fin.min_updates.len(), 2);
+    assert_eq!(fin.min_updates[1].updates.len(), 1);
+    assert_update(&fin.min_updates[1].updates[0], "a", Some(10), 10);
+    assert!(fin.min_updates[1].updates[0].removed);
+    assert_eq!(fin.min_finish.len(), 1);
+    assert_eq!(fin.min_finish.get("b"), Some(&15));
+}
+
+#[test]
+fn remove_unseen_row() {
+    let mut db = CRDB::new();
+    let mut min = db.create_table("min", Min);
+
+    {
+        let mut tx = min.open();
+        tx.remove("a".to_string());
+        db.commit(tx);
+    }
+
+    // there was nothing to leave a tombstone on, so even an older write shows up afterwards
+    let older = Stamp { time: time::Timespec::new(0, 0), txid: 0 };
+    let mut tx = RawTransaction::new();
+    tx.add_stamped("min".to_string(), "a".to_string(), Min.encode(&10), older, None);
+    db.commit_raw(tx);
+
+    assert_eq!(min.get("a"), Some(10));
+}
+
+#[test]
+fn remove_row_from_the_future() {
+    let mut db = CRDB::new();
+    let mut min = db.create_table("min", Min);
+    let raw_observer = db.updates().map(|obs| obs.into_inner()).collect();
+
+    // a peer with a fast clock wrote the row
+    let mut later = time::get_time();
+    later.sec += 3600;
+    let written = Stamp { time: later, txid: 7 };
+    let mut tx = RawTransaction::new();
+    tx.add_stamped("min".to_string(), "a".to_string(), Min.encode(&10), written, None);
+    db.commit_raw(tx);
+    assert_eq!(min.get("a"), Some(10));
+
+    {
+        let mut tx = min.open();
+        tx.remove("a".to_string());
+        db.commit(tx);
+    }
+    assert_eq!(min.get("a"), None);
+
+    drop(db);
+
+    let raw_updates = raw_observer.wait().expect("raw_observer");
+    let removed = raw_updates[1].updates[0].removed;
+    assert!(removed.map(|r| r > written).unwrap_or(false));
+    assert_eq!(removed.map(|r| r.txid()), Some(raw_updates[1].txid));
+}
+
+#[test]
+fn remove_then_add_in_same_transaction() {
+    let fin = with_test_crdb(|db, min, _max| {
+        let mut tx = min.open();
+        tx.add("a".to_string(), 10);
+        tx.remove("a".to_string());
+        tx.remove("never-seen".to_string());
+        assert_eq!(tx.get("a"), None);
+        tx.add("a".to_string(), 12);
+        assert_eq!(tx.get("a"), Some(12));
+        db.commit(tx);
+    });
+
+    assert_eq!(fin.min_updates.len(), 1);
+    assert_eq!(fin.min_updates[0].updates.len(), 1);
+    assert_update(&fin.min_updates[0].updates[0], "a", None, 12);
+    assert_eq!(fin.min_finish.len(), 1);
+    assert_eq!(fin.min_finish.get("a"), Some(&12));
+}
+
+#[test]
+fn remove_and_concurrent_readd_converge() {
+    let mut a = CRDB::new();
+    let mut a_max = a.create_table("max", Max);
+    let mut a_raw = a.updates().wait();
+
+    let mut b = CRDB::new();
+    let mut b_max = b.create_table("max", Max);
+    let mut b_raw = b.updates().wait();
+
+    // both replicas start out with the same row
+    {
+        let mut tx = a_max.open();
+        tx.add("k".to_string(), 5);
+        a.commit(tx);
+    }
+    let first = a_raw.next().unwrap().unwrap();
+    replicate(&first, &mut b);
+    b_raw.next().unwrap().unwrap();
+    assert_eq!(b_max.get("k"), Some(5));
+
+    // a removes the row, and concurrently b writes to it
+    let removal = {
+        let mut tx = a_max.open();
+        tx.remove("k".to_string());
+        a.commit(tx);
+        a_raw.next().unwrap().unwrap()
+    };
+    let readd = {
+        let mut tx = b_max.open();
+        tx.add("k".to_string(), 7);
+        b.commit(tx);
+        b_raw.next().unwrap().unwrap()
+    };
+
+    replicate(&removal, &mut b);
+    replicate(&readd, &mut a);
+
+    let removed_at = removal.updates[0].removed.expect("removal stamp");
+    let written_at = readd.updates[0].written;
+    let expected = if written_at > removed_at { Some(7) } else { None };
+
+    assert_eq!(a_max.get("k"), expected);
+    assert_eq!(b_max.get("k"), expected);
+}
+
+#[test]
+fn snapshot_raw_bootstraps_replica() {
+    let mut a = CRDB::new();
+    let mut a_min = a.create_table("min", Min);
+    let mut a_max = a.create_table("max", Max);
+
+    {
+        let mut tx = a_min.open();
+        tx.add("a".to_string(), 10);
+        tx.add("b".to_string(), 15);
+        a.commit(tx);
+    }
+    {
+        let mut tx = a_min.open();
+        tx.remove("b".to_string());
+        a.commit(tx);
+    }
+    {
+        let mut tx = a_max.open();
+        tx.add("c".to_string(), 20);
+        a.commit(tx);
+    }
+    {
+        let mut tx = RawTransaction::new();
+        tx.add("later".to_string(), "d".to_string(), Max.encode(&30));
+        a.commit_raw(tx);
+    }
+
+    let snapshot = a.snapshot_raw();
+    assert_eq!(snapshot.len(), 4);
+    assert!(snapshot.iter().all(|u| u.prev.is_none()));
+
+    let mut b = CRDB::new();
+    let b_min = b.create_table("min", Min);
+    let b_max = b.create_table("max", Max);
+    b.commit_raw(RawTransaction::from_updates(snapshot));
+
+    assert_eq!(b_min.snapshot(), a_min.snapshot());
+    assert_eq!(b_max.snapshot(), a_max.snapshot());
+
+    // the tombstone for the removed row carries over too
+    assert_eq!(b.snapshot_raw().iter().filter(|u| u.removed.is_some()).count(), 1);
+
+    let b_later = b.create_table("later", Max);
+    assert_eq!(b_later.get("d"), Some(30));
+}
+
+#[test]
+fn raw_transaction_from_updates() {
+    let mut a = CRDB::new();
+    let mut a_min = a.create_table("min", Min);
+    let mut a_raw = a.updates().wait();
+
+    let mut b = CRDB::new();
+    let b_min = b.create_table("min", Min);
+    let b_max = b.create_table("max", Max);
+
+    {
+        let mut tx = a_min.open();
+        tx.add("a".to_string(), 10);
+        tx.add("b".to_string(), 15);
+        a.commit(tx);
+    }
+    {
+        let mut tx = a_min.open();
+        tx.remove("b".to_string());
+        a.commit(tx);
+    }
+
+    let mut updates = Vec::new();
+    for _ in 0..2 {
+        let raw = Rc::try_unwrap(a_raw.next().unwrap().unwrap().into_inner());
+        updates.extend(raw.expect("only observer").updates);
+    }
+
+    // updates to the same row, and to other tables, can all go in one transaction
+    let written = updates[0].written;
+    updates.push(RawUpdate {
+        table: "min".to_string(),
+        key: "a".to_string(),
+        prev: None,
+        item: Min.encode(&5),
+        written: written,
+        removed: None,
+    });
+    updates.push(RawUpdate {
+        table: "max".to_string(),
+        key: "c".to_string(),
+        prev: None,
+        item: Max.encode(&20),
+        written: written,
+        removed: None,
+    });
+
+    b.commit_raw(RawTransaction::from_updates(updates));
+
+    assert_eq!(b_min.snapshot(), vec![("a".to_string(), 5)].into_iter().collect());
+    assert_eq!(b_max.snapshot(), vec![("c".to_string(), 20)].into_iter().collect());
+}
+
+#[test]
+fn versioned_records() {
+    let record = Record::versioned(2, b"abc");
+    assert_eq!(record.0, b"\x02abc".to_vec());
+    assert_eq!(record.split_version(2), Ok((2, &b"abc"[..])));
+    assert_eq!(record.split_version(3), Ok((2, &b"abc"[..])));
+    assert_eq!(record.split_version(1), Err(RecordError::SchemaVersion(2)));
+    assert_eq!(Record::versioned(1, b"").split_version(1), Ok((1, &b""[..])));
+    assert_eq!(Record(Vec::new()).split_version(1), Err(RecordError::Empty));
+}
+
+#[test]
+fn drop_table() {
+    let mut db = CRDB::new();
+    let min = db.create_table("min", Min);
+
+    let mut tx = RawTransaction::new();
+    tx.add("min".to_string(), "a".to_string(), Min.encode(&12));
+    db.commit_raw(tx);
+    assert_eq!(min.get("a"), Some(12));
+
+    assert!(db.drop_table("min"));
+    assert!(!db.drop_table("min"));
+
+    let mut tx = RawTransaction::new();
+    tx.add("min".to_string(), "b".to_string(), Min.encode(&12));
+    db.commit_raw(tx);
+    assert_eq!(min.get("b"), None);
+
+    // the new table doesn't inherit the old contents, but does pick up writes since the drop
+    let min = db.create_table("min", Min);
+    assert_eq!(min.get("a"), None);
+    assert_eq!(min.get("b"), Some(12));
+}
+
+#[test]
+fn enumerate_table() {
+    let fin = with_test_crdb(|db, min, _max| {
+        {
+            let mut tx = min.open();
+            tx.add("a".to_string(), 10);
+            tx.add("b".to_string(), 15);
+            db.commit(tx);
+        }
+
+        {
+            let mut tx = min.open();
+            tx.add("a".to_string(), 5);
+            tx.add("c".to_string(), 20);
+            db.commit(tx);
+        }
+
+        {
+            let mut tx = RawTransaction::new();
+            tx.add("min".to_string(), "b".to_string(), Min.encode(&12));
+            tx.add("min".to_string(), "d".to_string(), Min.encode(&1));
+            db.commit_raw(tx);
+        }
+
+        {
+            let mut tx = min.open();
+            tx.remove("d".to_string());
+            db.commit(tx);
+        }
+
+        let mut keys = min.keys();
+        keys.sort();
+        assert_eq!(keys, vec!["a", "b", "c"]);
+
+        let mut rows: Vec<(String, u8)> = min.iter().collect();
+        rows.sort();
+        assert_eq!(rows, vec![
+            ("a".to_string(), 5),
+            ("b".to_string(), 12),
+            ("c".to_string(), 20),
+        ]);
+    });
+
+    assert_eq!(fin.min_finish.len(), 3);
+}
+
+#[test]
+fn updates_with_snapshot() {
+    let mut db = CRDB::new();
+    let mut max = db.create_table("max", Max);
+
+    {
+        let mut tx = max.open();
+        tx.add("a".to_string(), 10);
+        tx.add("b".to_string(), 20);
+        db.commit(tx);
+    }
+
+    let (mut snapshot, observer) = max.updates_with_snapshot();
+    let observer = observer.map(|obs| obs.into_inner()).collect();
+
+    {
+        let mut tx = max.open();
+        tx.add("a".to_string(), 15);
+        tx.add("c".to_string(), 30);
+        db.commit(tx);
+    }
+
+    drop(db);
+    drop(max);
+
+    snapshot.sort();
+    assert_eq!(snapshot, vec![("a".to_string(), 10), ("b".to_string(), 20)]);
+
+    let updates = observer.wait().expect("observer");
+    assert_eq!(updates.len(), 1);
+
+    let mut changed: Vec<(String, u8)> = updates[0].updates.iter()
+        .map(|u| (u.key.clone(), u.item))
+        .collect();
+    changed.sort();
+    assert_eq!(changed, vec![("a".to_string(), 15), ("c".to_string(), 30)]);
+}
+
+#[test]
+fn commit_raw_before_create_table() {
+    let mut db = CRDB::new();
+    let raw_observer = db.updates().map(|obs| obs.into_inner()).collect();
+
+    {
+        let mut tx = RawTransaction::new();
+        tx.add("x".to_string(), "a".to_string(), Max.encode(&10));
+        tx.add("x".to_string(), "b".to_string(), Max.encode(&20));
+        db.commit_raw(tx);
+    }
+
+    let x = db.create_table("x", Max);
+    assert_eq!(x.get("a"), Some(10));
+    assert_eq!(x.get("b"), Some(20));
+
+    {
+        let mut tx = RawTransaction::new();
+        tx.add("x".to_string(), "a".to_string(), Max.encode(&5));
+        tx.add("x".to_string(), "c".to_string(), Max.encode(&30));
+        db.commit_raw(tx);
+    }
+
+    assert_eq!(x.get("a"), Some(10));
+    assert_eq!(x.get("b"), Some(20));
+    assert_eq!(x.get("c"), Some(30));
+
+    drop(db);
+
+    // the deferred items are only published once they're actually applied
+    let raw_updates = raw_observer.wait().expect("raw_observer");
+    let counts: Vec<usize> = raw_updates.iter().map(|u| u.updates.len()).collect();
+    assert_eq!(counts, vec![2, 2]);
+}
+
+#[test]
+fn raw_items_must_be_accepted() {
+    let mut db = CRDB::new();
+    let raw_observer = db.updates().map(|obs| obs.into_inner()).collect();
+    let mut small = db.create_table("small", Small);
+
+    {
+        let mut tx = RawTransaction::new();
+        tx.add("small".to_string(), "a".to_string(), Small.encode(&10));
+        tx.add("small".to_string(), "a".to_string(), Small.encode(&200));
+        tx.add("small".to_string(), "b".to_string(), Small.encode(&150));
+        db.commit_raw(tx);
+    }
+
+    // rejected items are dropped before they can win a merge, or create a row
+    assert_eq!(small.get("a"), Some(10));
+    assert_eq!(small.get("b"), None);
+    assert_eq!(small.keys(), vec!["a".to_string()]);
+
+    // local writes are not checked
+    {
+        let mut tx = small.open();
+        tx.add("b".to_string(), 150);
+        db.commit(tx);
+    }
+    assert_eq!(small.get("b"), Some(150));
+
+    drop(db);
+
+    let raw_updates = raw_observer.wait().expect("raw_observer");
+    let counts: Vec<usize> = raw_updates.iter().map(|u| u.updates.len()).collect();
+    assert_eq!(counts, vec![1, 1]);
+}
+
+#[test]
+fn pending_commits_are_bounded() {
+    let mut db = CRDB::new();
+
+    for i in 0..MAX_PENDING_ROWS + 1 {
+        let mut tx = RawTransaction::new();
+        tx.add("x".to_string(), format!("{}", i), Max.encode(&1));
+        db.commit_raw(tx);
+    }
+
+    // the oldest commit made way for the newest
+    assert_eq!(db.pending.len(), MAX_PENDING_ROWS);
+    assert_eq!(db.pending_rows, MAX_PENDING_ROWS);
+    let x = db.create_table("x", Max);
+    assert_eq!(x.get("0"), None);
+    assert_eq!(x.get(&format!("{}", MAX_PENDING_ROWS)), Some(1));
+    assert_eq!(db.pending_rows, 0);
+
+    // dropping a table that was never created discards what was held for it
+    let mut tx = RawTransaction::new();
+    tx.add("y".to_string(), "a".to_string(), Max.encode(&1));
+    db.commit_raw(tx);
+    assert!(!db.drop_table("y"));
+    assert!(db.pending.is_empty());
+    assert_eq!(db.pending_rows, 0);
+    assert_eq!(db.create_table("y", Max).get("a"), None);
+}
+
+#[test]
+fn crdt_laws() {
+    let samples = [0, 1, 7, 100, 255];
+    testing::assert_crdt_laws(&Min, &samples);
+    testing::assert_crdt_laws(&Max, &samples);
+}
+
+#[test]
+#[should_panic(expected = "merge is not")]
+fn crdt_laws_broken_schema() {
+    testing::assert_crdt_laws(&Subtract, &[0, 1, 7]);
+}
@@ -1,49 +1,179 @@
 //! Active (fully-registered) client connection handling
 
+use std::collections::HashSet;
+use std::str;
+
+use futures::Future;
+use futures::future;
+
+use common::casemap::irc_lower;
+use common::observe;
+use crdb;
 use irc;
 use irc::driver::Client;
+use irc::numeric::*;
+use irc::pool::Pool;
 use irc::send::Sender;
+use irc::Message;
+use irc::message::MAX_TARGETS;
 
 use world::World;
 
-/// An active client
+/// An active client, known to the world by its user identity
 pub struct Active {
     world: World,
-    _out: Sender,
-    nick: String,
+    out: Sender,
+    user: String,
+    pool: Option<Pool>,
 }
 
 impl Active {
     /// Creates a new `Active`
-    pub fn new(world: World, out: Sender, nick: String) -> Active {
-        Active { world: world, _out: out, nick: nick }
+    pub fn new(world: World, out: Sender, user: String) -> Active {
+        Active { world: world, out: out, user: user, pool: None }
+    }
+
+    /// Creates a new `Active` that delivers messages to the other local clients in `pool`
+    pub fn with_pool(world: World, out: Sender, user: String, pool: Pool) -> Active {
+        Active { world: world, out: out, user: user, pool: Some(pool) }
+    }
+
+    /// Returns the client's user identity
+    pub fn user(&self) -> &str {
+        &self.user
+    }
+
+    /// Returns the client's nickname, or its identity if it has lost its nickname
+    fn nick(&self) -> String {
+        self.world.nick(&self.user).unwrap_or_else(|| self.user.clone())
     }
 
     pub fn handle(self, m: irc::Message) -> irc::Op<Client> {
         self.handle_easy(m).map(Client::Active)
     }
 
+    /// Handles the client closing its end of the connection. Anything still queued is sent,
+    /// followed by an `ERROR` to say goodbye.
+    pub fn handle_eof(mut self) {
+        let _ = self.out.send(b"ERROR :Closing link (Connection closed)\r\n");
+        self.out.close_soft();
+    }
+
     fn handle_easy(mut self, m: irc::Message) -> irc::Op<Active> {
         debug!(" -> {:?}", m);
 
         match &m.verb[..] {
             b"JOIN" => {
-                let chan = "#foo".to_string();
-                let op = self.world.join_user(chan, self.nick.clone());
-                irc::Op::crdb(op, self)
+                let chans = match arg(&m, 0) {
+                    Some(chans) => chans,
+                    None => return irc::Op::ok(self),
+                };
+
+                let mut op: Option<crdb::Completion> = None;
+                for chan in chans.split(',').filter(|c| !c.is_empty()) {
+                    let joined = self.world.join_user(chan.to_string(), self.user.clone());
+                    op = Some(match op {
+                        Some(op) => op.and(joined),
+                        None => joined,
+                    });
+                }
+
+                match op {
+                    Some(op) => irc::Op::crdb(op, self),
+                    None => irc::Op::ok(self),
+                }
             },
 
             b"PART" => {
-                let chan = "#foo".to_string();
-                let op = self.world.part_user(chan, self.nick.clone());
-                irc::Op::crdb(op, self)
+                let chans = match arg(&m, 0) {
+                    Some(chans) => chans,
+                    None => return irc::Op::ok(self),
+                };
+
+                let mut op: Option<crdb::Completion> = None;
+                for chan in chans.split(',').filter(|c| !c.is_empty()) {
+                    let parted = self.world.part_user(chan.to_string(), self.user.clone());
+                    op = Some(match op {
+                        Some(op) => op.and(parted),
+                        None => parted,
+                    });
+                }
+
+                match op {
+                    Some(op) => irc::Op::crdb(op, self),
+                    None => irc::Op::ok(self),
+                }
             },
 
             b"PRIVMSG" => {
-                let chan = "#foo".to_string();
-                let message = "hello".to_string();
-                let op = self.world.message(chan, self.nick.clone(), message);
-                irc::Op::observe(op, self)
+                let message = match arg(&m, 1) {
+                    Some(message) => message,
+                    None => return irc::Op::ok(self),
+                };
+
+                let mut seen = HashSet::new();
+                let mut targets = Vec::new();
+                for target in m.targets(0) {
+                    if let Ok(target) = str::from_utf8(target) {
+                        if seen.insert(irc_lower(target)) {
+                            targets.push(target);
+                        }
+                    }
+                }
+
+                if targets.len() > MAX_TARGETS {
+                    let target = targets[MAX_TARGETS].as_bytes();
+                    let text = format!("Too many targets. The maximum is {}", MAX_TARGETS);
+                    self.numeric(ERR_TOOMANYTARGETS, &[target, text.as_bytes()]);
+                    return irc::Op::ok(self);
+                }
+
+                let mut sent = Vec::new();
+                for target in targets {
+                    if target.starts_with('#') {
+                        sent.extend(self.privmsg_chan(target, &message));
+                    } else {
+                        self.privmsg_user(target, &message);
+                    }
+                }
+
+                match sent.len() {
+                    0 => irc::Op::ok(self),
+                    1 => irc::Op::observe(sent.pop().unwrap(), self),
+                    _ => {
+                        let sent = future::join_all(sent).map_err(irc::Error::from);
+                        irc::Op::boxed(sent.map(move |_| self))
+                    },
+                }
+            },
+
+            b"PING" => {
+                match m.arg(0) {
+                    Some(token) => {
+                        let pong = Message::pong(irc::SERVER_NAME, token);
+                        let _ = self.out.send(&pong.to_bytes());
+                    },
+                    None => self.numeric(ERR_NEEDMOREPARAMS, &[b"PING", b"Not enough parameters"]),
+                }
+                irc::Op::ok(self)
+            },
+
+            b"STATS" => {
+                let query = arg(&m, 0).unwrap_or_else(|| "*".to_string());
+                let stats = self.world.stats();
+
+                let lines = [
+                    format!("users {}", stats.users),
+                    format!("channels {}", stats.chans),
+                    format!("unreachable servers {}", stats.unreachable),
+                    format!("pending rows {}", stats.pending),
+                ];
+                for line in lines.iter() {
+                    self.numeric(RPL_STATSDEBUG, &[line.as_bytes()]);
+                }
+                self.numeric(RPL_ENDOFSTATS, &[query.as_bytes(), b"End of /STATS report"]);
+
+                irc::Op::ok(self)
             },
 
             _ => {
@@ -51,4 +181,156 @@ impl Active {
             }
         }
     }
+
+    /// Delivers a message to the local members of a channel, returning the completion of
+    /// announcing it to the world if the channel exists. Members on other servers do not
+    /// receive it.
+    fn privmsg_chan(&mut self, chan: &str, message: &str) -> Option<observe::Completion> {
+        // channels only exist while they have members
+        let members = self.world.channel_members(chan);
+        if members.is_empty() {
+            self.numeric(ERR_NOSUCHCHANNEL, &[chan.as_bytes(), b"No such channel"]);
+            return None;
+        }
+
+        if let Some(ref pool) = self.pool {
+            let line = format!(":{} PRIVMSG {} :{}\r\n", self.nick(), chan, message);
+            let others: Vec<&str> = members.iter()
+                .map(|u| &u[..])
+                .filter(|u| *u != self.user)
+                .collect();
+            pool.send_to(&others, line.as_bytes());
+        }
+
+        // the message is only announced locally, since nothing forwards it to other servers yet
+        Some(self.world.message(chan.to_string(), self.user.clone(), message.to_string()))
+    }
+
+    /// Delivers a message to the user holding `nick`, if they are connected to this server.
+    fn privmsg_user(&mut self, nick: &str, message: &str) {
+        let user = match self.world.nick_owner(nick) {
+            Some(user) => user,
+            None => {
+                self.numeric(ERR_NOSUCHNICK, &[nick.as_bytes(), b"No such nick/channel"]);
+                return;
+            },
+        };
+
+        if let Some(ref pool) = self.pool {
+            let line = format!(":{} PRIVMSG {} :{}\r\n", self.nick(), nick, message);
+            pool.send_to(&[&user], line.as_bytes());
+        }
+    }
+
+    fn numeric(&mut self, code: u16, args: &[&[u8]]) {
+        let m = Message::numeric(irc::SERVER_NAME, code, self.nick().as_bytes(), args);
+        let _ = self.out.send(&m.to_bytes());
+    }
+}
+
+/// Returns the given argument as a string, if it exists and is valid UTF-8
+fn arg(m: &irc::Message, i: usize) -> Option<String> {
+    m.arg(i).and_then(|a| String::from_utf8(a.to_vec()).ok())
+}
+
+#[test]
+fn stats_reports_counts() {
+    use futures::Future;
+    use futures::future;
+    use common::Sid;
+    use irc::driver::test_io::Output;
+    use irc::send::SendDriver;
+    use tokio_core::reactor::Core;
+
+    let mut core = Core::new().unwrap();
+    let mut world = World::new(&core.handle(), Sid::identity());
+
+    core.run(world.add_user("alice".to_string())).unwrap();
+    core.run(world.add_user("bob".to_string())).unwrap();
+    core.run(world.add_chan("#oxide".to_string())).unwrap();
+    world.set_reachable(Sid::new("0BB"), false);
+
+    // rows for a table this server doesn't know about wait until it does
+    let mut tx = ::crdb::RawTransaction::new();
+    tx.add("x".to_string(), "a".to_string(), ::crdb::Record(vec![1]));
+    tx.add("x".to_string(), "b".to_string(), ::crdb::Record(vec![2]));
+    core.run(world.commit_raw(tx)).unwrap();
+
+    let output = Output::new();
+    let mut send = SendDriver::new(output.clone());
+    let active = Active::new(world, send.sender(), "alice".to_string());
+    core.run(active.handle(Message::parse("STATS d").unwrap())).unwrap();
+
+    core.run(future::poll_fn(|| {
+        let _ = send.poll();
+        Ok::<_, ()>(::futures::Async::Ready(()))
+    })).unwrap();
+
+    let out = String::from_utf8(output.0.borrow().clone()).unwrap();
+    assert_eq!(out, concat!(
+        ":ircd-oxide 249 alice :users 2\r\n",
+        ":ircd-oxide 249 alice :channels 1\r\n",
+        ":ircd-oxide 249 alice :unreachable servers 1\r\n",
+        ":ircd-oxide 249 alice :pending rows 2\r\n",
+        ":ircd-oxide 219 alice d :End of /STATS report\r\n",
+    ));
+}
+
+#[test]
+fn privmsg_reaches_channel_members() {
+    use std::time::Duration;
+    use common::Sid;
+    use irc::testing::TestClient;
+    use tokio_core::reactor::Core;
+
+    let mut core = Core::new().unwrap();
+    let mut world = World::new(&core.handle(), Sid::identity());
+    let pool = Pool::new();
+    pool.bind(&core.handle(), &mut world);
+
+    let mut clients = Vec::new();
+    for nick in ["alice", "bob", "carol"].iter() {
+        let mut client = TestClient::with_pool(world.clone(), pool.clone());
+        let user = format!("USER {} 0 * :{}", nick, nick);
+        client.feed_lines(&mut core, &[&format!("NICK {}", nick), &user]).unwrap();
+        assert!(client.is_active());
+        clients.push(client);
+    }
+
+    clients[0].feed_lines(&mut core, &["JOIN #oxide"]).unwrap();
+    clients[1].feed_lines(&mut core, &["JOIN #oxide"]).unwrap();
+
+    // let the pool catch up on sending out the joins
+    for _ in 0..10 {
+        core.turn(Some(Duration::from_millis(0)));
+    }
+    for client in clients.iter_mut() {
+        client.take_output();
+    }
+
+    clients[0].feed_lines(&mut core, &["PRIVMSG #oxide :hello there"]).unwrap();
+    clients[2].feed_lines(&mut core, &["PRIVMSG #nowhere :anyone?"]).unwrap();
+
+    assert_eq!(clients[0].take_output(), "");
+    assert_eq!(clients[1].take_output(), ":alice PRIVMSG #oxide :hello there\r\n");
+    assert_eq!(clients[2].take_output(),
+        ":ircd-oxide 403 carol #nowhere :No such channel\r\n");
+
+    // each target is sent to once, however many times it is named
+    let targets = "PRIVMSG carol,,#oxide,CAROL,#OXIDE,dave :hi all";
+    clients[0].feed_lines(&mut core, &[targets]).unwrap();
+
+    assert_eq!(clients[0].take_output(), ":ircd-oxide 401 alice dave :No such nick/channel\r\n");
+    assert_eq!(clients[1].take_output(), ":alice PRIVMSG #oxide :hi all\r\n");
+    assert_eq!(clients[2].take_output(), ":alice PRIVMSG carol :hi all\r\n");
+
+    // nicks are matched casefolded, and the message keeps the form the sender used
+    clients[0].feed_lines(&mut core, &["PRIVMSG Bob :hey"]).unwrap();
+    assert_eq!(clients[1].take_output(), ":alice PRIVMSG Bob :hey\r\n");
+
+    // naming too many targets sends to none of them
+    clients[0].feed_lines(&mut core, &["PRIVMSG a,b,c,d,carol :hi"]).unwrap();
+    assert_eq!(clients[0].take_output(),
+        ":ircd-oxide 407 alice carol :Too many targets. The maximum is 4\r\n");
+    assert_eq!(clients[2].take_output(), "");
 }
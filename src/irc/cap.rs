@@ -6,16 +6,17 @@
 
 //! Client capabilities
 
-// THINGS TO UPDATE WHEN ADDING A NEW CAPABILITY: (this is a small file, but
-// adding caps is a non-automatic process that tests can't really catch):
-//
-//   - The constants in `Caps`
-//   - The functions on `ClientCaps`
-//   - The `FromStr` impl (which `of` uses)
-//   - `worthless_test`
+// To add a new capability, add a constant to `Caps` and an entry for it to
+// `REGISTRY`. Everything else, including parsing and listing, is driven by the
+// registry. A convenience accessor on `ClientCaps` is optional.
 
+use std::collections::HashMap;
 use std::str::FromStr;
 
+use bytes::Bytes;
+
+use irc::Message;
+
 #[allow(dead_code)]
 mod cap {
     bitflags! {
@@ -24,11 +25,24 @@ mod cap {
             const ACCOUNT_NOTIFY     = 0b_00000000_00000010,
             const AWAY_NOTIFY        = 0b_00000000_00000100,
             const EXTENDED_JOIN      = 0b_00000000_00001000,
+            const SASL               = 0b_00000000_00010000,
+            const CAP_NOTIFY         = 0b_00000000_00100000,
         }
     }
 }
 
+/// Every capability we know about, by IRCv3 name, in the order they are listed.
+static REGISTRY: &'static [(&'static str, cap::Caps)] = &[
+    ("multi-prefix",      cap::MULTI_PREFIX),
+    ("account-notify",    cap::ACCOUNT_NOTIFY),
+    ("away-notify",       cap::AWAY_NOTIFY),
+    ("extended-join",     cap::EXTENDED_JOIN),
+    ("sasl",              cap::SASL),
+    ("cap-notify",        cap::CAP_NOTIFY),
+];
+
 /// An immutable client capability set.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub struct ClientCaps {
     caps: cap::Caps
 }
@@ -39,6 +53,11 @@ impl ClientCaps {
         ClientCaps { caps: cap::Caps::empty() }
     }
 
+    /// Creates a client capability set containing every known capability.
+    pub fn all() -> ClientCaps {
+        ClientCaps { caps: cap::Caps::all() }
+    }
+
     /// Attemps to convert the given string into a `ClientCaps` representing a
     /// single capability. The string should be the IRCv3 name of the capability,
     /// such as `"multi-prefix"`.
@@ -61,6 +80,31 @@ impl ClientCaps {
         ClientCaps { caps: self.caps | other.caps }
     }
 
+    /// Creates a new client capability set that includes the capabilities in
+    /// this set that are not in `other`.
+    pub fn without(&self, other: &ClientCaps) -> ClientCaps {
+        ClientCaps { caps: self.caps - other.caps }
+    }
+
+    /// Indicates whether every capability in `other` is also in this set.
+    pub fn contains(&self, other: &ClientCaps) -> bool {
+        self.caps.contains(other.caps)
+    }
+
+    /// Returns the IRCv3 names of the capabilities in this set.
+    pub fn names(&self) -> Vec<&'static str> {
+        REGISTRY.iter()
+            .filter(|&&(_, c)| self.caps.contains(c))
+            .map(|&(name, _)| name)
+            .collect()
+    }
+
+    /// Indicates whether the named capability is enabled. Unknown capabilities
+    /// are never enabled.
+    pub fn has(&self, name: &str) -> bool {
+        ClientCaps::of(name).map(|c| self.contains(&c)).unwrap_or(false)
+    }
+
     /// Indicates whether the `multi-prefix` capability is enabled.
     pub fn multi_prefix(&self) -> bool {
         self.caps.contains(cap::MULTI_PREFIX)
@@ -80,20 +124,304 @@ impl ClientCaps {
     pub fn extended_join(&self) -> bool {
         self.caps.contains(cap::EXTENDED_JOIN)
     }
+
+    /// Indicates whether the `sasl` capability is enabled.
+    pub fn sasl(&self) -> bool {
+        self.caps.contains(cap::SASL)
+    }
+
+    /// Indicates whether the `cap-notify` capability is enabled.
+    pub fn cap_notify(&self) -> bool {
+        self.caps.contains(cap::CAP_NOTIFY)
+    }
 }
 
 impl FromStr for ClientCaps {
     type Err = ();
 
     fn from_str(s: &str) -> Result<ClientCaps, ()> {
-        Ok(match s {
-            "multi-prefix"        => ClientCaps { caps: cap::MULTI_PREFIX },
-            "account-notify"      => ClientCaps { caps: cap::ACCOUNT_NOTIFY },
-            "away-notify"         => ClientCaps { caps: cap::AWAY_NOTIFY },
-            "extended-join"       => ClientCaps { caps: cap::EXTENDED_JOIN },
-            _ => return Err(())
-        })
+        REGISTRY.iter()
+            .find(|&&(name, _)| name == s)
+            .map(|&(_, c)| ClientCaps { caps: c })
+            .ok_or(())
+    }
+}
+
+/// A `CAP` subcommand sent by a client.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum CapCommand {
+    /// `CAP LS`, with the version the client asked for, if any
+    Ls(Option<u32>),
+    /// `CAP LIST`
+    List,
+    /// `CAP REQ`, with the requested capability list
+    Req(String),
+    /// `CAP END`
+    End,
+}
+
+impl CapCommand {
+    /// Parses the arguments of a `CAP` message. Returns `None` for unknown or malformed
+    /// subcommands.
+    pub fn parse(m: &Message) -> Option<CapCommand> {
+        let sub = match m.arg(0) {
+            Some(sub) => sub.to_ascii_uppercase(),
+            None => return None,
+        };
+        let arg = m.arg_str(1).map(|a| a.into_owned());
+
+        match &sub[..] {
+            b"LS" => Some(CapCommand::Ls(arg.and_then(|v| v.parse().ok()))),
+            b"LIST" => Some(CapCommand::List),
+            b"REQ" => arg.map(CapCommand::Req),
+            b"END" => Some(CapCommand::End),
+            _ => None,
+        }
+    }
+}
+
+/// Splits an entry of a `CAP LS` listing into the capability name and its values.
+pub fn split_cap_value(entry: &str) -> (&str, Vec<&str>) {
+    match entry.find('=') {
+        Some(i) => (&entry[..i], entry[i+1..].split(',').collect()),
+        None => (entry, Vec::new()),
+    }
+}
+
+/// The state of a client's capability negotiation.
+///
+/// A client that wants to negotiate capabilities starts with `CAP LS`, after which registration
+/// is held up until the client sends `CAP END`. In between, the client can `CAP REQ` any of the
+/// capabilities we offered. A `CAP REQ` is applied atomically: if any of the capabilities in it
+/// can't be granted, none of them are.
+///
+/// Clients asking for `CAP LS 302` or newer are also sent capability values, such as the
+/// mechanisms for `sasl`, and are implicitly given `cap-notify`.
+pub struct CapNegotiation {
+    offered: ClientCaps,
+    values: HashMap<&'static str, Vec<String>>,
+    enabled: ClientCaps,
+    version: u32,
+    negotiating: bool,
+}
+
+/// The response to a `CAP REQ`, containing the capability list from the request.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum CapReply {
+    Ack(String),
+    Nak(String),
+}
+
+impl CapNegotiation {
+    /// Creates a negotiation in which the given capabilities are offered.
+    pub fn new(offered: ClientCaps) -> CapNegotiation {
+        CapNegotiation {
+            offered: offered,
+            values: HashMap::new(),
+            enabled: ClientCaps::empty(),
+            version: 0,
+            negotiating: false,
+        }
+    }
+
+    /// Sets the values advertised for an offered capability.
+    pub fn set_values(&mut self, cap: &'static str, values: &[&str]) {
+        self.values.insert(cap, values.iter().map(|v| v.to_string()).collect());
+    }
+
+    /// Handles `CAP LS`, returning the list of offered capabilities. Registration is held up
+    /// until `end` is called.
+    pub fn ls(&mut self, version: Option<u32>) -> String {
+        self.negotiating = true;
+
+        if let Some(version) = version {
+            if version > self.version {
+                self.version = version;
+            }
+        }
+
+        if self.version >= 302 {
+            self.enabled.add(&ClientCaps { caps: cap::CAP_NOTIFY });
+        }
+
+        self.listing(&self.offered)
+    }
+
+    /// Offers additional capabilities. Returns the list to send with `CAP NEW` if the client
+    /// wants to hear about new capabilities.
+    pub fn offer(&mut self, caps: &ClientCaps) -> Option<String> {
+        let new = caps.without(&self.offered);
+        self.offered.add(&new);
+
+        if self.enabled.cap_notify() && new != ClientCaps::empty() {
+            Some(self.listing(&new))
+        } else {
+            None
+        }
+    }
+
+    fn listing(&self, caps: &ClientCaps) -> String {
+        let entries: Vec<String> = caps.names().into_iter().map(|name| {
+            match self.values.get(name) {
+                Some(values) if self.version >= 302 && !values.is_empty() =>
+                    format!("{}={}", name, values.join(",")),
+                _ => name.to_string(),
+            }
+        }).collect();
+
+        entries.join(" ")
+    }
+
+    /// Handles `CAP REQ` with the given capability list. Capabilities prefixed with `-` are
+    /// disabled rather than enabled.
+    pub fn req(&mut self, list: &str) -> CapReply {
+        self.negotiating = true;
+
+        let mut add = ClientCaps::empty();
+        let mut remove = ClientCaps::empty();
+
+        for name in list.split_whitespace() {
+            let (set, name) = match name.strip_prefix('-') {
+                Some(name) => (&mut remove, name),
+                None => (&mut add, name),
+            };
+
+            match ClientCaps::of(name) {
+                Some(c) if self.offered.contains(&c) => set.add(&c),
+                _ => return CapReply::Nak(list.trim().to_string()),
+            }
+        }
+
+        self.enabled = self.enabled.with(&add).without(&remove);
+        CapReply::Ack(list.trim().to_string())
+    }
+
+    /// Handles `CAP END`.
+    pub fn end(&mut self) {
+        self.negotiating = false;
+    }
+
+    /// Indicates whether the client is in the middle of negotiating capabilities, in which case
+    /// registration should not complete yet.
+    pub fn negotiating(&self) -> bool {
+        self.negotiating
+    }
+
+    /// Returns the capabilities the client has enabled.
+    pub fn enabled(&self) -> &ClientCaps {
+        &self.enabled
+    }
+}
+
+impl CapReply {
+    /// Builds the message to send to the client.
+    pub fn message(&self, server: &[u8], target: &[u8]) -> Message {
+        let (verb, list) = match *self {
+            CapReply::Ack(ref list) => ("ACK", list),
+            CapReply::Nak(ref list) => ("NAK", list),
+        };
+
+        Message {
+            tags: Vec::new(),
+            prefix: Some(Bytes::from(server)),
+            verb: Bytes::from("CAP"),
+            args: vec![Bytes::from(target), Bytes::from(verb), Bytes::from(&list[..])],
+        }
+    }
+}
+
+#[cfg(test)]
+fn all_caps() -> ClientCaps {
+    ClientCaps::all().without(&ClientCaps::of("cap-notify").unwrap())
+}
+
+#[test]
+fn cap_req_ack() {
+    let mut neg = CapNegotiation::new(all_caps());
+    assert_eq!(neg.ls(None), "multi-prefix account-notify away-notify extended-join sasl");
+    assert!(neg.negotiating());
+
+    let reply = neg.req("multi-prefix away-notify ");
+    assert_eq!(reply, CapReply::Ack("multi-prefix away-notify".to_string()));
+    assert!(neg.enabled().multi_prefix());
+    assert!(neg.enabled().away_notify());
+    assert!(!neg.enabled().extended_join());
+
+    assert_eq!(&reply.message(b"irc.example", b"*").to_bytes()[..],
+        &b":irc.example CAP * ACK :multi-prefix away-notify\r\n"[..]);
+
+    assert_eq!(neg.req("-multi-prefix"), CapReply::Ack("-multi-prefix".to_string()));
+    assert!(!neg.enabled().multi_prefix());
+    assert!(neg.enabled().away_notify());
+
+    neg.end();
+    assert!(!neg.negotiating());
+}
+
+#[test]
+fn cap_req_nak() {
+    let offered = ClientCaps::of("multi-prefix").unwrap();
+    let mut neg = CapNegotiation::new(offered);
+
+    // unknown caps, and known caps that weren't offered, are both refused
+    assert_eq!(neg.req("multi-prefix poo"), CapReply::Nak("multi-prefix poo".to_string()));
+    assert_eq!(neg.req("multi-prefix away-notify"),
+        CapReply::Nak("multi-prefix away-notify".to_string()));
+    assert_eq!(*neg.enabled(), ClientCaps::empty());
+}
+
+#[test]
+fn cap_ls_302() {
+    let m = Message::parse("CAP LS 302").unwrap();
+    assert_eq!(CapCommand::parse(&m), Some(CapCommand::Ls(Some(302))));
+    assert_eq!(CapCommand::parse(&Message::parse("CAP ls").unwrap()), Some(CapCommand::Ls(None)));
+    assert_eq!(CapCommand::parse(&Message::parse("CAP REQ :sasl").unwrap()),
+        Some(CapCommand::Req("sasl".to_string())));
+
+    let mut neg = CapNegotiation::new(ClientCaps::of("sasl").unwrap());
+    neg.set_values("sasl", &["PLAIN", "EXTERNAL"]);
+
+    // without 302, values aren't sent
+    assert_eq!(neg.ls(None), "sasl");
+    assert!(!neg.enabled().cap_notify());
+
+    let listing = neg.ls(Some(302));
+    assert_eq!(listing, "sasl=PLAIN,EXTERNAL");
+    assert!(neg.enabled().cap_notify());
+
+    let (name, values) = split_cap_value(&listing);
+    assert!(ClientCaps::of(name).unwrap().sasl());
+    assert_eq!(values, vec!["PLAIN", "EXTERNAL"]);
+}
+
+#[test]
+fn cap_new() {
+    let mut neg = CapNegotiation::new(ClientCaps::of("multi-prefix").unwrap());
+    neg.ls(None);
+    assert_eq!(neg.offer(&ClientCaps::of("away-notify").unwrap()), None);
+
+    neg.ls(Some(302));
+    assert_eq!(neg.offer(&ClientCaps::of("away-notify").unwrap()), None);
+    assert_eq!(neg.offer(&ClientCaps::of("extended-join").unwrap()),
+        Some("extended-join".to_string()));
+    assert_eq!(neg.req("extended-join"), CapReply::Ack("extended-join".to_string()));
+}
+
+#[test]
+fn registry_round_trip() {
+    let mut seen = ClientCaps::empty();
+
+    for &(name, _) in REGISTRY.iter() {
+        let c = ClientCaps::of(name).expect("registered cap parses");
+        assert_eq!(c.names(), vec![name]);
+        assert!(c.has(name));
+        assert!(!seen.contains(&c), "{} registered twice", name);
+        seen.add(&c);
     }
+
+    // every flag has a name
+    assert_eq!(seen, ClientCaps::all());
+    assert!(!ClientCaps::all().has("poo"));
 }
 
 #[test]
@@ -106,6 +434,8 @@ fn worthless_test() {
     assert!(ClientCaps::of("account-notify").unwrap().account_notify());
     assert!(ClientCaps::of("away-notify").unwrap().away_notify());
     assert!(ClientCaps::of("extended-join").unwrap().extended_join());
+    assert!(ClientCaps::of("sasl").unwrap().sasl());
+    assert!(ClientCaps::of("cap-notify").unwrap().cap_notify());
 
     // ping me if they ever standardize a capability called "poo" because I want
     // to implement it.
@@ -4,6 +4,11 @@ use tokio_io::codec::Decoder;
 use tokio_io::codec::Encoder;
 
 use irc::message::Message;
+use irc::message::MAX_LINE_LEN;
+use irc::message::MAX_TAGS_LEN;
+
+/// The longest line the codec will read, including tags and the trailing `\r\n`
+const MAX_READ_LEN: usize = MAX_LINE_LEN + MAX_TAGS_LEN;
 
 pub struct IrcCodec;
 
@@ -13,19 +18,24 @@ impl Decoder for IrcCodec {
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Message>, Self::Error> {
         loop {
-            let n_loc = src.iter().position(|b| *b == b'\n');
-            let r_loc = src.iter().position(|b| *b == b'\r');
-
-            let (nl_start, nl_size) = match n_loc {
-                None => return Ok(None), // no \n
-                Some(i) => match r_loc {
-                    Some(j) if j + 1 == i => (j, 2), // \r\n
-                    _ => (i, 1), // \n
-                },
+            // lines can end in either \r\n or a bare \n
+            let nl = match src.iter().position(|b| *b == b'\n') {
+                Some(i) => i,
+                None if src.len() >= MAX_READ_LEN => return Err(line_too_long()),
+                None => return Ok(None),
             };
 
-            let line = src.split_to(nl_start);
-            src.split_to(nl_size);
+            let mut line = src.split_to(nl);
+            src.split_to(1);
+
+            if line.last() == Some(&b'\r') {
+                let len = line.len() - 1;
+                line.truncate(len);
+            }
+
+            if line.len() + 2 > MAX_READ_LEN {
+                return Err(line_too_long());
+            }
 
             if line.len() != 0 {
                 return match Message::parse(line) {
@@ -37,6 +47,10 @@ impl Decoder for IrcCodec {
     }
 }
 
+fn line_too_long() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "line too long")
+}
+
 impl Encoder for IrcCodec {
     type Item = String;
     type Error = io::Error;
@@ -49,3 +63,39 @@ impl Encoder for IrcCodec {
         Ok(())
     }
 }
+
+#[test]
+fn decode_mixed_line_endings() {
+    let mut codec = IrcCodec;
+    let mut buf = BytesMut::from(&b"NICK a\r\nUSER a 0 * :A\n\r\nPING :x\ry\nPONG"[..]);
+
+    let mut verbs = Vec::new();
+    while let Some(m) = codec.decode(&mut buf).unwrap() {
+        if m.verb == "PING" {
+            assert_eq!(&m.args[0][..], b"x\ry");
+        }
+        verbs.push(m.verb);
+    }
+
+    assert_eq!(verbs, vec!["NICK", "USER", "PING"]);
+    assert_eq!(&buf[..], b"PONG");
+}
+
+#[test]
+fn decode_long_lines() {
+    let mut codec = IrcCodec;
+
+    let mut buf = BytesMut::from(&b"PING :"[..]);
+    buf.extend_from_slice(&vec![b'x'; MAX_READ_LEN - 8]);
+    buf.extend_from_slice(b"\r\n");
+    assert!(codec.decode(&mut buf).unwrap().is_some());
+
+    let mut buf = BytesMut::from(vec![b'x'; MAX_READ_LEN - 1]);
+    buf.extend_from_slice(b"\n");
+    assert!(codec.decode(&mut buf).is_err());
+
+    let mut buf = BytesMut::from(vec![b'x'; MAX_READ_LEN - 1]);
+    assert!(codec.decode(&mut buf).unwrap().is_none());
+    buf.extend_from_slice(b"x");
+    assert!(codec.decode(&mut buf).is_err());
+}
@@ -4,6 +4,15 @@ use futures::Poll;
 use futures::Stream;
 use futures::task;
 
+use std::cell::Cell;
+use std::fmt;
+use std::io;
+use std::rc::Rc;
+use std::time::Duration;
+use std::time::Instant;
+
+use tokio_core::reactor::Handle;
+use tokio_core::reactor::Timeout;
 use tokio_io::AsyncRead;
 use tokio_io::AsyncWrite;
 use tokio_io::codec::FramedRead;
@@ -11,9 +20,16 @@ use tokio_io::codec::FramedRead;
 use irc;
 use irc::active::Active;
 use irc::codec::IrcCodec;
+use irc::liveness;
+use irc::liveness::Check;
+use irc::liveness::Liveness;
 use irc::message::Message;
 use irc::pending::Pending;
+use irc::pool::Pool;
 use irc::send::SendDriver;
+use irc::send::Sender;
+use irc::throttle;
+use irc::throttle::TokenBucket;
 use world::World;
 
 pub enum Client {
@@ -28,23 +44,71 @@ impl Client {
             Client::Active(active) => active.handle(message),
         }
     }
+
+    fn handle_eof(self) {
+        match self {
+            Client::Pending(pending) => pending.handle_eof(),
+            Client::Active(active) => active.handle_eof(),
+        }
+    }
+}
+
+/// Why a client connection ended, as resolved by its `Driver`
+#[derive(Debug, PartialEq)]
+pub enum Disconnect {
+    /// The client sent `QUIT`, with the message it gave, if any
+    Quit(Option<String>),
+    /// The client closed its end of the connection
+    Eof,
+    /// Reading from the client failed, or the client broke the protocol
+    Error(String),
+    /// The client stopped responding to pings
+    Timeout,
+    /// The server closed the connection, usually because it is shutting down
+    Shutdown,
+}
+
+impl fmt::Display for Disconnect {
+    /// Formats the reason as it would appear in a `QUIT` sent to other users
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Disconnect::Quit(Some(ref msg)) => write!(f, "Quit: {}", msg),
+            Disconnect::Quit(None) => write!(f, "Quit"),
+            Disconnect::Eof => write!(f, "Connection closed"),
+            Disconnect::Error(ref e) => write!(f, "Error: {}", e),
+            Disconnect::Timeout => write!(f, "Ping timeout"),
+            Disconnect::Shutdown => write!(f, "Server shutting down"),
+        }
+    }
 }
 
 pub struct Driver<R, W> {
     send: SendDriver<W>,
     recv: FramedRead<R, IrcCodec>,
     state: Option<State>,
+    out: Sender,
+    liveness: Liveness,
+    timer: Timeout,
+    throttle: TokenBucket,
+    throttle_timer: Timeout,
+    world: World,
+    pool: Option<Pool>,
+    user: Option<String>,
+    reason: Option<Disconnect>,
+    /// A time for liveness and throttling to use instead of the real one, which tests can move
+    /// forward by hand
+    clock: Option<Rc<Cell<Instant>>>,
 }
 
 enum State {
     Ready(Client),
     Processing(irc::Op<Client>),
+    /// The client has stopped sending, and the driver is only waiting for output to drain
+    Closed,
 }
 
 type DriverPoll = Result<(State, bool), irc::Error>;
 
-fn driver_err(e: irc::Error) -> DriverPoll { Err(e) }
-
 fn driver_not_ready(s: State) -> DriverPoll { Ok((s, false)) }
 
 fn driver_continue(s: State) -> DriverPoll { Ok((s, true)) }
@@ -53,14 +117,80 @@ impl<R: 'static, W: 'static> Driver<R, W>
     where R: AsyncRead,
           W: AsyncWrite,
 {
-    pub fn new(world: World, recv: R, send: W) -> Driver<R, W> {
+    pub fn new(handle: &Handle, world: World, recv: R, send: W) -> io::Result<Driver<R, W>> {
+        Driver::with_pending(handle, world, recv, send, None, Pending::new)
+    }
+
+    /// Creates a `Driver` for a client that joins `pool` once it registers. When the client
+    /// disconnects, the users sharing a channel with it are sent a `QUIT` through the pool.
+    pub fn with_pool(handle: &Handle, world: World, pool: Pool, recv: R, send: W)
+        -> io::Result<Driver<R, W>>
+    {
+        let quit_pool = Some(pool.clone());
+        Driver::with_pending(handle, world, recv, send, quit_pool, |world, out| {
+            Pending::with_pool(world, out, pool)
+        })
+    }
+
+    fn with_pending<F>(handle: &Handle, world: World, recv: R, send: W, pool: Option<Pool>,
+        pending: F) -> io::Result<Driver<R, W>> where F: FnOnce(World, Sender) -> Pending
+    {
+        let ping_after = Duration::from_secs(liveness::PING_AFTER);
+        let grace = Duration::from_secs(liveness::PING_GRACE);
+        let flood_interval = Duration::from_millis(throttle::FLOOD_INTERVAL);
+        let now = Instant::now();
+
         let mut send_driver = SendDriver::new(send);
-        let pending = Pending::new(world, send_driver.sender());
+        let pending = pending(world.clone(), send_driver.sender());
 
-        Driver {
+        Ok(Driver {
+            out: send_driver.sender(),
             send: send_driver,
             recv: FramedRead::new(recv, IrcCodec),
-            state: Some(State::Ready(Client::Pending(pending)))
+            state: Some(State::Ready(Client::Pending(pending))),
+            liveness: Liveness::new(now, ping_after, grace),
+            timer: try!(Timeout::new_at(now + ping_after, handle)),
+            throttle: TokenBucket::new(now, flood_interval, throttle::FLOOD_BURST),
+            throttle_timer: try!(Timeout::new_at(now, handle)),
+            world: world,
+            pool: pool,
+            user: None,
+            reason: None,
+            clock: None,
+        })
+    }
+
+    fn now(&self) -> Instant {
+        self.clock.as_ref().map(|clock| clock.get()).unwrap_or_else(Instant::now)
+    }
+
+    /// Pings the client if it has been quiet for too long, and gives up on it entirely if it
+    /// stays quiet, in which case this is ready.
+    fn poll_liveness(&mut self) -> Poll<(), irc::Error> {
+        loop {
+            match self.liveness.check(self.now()) {
+                Check::Wait(at) => {
+                    self.timer.reset(at);
+                    if try!(self.timer.poll()).is_not_ready() {
+                        return Ok(Async::NotReady);
+                    }
+                },
+
+                Check::Ping => {
+                    let ping = Message {
+                        tags: Vec::new(),
+                        prefix: None,
+                        verb: "PING".into(),
+                        args: vec![irc::SERVER_NAME.into()],
+                    };
+                    try!(self.out.send(&ping.to_bytes()));
+                },
+
+                Check::Dead => {
+                    self.out.close_hard();
+                    return Ok(Async::Ready(()));
+                },
+            }
         }
     }
 
@@ -69,12 +199,30 @@ impl<R: 'static, W: 'static> Driver<R, W>
 
         match state {
             Ready(client) => {
+                // a throttled client is simply not read from until it has earned another token
+                if let Err(at) = self.throttle.check(self.now()) {
+                    self.throttle_timer.reset(at);
+                    if try!(self.throttle_timer.poll()).is_not_ready() {
+                        return driver_not_ready(Ready(client));
+                    }
+                    return driver_continue(Ready(client));
+                }
+
                 if let Async::Ready(result) = try!(self.recv.poll()) {
                     if let Some(message) = result {
+                        let now = self.now();
+                        self.liveness.seen(now);
+                        let _ = self.throttle.take(now);
+                        if &message.verb[..] == b"QUIT" {
+                            self.quit(message.arg_str(0).map(|m| m.into_owned()));
+                            return driver_continue(Closed);
+                        }
                         let op = client.handle(message);
                         driver_continue(Processing(op))
                     } else {
-                        driver_err(irc::Error::Other("unexpected EOF"))
+                        client.handle_eof();
+                        self.reason = Some(Disconnect::Eof);
+                        driver_continue(Closed)
                     }
                 } else {
                     driver_not_ready(Ready(client))
@@ -83,15 +231,38 @@ impl<R: 'static, W: 'static> Driver<R, W>
 
             Processing(mut op) => {
                 match try!(op.poll()) {
-                    Async::Ready(client) => driver_continue(Ready(client)),
+                    Async::Ready(client) => {
+                        if let Client::Active(ref active) = client {
+                            self.user = Some(active.user().to_string());
+                        }
+                        driver_continue(Ready(client))
+                    },
                     Async::NotReady => driver_not_ready(Processing(op)),
                 }
             },
+
+            Closed => driver_not_ready(Closed),
         }
     }
 
-    fn poll_error(&mut self) -> Poll<(), irc::Error> {
-        let _ = try!(self.send.poll());
+    /// Handles `QUIT`, which ends the connection whatever state the client is in.
+    fn quit(&mut self, message: Option<String>) {
+        let reason = Disconnect::Quit(message);
+        let error = format!("ERROR :Closing link ({})\r\n", reason);
+        let _ = self.out.send(error.as_bytes());
+        self.out.close_soft();
+        self.reason = Some(reason);
+    }
+
+    fn poll_error(&mut self) -> Poll<Disconnect, irc::Error> {
+        if try!(self.send.poll()).is_ready() {
+            // the client was closed, and everything we had to say has been said. if the client
+            // didn't ask for that, the server must have.
+            return Ok(Async::Ready(self.reason.take().unwrap_or(Disconnect::Shutdown)));
+        }
+        if try!(self.poll_liveness()).is_ready() {
+            return Ok(Async::Ready(Disconnect::Timeout));
+        }
 
         for _ in 0..50 {
             let state = match self.state.take() {
@@ -117,10 +288,348 @@ impl<R: 'static, W: 'static> Future for Driver<R, W>
     where R: AsyncRead,
           W: AsyncWrite,
 {
-    type Item = ();
+    type Item = Disconnect;
     type Error = ();
 
-    fn poll(&mut self) -> Poll<(), ()> {
-        self.poll_error().map_err(|e| info!("driver error: {}", e))
+    fn poll(&mut self) -> Poll<Disconnect, ()> {
+        let reason = match self.poll_error() {
+            Ok(Async::Ready(reason)) => reason,
+            Ok(Async::NotReady) => return Ok(Async::NotReady),
+            Err(e) => {
+                info!("driver error: {}", e);
+                Disconnect::Error(e.to_string())
+            },
+        };
+
+        // the user's peers are told before the world forgets their nick
+        if let Some(user) = self.user.take() {
+            if let Some(pool) = self.pool.take() {
+                pool.quit(&user, &reason);
+            }
+            let _ = self.world.remove_user(user);
+        }
+        Ok(Async::Ready(reason))
+    }
+}
+
+#[cfg(test)]
+pub mod test_io {
+    use std::cell::RefCell;
+    use std::io;
+    use std::rc::Rc;
+
+    use futures::Async;
+    use futures::Poll;
+
+    use tokio_io::AsyncRead;
+    use tokio_io::AsyncWrite;
+
+    /// A reader that yields its data and then waits forever, like a client that stays connected
+    /// without saying anything more
+    pub struct Stalled(io::Cursor<Vec<u8>>);
+
+    impl Stalled {
+        pub fn new(data: &[u8]) -> Stalled {
+            Stalled(io::Cursor::new(data.to_vec()))
+        }
+    }
+
+    impl io::Read for Stalled {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.0.read(buf) {
+                Ok(0) if !buf.is_empty() => Err(io::ErrorKind::WouldBlock.into()),
+                r => r,
+            }
+        }
+    }
+
+    impl AsyncRead for Stalled { }
+
+    /// A writer that collects everything written to it, for inspecting a client's output
+    #[derive(Clone, Default)]
+    pub struct Output(pub Rc<RefCell<Vec<u8>>>);
+
+    impl Output {
+        pub fn new() -> Output {
+            Output(Rc::new(RefCell::new(Vec::new())))
+        }
+    }
+
+    impl io::Write for Output {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl AsyncWrite for Output {
+        fn shutdown(&mut self) -> Poll<(), io::Error> {
+            Ok(Async::Ready(()))
+        }
+    }
+}
+
+#[test]
+fn client_commands_reach_world() {
+    use common::Sid;
+    use tokio_core::reactor::Core;
+    use world::WorldEvent;
+
+    let mut core = Core::new().unwrap();
+    let mut world = World::new(&core.handle(), Sid::identity());
+    let events = world.events();
+
+    let input: &'static [u8] =
+        b"NICK alice\r\nUSER alice 0 * :Alice\r\nJOIN #oxide,#rust\r\nPART #rust\r\n";
+    let driver = Driver::new(&core.handle(), world.clone(), input, test_io::Output::new());
+    let driver = driver.unwrap();
+
+    // the driver stops cleanly once it runs out of input
+    assert_eq!(core.run(driver), Ok(Disconnect::Eof));
+
+    let events = core.run(events.take(5).collect()).unwrap();
+    let user = match *events[0] {
+        WorldEvent::UserAdded(ref user) => user.clone(),
+        ref ev => panic!("unexpected event {:?}", ev),
+    };
+    let events: Vec<String> = events.iter().filter_map(|ev| match **ev {
+        WorldEvent::UserJoin(ref chan, ref user) => Some(format!("join {} {}", chan, user)),
+        WorldEvent::UserPart(ref chan, ref user) => Some(format!("part {} {}", chan, user)),
+        WorldEvent::NickChange(_, _) => None,
+        ref ev => Some(format!("{:?}", ev)),
+    }).collect();
+
+    let mut joins = events[1..3].to_vec();
+    joins.sort();
+    assert_eq!(joins, vec![format!("join #oxide {}", user), format!("join #rust {}", user)]);
+    assert_eq!(events[3], format!("part #rust {}", user));
+}
+
+#[test]
+fn eof_while_pending() {
+    use common::Sid;
+    use tokio_core::reactor::Core;
+
+    let mut core = Core::new().unwrap();
+    let world = World::new(&core.handle(), Sid::identity());
+    let output = test_io::Output::new();
+
+    let input: &'static [u8] = b"NICK alice\r\n";
+    let driver = Driver::new(&core.handle(), world.clone(), input, output.clone()).unwrap();
+
+    assert_eq!(core.run(driver), Ok(Disconnect::Eof));
+    assert!(output.0.borrow().is_empty());
+    assert!(world.nick_owner("alice").is_none());
+}
+
+#[test]
+fn eof_while_active() {
+    use common::Sid;
+    use tokio_core::reactor::Core;
+
+    let mut core = Core::new().unwrap();
+    let world = World::new(&core.handle(), Sid::identity());
+    let output = test_io::Output::new();
+
+    let input: &'static [u8] = b"NICK alice\r\nUSER alice 0 * :Alice\r\n";
+    let driver = Driver::new(&core.handle(), world.clone(), input, output.clone()).unwrap();
+
+    assert_eq!(core.run(driver), Ok(Disconnect::Eof));
+    let out = String::from_utf8(output.0.borrow().clone()).unwrap();
+    assert!(out.ends_with("\r\nERROR :Closing link (Connection closed)\r\n"));
+}
+
+#[test]
+fn reconnect_with_same_nick() {
+    use common::Sid;
+    use tokio_core::reactor::Core;
+
+    let mut core = Core::new().unwrap();
+    let mut world = World::new(&core.handle(), Sid::identity());
+    let pool = Pool::new();
+    pool.bind(&core.handle(), &mut world);
+
+    for _ in 0..2 {
+        let output = test_io::Output::new();
+        let input: &'static [u8] = b"NICK alice\r\nUSER alice 0 * :Alice\r\nJOIN #oxide\r\n";
+        let driver = Driver::with_pool(&core.handle(), world.clone(), pool.clone(), input,
+            output.clone()).unwrap();
+
+        assert_eq!(core.run(driver), Ok(Disconnect::Eof));
+        let out = String::from_utf8(output.0.borrow().clone()).unwrap();
+        assert!(out.starts_with(":ircd-oxide 001 alice "));
+    }
+
+    // let the world catch up on the second disconnect
+    for _ in 0..10 {
+        core.turn(Some(Duration::from_millis(0)));
+    }
+
+    assert_eq!(world.nick_owner("alice"), None);
+    assert_eq!(world.stats().users, 0);
+    assert!(world.channel_members("#oxide").is_empty());
+}
+
+#[test]
+fn quit_is_not_followed_by_part() {
+    use common::Sid;
+    use irc::testing::TestClient;
+    use tokio_core::reactor::Core;
+
+    let mut core = Core::new().unwrap();
+    let mut world = World::new(&core.handle(), Sid::identity());
+    let pool = Pool::new();
+    pool.bind(&core.handle(), &mut world);
+
+    let mut bob = TestClient::with_pool(world.clone(), pool.clone());
+    bob.feed_lines(&mut core, &["NICK bob", "USER bob 0 * :Bob", "JOIN #oxide"]).unwrap();
+    bob.take_output();
+
+    let input: &'static [u8] = b"NICK alice\r\nUSER alice 0 * :Alice\r\nJOIN #oxide\r\n";
+    let driver = Driver::with_pool(&core.handle(), world.clone(), pool.clone(), input,
+        test_io::Output::new()).unwrap();
+    assert_eq!(core.run(driver), Ok(Disconnect::Eof));
+
+    // let the world catch up on the disconnect
+    for _ in 0..10 {
+        core.turn(Some(Duration::from_millis(0)));
+    }
+
+    let out = bob.take_output();
+    let quits: Vec<&str> = out.lines().filter(|l| l.contains(" QUIT ")).collect();
+    assert_eq!(quits, vec![":alice QUIT :Connection closed"]);
+    assert!(!out.contains(" PART "), "{}", out);
+    assert_eq!(world.channel_members("#oxide").len(), 1);
+}
+
+#[test]
+fn disconnect_on_quit() {
+    use common::Sid;
+    use tokio_core::reactor::Core;
+
+    let mut core = Core::new().unwrap();
+    let world = World::new(&core.handle(), Sid::identity());
+    let output = test_io::Output::new();
+
+    // nothing after the QUIT is read
+    let input = test_io::Stalled::new(b"NICK alice\r\nQUIT :gone fishing\r\nUSER a 0 * :A\r\n");
+    let driver = Driver::new(&core.handle(), world.clone(), input, output.clone()).unwrap();
+
+    let reason = core.run(driver).unwrap();
+    assert_eq!(reason, Disconnect::Quit(Some("gone fishing".to_string())));
+    assert_eq!(&output.0.borrow()[..], &b"ERROR :Closing link (Quit: gone fishing)\r\n"[..]);
+    assert!(world.nick_owner("alice").is_none());
+}
+
+#[test]
+fn disconnect_on_protocol_error() {
+    use common::Sid;
+    use tokio_core::reactor::Core;
+
+    let mut core = Core::new().unwrap();
+    let world = World::new(&core.handle(), Sid::identity());
+
+    let input: &'static [u8] = b"NICK alice\r\n:alice\r\n";
+    let driver = Driver::new(&core.handle(), world, input, test_io::Output::new()).unwrap();
+
+    match core.run(driver) {
+        Ok(Disconnect::Error(e)) => assert!(e.contains("missing verb"), "{}", e),
+        other => panic!("unexpected result: {:?}", other),
     }
 }
+
+#[test]
+fn disconnect_on_timeout() {
+    use common::Sid;
+    use tokio_core::reactor::Core;
+
+    let mut core = Core::new().unwrap();
+    let world = World::new(&core.handle(), Sid::identity());
+    let output = test_io::Output::new();
+
+    let input = test_io::Stalled::new(b"");
+    let mut driver = Driver::new(&core.handle(), world, input, output.clone()).unwrap();
+    let never = Duration::from_secs(0);
+    driver.liveness = Liveness::new(Instant::now(), never, never);
+
+    assert_eq!(core.run(driver), Ok(Disconnect::Timeout));
+}
+
+#[test]
+fn silent_client_is_pinged_then_disconnected() {
+    use futures::future;
+    use common::Sid;
+    use tokio_core::reactor::Core;
+
+    let mut core = Core::new().unwrap();
+    let world = World::new(&core.handle(), Sid::identity());
+    let output = test_io::Output::new();
+
+    let input = test_io::Stalled::new(b"NICK alice\r\nUSER alice 0 * :Alice\r\nPING :abc\r\n");
+    let mut driver = Driver::new(&core.handle(), world, input, output.clone()).unwrap();
+
+    let now = Rc::new(Cell::new(Instant::now()));
+    driver.clock = Some(now.clone());
+
+    // polls the driver a few times, letting the world catch up in between
+    let mut step = |core: &mut Core| {
+        for _ in 0..10 {
+            let polled = core.run(future::poll_fn(|| Ok::<_, ()>(Async::Ready(driver.poll()))));
+            if let Ok(Ok(Async::Ready(reason))) = polled {
+                return Some(reason);
+            }
+            core.turn(Some(Duration::from_millis(0)));
+        }
+        None
+    };
+
+    assert_eq!(step(&mut core), None);
+    let out = String::from_utf8(output.0.borrow().clone()).unwrap();
+    assert!(out.starts_with(":ircd-oxide 001 alice "));
+    assert!(out.ends_with("\r\n:ircd-oxide PONG ircd-oxide abc\r\n"));
+
+    now.set(now.get() + Duration::from_secs(liveness::PING_AFTER));
+    assert_eq!(step(&mut core), None);
+    assert!(output.0.borrow().ends_with(b"PONG ircd-oxide abc\r\nPING ircd-oxide\r\n"));
+
+    now.set(now.get() + Duration::from_secs(liveness::PING_GRACE));
+    assert_eq!(step(&mut core), Some(Disconnect::Timeout));
+}
+
+#[test]
+fn disconnect_on_shutdown() {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use common::Sid;
+    use tokio_core::reactor::Core;
+
+    let mut core = Core::new().unwrap();
+    let mut world = World::new(&core.handle(), Sid::identity());
+    let mut pool = Pool::new();
+    pool.bind(&core.handle(), &mut world);
+    let output = test_io::Output::new();
+
+    let input = test_io::Stalled::new(b"NICK alice\r\nUSER alice 0 * :Alice\r\n");
+    let driver = Driver::with_pool(&core.handle(), world, pool.clone(), input, output.clone());
+
+    let result = Rc::new(RefCell::new(None));
+    let result_ = result.clone();
+    core.handle().spawn(driver.unwrap().map(move |reason| *result_.borrow_mut() = Some(reason)));
+
+    for _ in 0..10 {
+        core.turn(Some(Duration::from_millis(0)));
+    }
+    assert_eq!(pool.user_count(), 1);
+    assert!(result.borrow().is_none());
+
+    core.run(pool.shutdown()).unwrap();
+    core.turn(Some(Duration::from_millis(0)));
+    assert_eq!(*result.borrow(), Some(Disconnect::Shutdown));
+
+    let out = String::from_utf8(output.0.borrow().clone()).unwrap();
+    assert!(out.ends_with("\r\nERROR :Server shutting down\r\n"));
+}
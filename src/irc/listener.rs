@@ -6,14 +6,17 @@ use futures::Stream;
 use tokio_core::reactor::Handle;
 use tokio_core::net::TcpStream;
 use tokio_io::AsyncRead;
+use tokio_io::io;
 
 use irc::driver::Driver;
+use irc::pool::Pool;
 use world::World;
 
 /// A task to spawn pending clients from a stream of incoming connections.
 pub struct Listener<A> {
     handle: Handle,
     world: World,
+    pool: Option<Pool>,
     accept: A,
 }
 
@@ -23,6 +26,17 @@ impl<A> Listener<A> {
         Listener {
             handle: handle.clone(),
             world: world,
+            pool: None,
+            accept: accept,
+        }
+    }
+
+    /// Creates a `Listener` whose clients join `pool` once they register
+    pub fn with_pool(handle: &Handle, world: World, pool: Pool, accept: A) -> Listener<A> {
+        Listener {
+            handle: handle.clone(),
+            world: world,
+            pool: Some(pool),
             accept: accept,
         }
     }
@@ -39,8 +53,27 @@ impl<A> Future for Listener<A> where A: Stream<Item=TcpStream> {
                 None => return Ok(Async::Ready(())),
             };
 
-            let driver = Driver::new(self.world.clone(), recv, send);
-            self.handle.spawn(driver);
+            if self.pool.as_ref().map(|pool| pool.is_full()).unwrap_or(false) {
+                info!("refusing connection: too many connections");
+                let refused = io::write_all(send, &b"ERROR :Too many connections\r\n"[..]);
+                self.handle.spawn(refused.then(|_| Ok(())));
+                continue;
+            }
+
+            let driver = match self.pool {
+                Some(ref pool) => {
+                    Driver::with_pool(&self.handle, self.world.clone(), pool.clone(), recv, send)
+                },
+                None => Driver::new(&self.handle, self.world.clone(), recv, send),
+            };
+
+            match driver {
+                Ok(driver) => {
+                    let driver = driver.map(|reason| info!("client disconnected: {}", reason));
+                    self.handle.spawn(driver);
+                },
+                Err(e) => warn!("could not start client driver: {}", e),
+            }
         }
     }
 }
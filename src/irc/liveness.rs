@@ -0,0 +1,97 @@
+//! Tracking whether a client connection is still alive
+//!
+//! A client that has been silent for a while is sent a `PING`, and is considered dead if it
+//! stays silent for a grace period after that. This module only keeps track of the timing, and
+//! is told what time it is by the caller, which makes it easy to test without waiting around.
+
+use std::time::Duration;
+use std::time::Instant;
+
+/// How long a client can be silent before being pinged
+pub const PING_AFTER: u64 = 120;
+
+/// How long a pinged client has to respond before being disconnected
+pub const PING_GRACE: u64 = 60;
+
+/// What to do about a connection, as decided by `Liveness::check`
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Check {
+    /// Nothing needs to be done until the given time
+    Wait(Instant),
+    /// The client should be sent a `PING`
+    Ping,
+    /// The client has not responded in time and should be disconnected
+    Dead,
+}
+
+/// The timing state of a single connection
+pub struct Liveness {
+    ping_after: Duration,
+    grace: Duration,
+    last_seen: Instant,
+    pinged: Option<Instant>,
+}
+
+impl Liveness {
+    /// Creates a new `Liveness` for a connection that was last active at `now`
+    pub fn new(now: Instant, ping_after: Duration, grace: Duration) -> Liveness {
+        Liveness {
+            ping_after: ping_after,
+            grace: grace,
+            last_seen: now,
+            pinged: None,
+        }
+    }
+
+    /// Records inbound activity from the client, resetting the timers
+    pub fn seen(&mut self, now: Instant) {
+        self.last_seen = now;
+        self.pinged = None;
+    }
+
+    /// Decides what to do about the connection at the given time. Returns `Check::Ping` at most
+    /// once per silent period.
+    pub fn check(&mut self, now: Instant) -> Check {
+        if let Some(pinged) = self.pinged {
+            let deadline = pinged + self.grace;
+            return if now >= deadline { Check::Dead } else { Check::Wait(deadline) };
+        }
+
+        let ping_at = self.last_seen + self.ping_after;
+        if now >= ping_at {
+            self.pinged = Some(now);
+            Check::Ping
+        } else {
+            Check::Wait(ping_at)
+        }
+    }
+}
+
+#[cfg(test)]
+fn secs(n: u64) -> Duration {
+    Duration::from_secs(n)
+}
+
+#[test]
+fn silent_client_is_pinged_then_dead() {
+    let start = Instant::now();
+    let mut live = Liveness::new(start, secs(120), secs(60));
+
+    assert_eq!(live.check(start + secs(10)), Check::Wait(start + secs(120)));
+    assert_eq!(live.check(start + secs(120)), Check::Ping);
+    assert_eq!(live.check(start + secs(150)), Check::Wait(start + secs(180)));
+    assert_eq!(live.check(start + secs(180)), Check::Dead);
+}
+
+#[test]
+fn activity_resets_timers() {
+    let start = Instant::now();
+    let mut live = Liveness::new(start, secs(120), secs(60));
+
+    live.seen(start + secs(100));
+    assert_eq!(live.check(start + secs(120)), Check::Wait(start + secs(220)));
+    assert_eq!(live.check(start + secs(220)), Check::Ping);
+
+    live.seen(start + secs(230));
+    assert_eq!(live.check(start + secs(290)), Check::Wait(start + secs(350)));
+}
@@ -6,12 +6,31 @@
 
 //! Message parsing
 
+use std::borrow::Cow;
 use std::fmt;
+use std::iter;
 
 use bytes::Bytes;
 
 pub type ParseResult<T> = Result<T, &'static str>;
 
+/// The maximum length of an IRC line, including the trailing `\r\n` but not including tags.
+pub const MAX_LINE_LEN: usize = 512;
+
+/// The maximum length of the tags section of an IRC line, including the leading `@` and the
+/// space that follows it.
+pub const MAX_TAGS_LEN: usize = 8191;
+
+/// The most arguments `Message::parse` will split a line into. Anything past the last one is
+/// folded into it, as traditional servers do.
+pub const MAX_ARGS: usize = 15;
+
+/// The longest verb `Message::parse` will accept. Real verbs are far shorter.
+pub const MAX_VERB_LEN: usize = 32;
+
+/// The most distinct targets a single command may name in its comma-separated list.
+pub const MAX_TARGETS: usize = 4;
+
 /// Helper for the message parser
 struct Scanner {
     b: Bytes,
@@ -20,6 +39,11 @@ struct Scanner {
 /// The parsed form of an IRC message.
 #[derive(PartialEq)]
 pub struct Message {
+    /// IRCv3 message tags, as key and value pairs. Values have already been unescaped. Tags
+    /// without a value, or with an empty value, have a value of `None`.
+    pub tags: Vec<(Bytes, Option<Bytes>)>,
+    /// The source of the message, without the leading `:`, if one was given.
+    pub prefix: Option<Bytes>,
     /// The verb portion of a message, specifying which action to take.
     pub verb: Bytes,
     /// The arguments to the verb.
@@ -69,31 +93,324 @@ impl Scanner {
     }
 }
 
+/// Parses the tags section of a message, not including the leading `@`
+fn parse_tags(mut b: Bytes) -> Vec<(Bytes, Option<Bytes>)> {
+    let mut tags = Vec::new();
+
+    while !b.is_empty() {
+        let end = b.iter().position(|c| *c == b';').unwrap_or(b.len());
+        let mut tag = b.split_to(end);
+        if !b.is_empty() {
+            b.split_to(1);
+        }
+
+        if tag.is_empty() {
+            continue;
+        }
+
+        match tag.iter().position(|c| *c == b'=') {
+            Some(eq) => {
+                let key = tag.split_to(eq);
+                let value = unescape_tag_value(&tag[1..]);
+                tags.push((key, if value.is_empty() { None } else { Some(value) }));
+            },
+
+            None => tags.push((tag, None)),
+        }
+    }
+
+    tags
+}
+
+/// Undoes the escaping applied to tag values
+fn unescape_tag_value(v: &[u8]) -> Bytes {
+    let mut out = Vec::with_capacity(v.len());
+    let mut iter = v.iter();
+
+    while let Some(c) = iter.next() {
+        if *c != b'\\' {
+            out.push(*c);
+            continue;
+        }
+
+        // a lone backslash at the end is dropped
+        match iter.next() {
+            Some(&b':') => out.push(b';'),
+            Some(&b's') => out.push(b' '),
+            Some(&b'r') => out.push(b'\r'),
+            Some(&b'n') => out.push(b'\n'),
+            Some(c) => out.push(*c),
+            None => { },
+        }
+    }
+
+    Bytes::from(out)
+}
+
+/// Applies the escaping needed for tag values
+fn escape_tag_value(v: &[u8], out: &mut Vec<u8>) {
+    for c in v.iter() {
+        match *c {
+            b';' => out.extend_from_slice(b"\\:"),
+            b' ' => out.extend_from_slice(b"\\s"),
+            b'\\' => out.extend_from_slice(b"\\\\"),
+            b'\r' => out.extend_from_slice(b"\\r"),
+            b'\n' => out.extend_from_slice(b"\\n"),
+            c => out.push(c),
+        }
+    }
+}
+
 impl Message {
-    /// Parses the byte slice into a `Message`
+    /// Parses the byte slice into a `Message`, with at most `MAX_ARGS` arguments
     pub fn parse<T>(spec: T) -> ParseResult<Message>
+    where Bytes: From<T> {
+        Message::parse_with_limit(spec, MAX_ARGS)
+    }
+
+    /// Parses the byte slice into a `Message` with at most `max_args` arguments. When there are
+    /// more, the rest of the line becomes the last argument, spaces and all, with a leading `:`
+    /// removed if there is one. `max_args` should be at least 1.
+    pub fn parse_with_limit<T>(spec: T, max_args: usize) -> ParseResult<Message>
     where Bytes: From<T> {
         let mut scan = Scanner::new(From::from(spec));
 
         scan.skip_spaces();
 
+        let tags = if scan.peek() == b'@' {
+            scan.skip();
+            parse_tags(scan.chomp())
+        } else {
+            Vec::new()
+        };
+
+        let prefix = if scan.peek() == b':' {
+            scan.skip();
+            Some(scan.chomp())
+        } else {
+            None
+        };
+
         let verb = scan.chomp();
 
+        if prefix.is_some() && verb.is_empty() {
+            return Err("missing verb after prefix");
+        }
+
+        if verb.len() > MAX_VERB_LEN {
+            return Err("verb too long");
+        }
+
         let mut args = Vec::new();
         while !scan.empty() {
             args.push(if scan.peek() == b':' {
                 scan.skip();
                 scan.chomp_remaining()
+            } else if args.len() + 1 >= max_args {
+                scan.chomp_remaining()
             } else {
                 scan.chomp()
             });
         }
 
         Ok(Message {
+            tags: tags,
+            prefix: prefix,
             verb: verb,
             args: args
         })
     }
+
+    /// Returns the verb as a string. Invalid UTF-8 is replaced with U+FFFD.
+    pub fn verb_str<'a>(&'a self) -> Cow<'a, str> {
+        String::from_utf8_lossy(&self.verb[..])
+    }
+
+    /// Returns the argument at the given index, if there is one.
+    pub fn arg(&self, i: usize) -> Option<&Bytes> {
+        self.args.get(i)
+    }
+
+    /// Returns the argument at the given index as a string, if there is one. Invalid UTF-8 is
+    /// replaced with U+FFFD.
+    pub fn arg_str<'a>(&'a self, i: usize) -> Option<Cow<'a, str>> {
+        self.arg(i).map(|a| String::from_utf8_lossy(&a[..]))
+    }
+
+    /// Returns an iterator over the arguments as strings, converted as with `arg_str`.
+    pub fn args_str<'a>(&'a self) -> Box<Iterator<Item=Cow<'a, str>> + 'a> {
+        Box::new(self.args.iter().map(|a| String::from_utf8_lossy(&a[..])))
+    }
+
+    /// Returns an iterator over the comma-separated targets in the argument at the given index.
+    /// Empty targets are skipped. Callers are expected to hold the number of distinct targets to
+    /// `MAX_TARGETS`.
+    pub fn targets<'a>(&'a self, i: usize) -> Box<Iterator<Item=&'a [u8]> + 'a> {
+        match self.arg(i) {
+            Some(arg) => Box::new(arg.split(|b| *b == b',').filter(|t| !t.is_empty())),
+            None => Box::new(iter::empty()),
+        }
+    }
+
+    /// Builds a numeric reply from the given server to the given target. See `irc::numeric` for
+    /// the codes.
+    pub fn numeric(server: &[u8], code: u16, target: &[u8], args: &[&[u8]]) -> Message {
+        let mut all_args = Vec::with_capacity(args.len() + 1);
+        all_args.push(Bytes::from(target));
+        all_args.extend(args.iter().map(|a| Bytes::from(*a)));
+
+        Message {
+            tags: Vec::new(),
+            prefix: Some(Bytes::from(server)),
+            verb: Bytes::from(format!("{:03}", code)),
+            args: all_args,
+        }
+    }
+
+    /// Builds the `PONG` the given server sends in answer to a `PING` carrying `token`.
+    pub fn pong(server: &[u8], token: &[u8]) -> Message {
+        Message {
+            tags: Vec::new(),
+            prefix: Some(Bytes::from(server)),
+            verb: Bytes::from("PONG"),
+            args: vec![Bytes::from(server), Bytes::from(token)],
+        }
+    }
+
+    /// If this is a `PRIVMSG` or `NOTICE` whose text is a CTCP message, such as
+    /// `\x01ACTION waves\x01`, returns the CTCP command and its arguments. The closing `\x01` is
+    /// optional, as some clients leave it off.
+    pub fn ctcp(&self) -> Option<(Bytes, Bytes)> {
+        if &self.verb[..] != b"PRIVMSG" && &self.verb[..] != b"NOTICE" {
+            return None;
+        }
+
+        let text = match self.args.get(1) {
+            Some(text) if text.len() > 1 && text[0] == 0x01 => text,
+            _ => return None,
+        };
+
+        let end = if text[text.len() - 1] == 0x01 { text.len() - 1 } else { text.len() };
+        let body = text.slice(1, end);
+
+        match body.iter().position(|c| *c == b' ') {
+            Some(i) => Some((body.slice(0, i), body.slice_from(i + 1))),
+            None => Some((body, Bytes::new())),
+        }
+    }
+
+    /// Builds a CTCP reply, which is a `NOTICE` to the given target.
+    pub fn ctcp_reply(source: &[u8], target: &[u8], command: &[u8], args: &[u8]) -> Message {
+        let mut text = Vec::with_capacity(command.len() + args.len() + 3);
+        text.push(0x01);
+        text.extend_from_slice(command);
+        if !args.is_empty() {
+            text.push(b' ');
+            text.extend_from_slice(args);
+        }
+        text.push(0x01);
+
+        Message {
+            tags: Vec::new(),
+            prefix: Some(Bytes::from(source)),
+            verb: Bytes::from("NOTICE"),
+            args: vec![Bytes::from(target), Bytes::from(text)],
+        }
+    }
+
+    /// Serializes the `Message` into a line suitable for sending, including the trailing
+    /// `\r\n`. The last argument is sent as a trailing argument if it needs to be. Other
+    /// arguments cannot contain spaces or start with `:`, and are sent as-is.
+    ///
+    /// If the line would be longer than `MAX_LINE_LEN`, the last argument is truncated to fit,
+    /// taking care not to split a UTF-8 sequence, and sent as a trailing argument. The prefix,
+    /// verb and other arguments are never cut, even if they alone are too long. As with IRCv3,
+    /// tags do not count towards the limit.
+    pub fn to_bytes(&self) -> Bytes {
+        let mut out = self.tags_to_vec();
+        out.extend_from_slice(&self.body_to_vec(Some(MAX_LINE_LEN - 2))[..]);
+        out.extend_from_slice(b"\r\n");
+        Bytes::from(out)
+    }
+
+    /// Like `to_bytes`, but returns an error instead of truncating when the line would be
+    /// longer than `MAX_LINE_LEN`.
+    pub fn to_bytes_checked(&self) -> Result<Bytes, &'static str> {
+        let body = self.body_to_vec(None);
+
+        if body.len() + 2 > MAX_LINE_LEN {
+            return Err("message too long");
+        }
+
+        let mut out = self.tags_to_vec();
+        out.extend_from_slice(&body[..]);
+        out.extend_from_slice(b"\r\n");
+        Ok(Bytes::from(out))
+    }
+
+    fn tags_to_vec(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+
+        if !self.tags.is_empty() {
+            out.push(b'@');
+            for (i, (key, value)) in self.tags.iter().enumerate() {
+                if i > 0 {
+                    out.push(b';');
+                }
+                out.extend_from_slice(&key[..]);
+                if let Some(value) = value.as_ref() {
+                    out.push(b'=');
+                    escape_tag_value(&value[..], &mut out);
+                }
+            }
+            out.push(b' ');
+        }
+
+        out
+    }
+
+    /// Serializes everything but the tags. If `limit` is given, the last argument is cut short
+    /// so that the result is no longer than that, if possible.
+    fn body_to_vec(&self, limit: Option<usize>) -> Vec<u8> {
+        let mut out = Vec::new();
+
+        if let Some(ref prefix) = self.prefix {
+            out.push(b':');
+            out.extend_from_slice(&prefix[..]);
+            out.push(b' ');
+        }
+
+        out.extend_from_slice(&self.verb[..]);
+
+        for (i, arg) in self.args.iter().enumerate() {
+            out.push(b' ');
+
+            let last = i + 1 == self.args.len();
+            let mut arg = &arg[..];
+            let mut needs_colon = arg.is_empty() || arg[0] == b':' ||
+                arg.iter().any(|c| (*c as char).is_whitespace());
+
+            if let (Some(limit), true) = (limit, last) {
+                // a truncated argument always gets a colon, which needs room too
+                if out.len() + 1 + arg.len() > limit {
+                    let mut cut = limit.saturating_sub(out.len() + 1);
+                    while cut > 0 && (arg[cut] & 0xc0) == 0x80 {
+                        cut -= 1;
+                    }
+                    arg = &arg[..cut];
+                    needs_colon = true;
+                }
+            }
+
+            if last && needs_colon {
+                out.push(b':');
+            }
+
+            out.extend_from_slice(arg);
+        }
+
+        out
+    }
 }
 
 fn write_bytes(f: &mut fmt::Formatter, s: &Bytes) -> fmt::Result {
@@ -106,6 +423,28 @@ fn write_bytes(f: &mut fmt::Formatter, s: &Bytes) -> fmt::Result {
 impl fmt::Debug for Message {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         try!(write!(f, "Message("));
+
+        if !self.tags.is_empty() {
+            try!(write!(f, "@"));
+            for (i, (key, value)) in self.tags.iter().enumerate() {
+                if i > 0 {
+                    try!(write!(f, ";"));
+                }
+                try!(write_bytes(f, key));
+                if let Some(value) = value.as_ref() {
+                    try!(write!(f, "="));
+                    try!(write_bytes(f, value));
+                }
+            }
+            try!(write!(f, ", "));
+        }
+
+        if let Some(ref prefix) = self.prefix {
+            try!(write!(f, ":"));
+            try!(write_bytes(f, prefix));
+            try!(write!(f, ", "));
+        }
+
         try!(write_bytes(f, &self.verb));
 
         for s in self.args.iter() {
@@ -125,6 +464,8 @@ fn test_good_parse(
     args: Vec<&str>
 ) {
     let expected = Message {
+        tags: Vec::new(),
+        prefix: None,
         verb: Bytes::from(verb),
         args: args.into_iter().map(|v| Bytes::from(v)).collect()
     };
@@ -181,3 +522,254 @@ fn message_parse_client_still_dumb() {
         "PING", vec!["this", "has", "spaces"],
     );
 }
+
+#[test]
+fn message_parse_folds_extra_args() {
+    let line = "CMD 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15 16 17  18 19 :20 and more";
+    let m = Message::parse(line).unwrap();
+
+    assert_eq!(m.args.len(), MAX_ARGS);
+    assert_eq!(&m.args[13][..], b"14");
+    assert_eq!(&m.args[14][..], &b"15 16 17  18 19 :20 and more"[..]);
+
+    let m = Message::parse("CMD 1 2 3 4 5 6 7 8 9 10 11 12 13 14 :15 16").unwrap();
+    assert_eq!(&m.args[14][..], b"15 16");
+
+    // lines within the limit are unaffected
+    let m = Message::parse("CMD 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15").unwrap();
+    assert_eq!(&m.args[14][..], b"15");
+
+    let m = Message::parse_with_limit("CMD a b c :d e", 2).unwrap();
+    assert_eq!(m.args, vec![Bytes::from("a"), Bytes::from("b c :d e")]);
+}
+
+#[test]
+fn message_parse_long_verb() {
+    let verb = "X".repeat(MAX_VERB_LEN);
+    assert!(Message::parse(&format!("{} a", verb)[..]).is_ok());
+    assert!(Message::parse(&format!("X{} a", verb)[..]).is_err());
+}
+
+#[test]
+fn message_parse_tags() {
+    let actual = Message::parse("@id=123;key=val\\s PRIVMSG #x :hi").unwrap();
+
+    assert_eq!(actual, Message {
+        tags: vec![
+            (Bytes::from("id"), Some(Bytes::from("123"))),
+            (Bytes::from("key"), Some(Bytes::from("val "))),
+        ],
+        prefix: None,
+        verb: Bytes::from("PRIVMSG"),
+        args: vec![Bytes::from("#x"), Bytes::from("hi")],
+    });
+
+    assert_eq!(format!("{:?}", actual),
+        r##"Message(@"id"="123";"key"="val ", "PRIVMSG", "#x", "hi")"##);
+}
+
+#[test]
+fn message_parse_bare_tag() {
+    let actual = Message::parse("@draft/bare;esc=a\\:b\\\\c\\r\\n;empty= PING x").unwrap();
+
+    assert_eq!(actual, Message {
+        tags: vec![
+            (Bytes::from("draft/bare"), None),
+            (Bytes::from("esc"), Some(Bytes::from("a;b\\c\r\n"))),
+            (Bytes::from("empty"), None),
+        ],
+        prefix: None,
+        verb: Bytes::from("PING"),
+        args: vec![Bytes::from("x")],
+    });
+}
+
+#[test]
+fn message_parse_prefix() {
+    let actual = Message::parse(":irc.example PING x").unwrap();
+
+    assert_eq!(actual, Message {
+        tags: Vec::new(),
+        prefix: Some(Bytes::from("irc.example")),
+        verb: Bytes::from("PING"),
+        args: vec![Bytes::from("x")],
+    });
+}
+
+#[test]
+fn message_parse_prefix_trailing() {
+    let actual = Message::parse("@id=1 :nick!u@h PRIVMSG #c :hey").unwrap();
+
+    assert_eq!(actual, Message {
+        tags: vec![(Bytes::from("id"), Some(Bytes::from("1")))],
+        prefix: Some(Bytes::from("nick!u@h")),
+        verb: Bytes::from("PRIVMSG"),
+        args: vec![Bytes::from("#c"), Bytes::from("hey")],
+    });
+
+    assert_eq!(format!("{:?}", actual),
+        r##"Message(@"id"="1", :"nick!u@h", "PRIVMSG", "#c", "hey")"##);
+}
+
+#[test]
+fn message_parse_prefix_without_verb() {
+    assert!(Message::parse(":nick!u@h").is_err());
+    assert!(Message::parse(":nick!u@h   ").is_err());
+}
+
+#[cfg(test)]
+fn test_round_trip(m: Message) {
+    let b = m.to_bytes();
+    assert!(b.ends_with(b"\r\n"));
+
+    let line = b.slice_to(b.len() - 2);
+    assert_eq!(Message::parse(line).unwrap(), m);
+}
+
+#[test]
+fn message_to_bytes() {
+    let m = Message {
+        tags: vec![(Bytes::from("key"), Some(Bytes::from("a; b")))],
+        prefix: Some(Bytes::from("nick!u@h")),
+        verb: Bytes::from("PRIVMSG"),
+        args: vec![Bytes::from("#c"), Bytes::from("hey there")],
+    };
+
+    assert_eq!(&m.to_bytes()[..], &b"@key=a\\:\\sb :nick!u@h PRIVMSG #c :hey there\r\n"[..]);
+}
+
+#[test]
+fn message_round_trip() {
+    test_round_trip(Message::parse("PING 123").unwrap());
+    test_round_trip(Message::parse("PRIVMSG #c :hello, world").unwrap());
+    test_round_trip(Message::parse("PRIVMSG #c ::)").unwrap());
+    test_round_trip(Message::parse("TOPIC #c :").unwrap());
+    test_round_trip(Message::parse(":irc.example 001 nick :Welcome to IRC").unwrap());
+    test_round_trip(Message::parse("@a;b=\\\\\\r\\n\\:;c=d :x NOTICE * :hi").unwrap());
+}
+
+#[test]
+fn message_to_bytes_truncates() {
+    let long = "\u{e9}".repeat(512);
+    let m = Message {
+        tags: vec![(Bytes::from("id"), Some(Bytes::from("1")))],
+        prefix: Some(Bytes::from("nick!u@h")),
+        verb: Bytes::from("PRIVMSG"),
+        args: vec![Bytes::from("#c"), Bytes::from(long.clone())],
+    };
+
+    let b = m.to_bytes();
+    let tags_len = "@id=1 ".len();
+    assert!(b.len() - tags_len <= MAX_LINE_LEN);
+    assert!(b.ends_with(b"\r\n"));
+
+    let line = ::std::str::from_utf8(&b[..]).expect("truncated on a UTF-8 boundary");
+    let parsed = Message::parse(&line[..line.len() - 2]).unwrap();
+    assert!(long.as_bytes().starts_with(&parsed.args[1][..]));
+
+    assert!(m.to_bytes_checked().is_err());
+}
+
+#[test]
+fn message_to_bytes_keeps_middle_args() {
+    // only the trailing argument is shortened, and a colon keeps it trailing
+    let m = Message {
+        tags: Vec::new(),
+        prefix: Some(Bytes::from("nick!u@h")),
+        verb: Bytes::from("PRIVMSG"),
+        args: vec![Bytes::from("#c"), Bytes::from("x".repeat(600))],
+    };
+    let b = m.to_bytes();
+    assert_eq!(b.len(), MAX_LINE_LEN);
+    assert!(b.starts_with(b":nick!u@h PRIVMSG #c :xxx"));
+
+    // a line that is too long without its trailing argument loses all of it, but nothing else
+    let target = "#".repeat(600);
+    let m = Message {
+        tags: Vec::new(),
+        prefix: None,
+        verb: Bytes::from("PRIVMSG"),
+        args: vec![Bytes::from(target.clone()), Bytes::from("hi")],
+    };
+    assert_eq!(&m.to_bytes()[..], format!("PRIVMSG {} :\r\n", target).as_bytes());
+}
+
+#[test]
+fn message_to_bytes_checked() {
+    let m = Message::parse("PRIVMSG #c :short enough").unwrap();
+    assert_eq!(m.to_bytes_checked(), Ok(m.to_bytes()));
+}
+
+#[test]
+fn message_numeric() {
+    use irc::numeric::*;
+
+    let m = Message::numeric(b"irc.example", RPL_WELCOME, b"nick", &[b"Welcome to IRC"]);
+    assert_eq!(&m.to_bytes()[..], &b":irc.example 001 nick :Welcome to IRC\r\n"[..]);
+
+    let m = Message::numeric(b"irc.example", ERR_NICKNAMEINUSE, b"*",
+        &[b"nick", b"Nickname is already in use"]);
+    assert_eq!(&m.to_bytes()[..], &b":irc.example 433 * nick :Nickname is already in use\r\n"[..]);
+
+    let m = Message::numeric(b"irc.example", RPL_ISUPPORT, b"nick", &[b"CASEMAPPING=rfc1459"]);
+    assert_eq!(&m.to_bytes()[..], &b":irc.example 005 nick CASEMAPPING=rfc1459\r\n"[..]);
+}
+
+#[test]
+fn message_ctcp() {
+    let m = Message::parse(&b"PRIVMSG nick :\x01ACTION waves\x01"[..]).unwrap();
+    assert_eq!(m.ctcp(), Some((Bytes::from("ACTION"), Bytes::from("waves"))));
+
+    let m = Message::parse(&b"PRIVMSG nick :\x01VERSION"[..]).unwrap();
+    assert_eq!(m.ctcp(), Some((Bytes::from("VERSION"), Bytes::new())));
+
+    let m = Message::parse(&b"PRIVMSG nick :hello there"[..]).unwrap();
+    assert_eq!(m.ctcp(), None);
+
+    let m = Message::parse(&b"TOPIC #chan :\x01ACTION waves\x01"[..]).unwrap();
+    assert_eq!(m.ctcp(), None);
+}
+
+#[test]
+fn message_ctcp_reply() {
+    let m = Message::ctcp_reply(b"server", b"nick", b"VERSION", b"ircd-oxide");
+    assert_eq!(&m.to_bytes()[..], &b":server NOTICE nick :\x01VERSION ircd-oxide\x01\r\n"[..]);
+    assert_eq!(m.ctcp(), Some((Bytes::from("VERSION"), Bytes::from("ircd-oxide"))));
+}
+
+#[test]
+fn message_arg_helpers() {
+    let m = Message::parse(&b"PRIVMSG #oxide :hi \xff there"[..]).unwrap();
+
+    assert_eq!(m.verb_str(), "PRIVMSG");
+    assert_eq!(m.arg(0), Some(&Bytes::from("#oxide")));
+    assert_eq!(m.arg(2), None);
+    assert_eq!(m.arg_str(0), Some(Cow::Borrowed("#oxide")));
+    assert_eq!(m.arg_str(1).unwrap(), "hi \u{fffd} there");
+    assert_eq!(m.arg_str(2), None);
+
+    let args: Vec<Cow<str>> = m.args_str().collect();
+    assert_eq!(args, vec!["#oxide", "hi \u{fffd} there"]);
+
+    let m = Message::parse("QUIT").unwrap();
+    assert_eq!(m.arg(0), None);
+    assert_eq!(m.arg_str(0), None);
+    assert_eq!(m.args_str().count(), 0);
+}
+
+#[test]
+fn message_targets() {
+    let m = Message::parse("PRIVMSG a,,b,#c :hi").unwrap();
+    let targets: Vec<&[u8]> = m.targets(0).collect();
+    assert_eq!(targets, vec![&b"a"[..], &b"b"[..], &b"#c"[..]]);
+    assert_eq!(m.targets(1).collect::<Vec<_>>(), vec![&b"hi"[..]]);
+    assert_eq!(m.targets(2).count(), 0);
+
+    let m = Message::parse("PRIVMSG ,,, :hi").unwrap();
+    assert_eq!(m.targets(0).count(), 0);
+
+    let m = Message::parse("PRIVMSG a,b,c,d,e,f :hi").unwrap();
+    let targets: Vec<&[u8]> = m.targets(0).collect();
+    assert_eq!(targets.len(), 6);
+    assert_eq!(targets[5], b"f");
+}
@@ -11,11 +11,17 @@ pub mod cap;
 pub mod codec;
 pub mod driver;
 pub mod listener;
+pub mod liveness;
 pub mod message;
+pub mod numeric;
 pub mod op;
 pub mod pending;
 pub mod pool;
+pub mod sasl;
 pub mod send;
+#[cfg(test)]
+pub mod testing;
+pub mod throttle;
 
 use std::convert::From;
 use std::fmt;
@@ -24,6 +30,12 @@ use std::io;
 pub use self::message::Message;
 pub use self::op::Op;
 
+/// The name this server uses as the prefix of the messages it sends
+pub const SERVER_NAME: &'static [u8] = b"ircd-oxide";
+
+/// The version string reported to clients
+pub const VERSION: &'static str = concat!("ircd-oxide-", env!("CARGO_PKG_VERSION"));
+
 /// A generic error type for IRC client handling. Where they occur, they generally cause the
 /// client connection to be closed as soon as possible.
 #[derive(Debug)]
@@ -0,0 +1,52 @@
+//! Numeric reply codes
+//!
+//! Only the replies we have a use for, or expect to have a use for soon, are listed here. Use
+//! `Message::numeric` to build the actual reply.
+
+pub const RPL_WELCOME: u16 = 1;
+pub const RPL_YOURHOST: u16 = 2;
+pub const RPL_CREATED: u16 = 3;
+pub const RPL_MYINFO: u16 = 4;
+pub const RPL_ISUPPORT: u16 = 5;
+
+pub const RPL_ENDOFSTATS: u16 = 219;
+pub const RPL_UMODEIS: u16 = 221;
+pub const RPL_STATSDEBUG: u16 = 249;
+pub const RPL_LUSERCLIENT: u16 = 251;
+
+pub const RPL_AWAY: u16 = 301;
+pub const RPL_ENDOFWHO: u16 = 315;
+pub const RPL_CHANNELMODEIS: u16 = 324;
+pub const RPL_NOTOPIC: u16 = 331;
+pub const RPL_TOPIC: u16 = 332;
+pub const RPL_WHOREPLY: u16 = 352;
+pub const RPL_NAMREPLY: u16 = 353;
+pub const RPL_ENDOFNAMES: u16 = 366;
+pub const RPL_MOTD: u16 = 372;
+pub const RPL_MOTDSTART: u16 = 375;
+pub const RPL_ENDOFMOTD: u16 = 376;
+
+pub const ERR_NOSUCHNICK: u16 = 401;
+pub const ERR_NOSUCHCHANNEL: u16 = 403;
+pub const ERR_CANNOTSENDTOCHAN: u16 = 404;
+pub const ERR_TOOMANYTARGETS: u16 = 407;
+pub const ERR_NORECIPIENT: u16 = 411;
+pub const ERR_NOTEXTTOSEND: u16 = 412;
+pub const ERR_UNKNOWNCOMMAND: u16 = 421;
+pub const ERR_NOMOTD: u16 = 422;
+pub const ERR_NONICKNAMEGIVEN: u16 = 431;
+pub const ERR_ERRONEUSNICKNAME: u16 = 432;
+pub const ERR_NICKNAMEINUSE: u16 = 433;
+pub const ERR_NOTONCHANNEL: u16 = 442;
+pub const ERR_NOTREGISTERED: u16 = 451;
+pub const ERR_NEEDMOREPARAMS: u16 = 461;
+pub const ERR_ALREADYREGISTRED: u16 = 462;
+pub const ERR_CHANOPRIVSNEEDED: u16 = 482;
+
+pub const RPL_LOGGEDIN: u16 = 900;
+pub const RPL_SASLSUCCESS: u16 = 903;
+pub const ERR_SASLFAIL: u16 = 904;
+pub const ERR_SASLTOOLONG: u16 = 905;
+pub const ERR_SASLABORTED: u16 = 906;
+pub const ERR_SASLALREADY: u16 = 907;
+pub const RPL_SASLMECHS: u16 = 908;
@@ -4,6 +4,8 @@ use futures::Async;
 use futures::Future;
 use futures::Poll;
 
+use tokio_core::reactor::Timeout;
+
 use common::observe;
 use crdb;
 use irc;
@@ -25,6 +27,9 @@ pub enum Op<T> {
     /// the transaction has been fully consumed
     CRDB(crdb::Completion, Option<T>),
 
+    /// A future that waits for a timer to fire and then returns the given data
+    Timeout(Timeout, Option<T>),
+
     /// A `Boxed` operation is simply a wrapper around any kind of future that resolves to a `T`
     /// and errors with `irc::Error`
     Boxed(Box<Future<Item=T, Error=irc::Error>>)
@@ -47,6 +52,11 @@ impl<T: 'static> Op<T> {
         Op::CRDB(cpl, Some(data))
     }
 
+    /// Creates an operation that waits for the timeout to fire and returns the given data.
+    pub fn after(timeout: Timeout, data: T) -> Op<T> {
+        Op::Timeout(timeout, Some(data))
+    }
+
     /// Creates an operation that wraps the given future.
     pub fn boxed<F: 'static>(f: F) -> Op<T>
     where F: Future<Item=T, Error=irc::Error> {
@@ -62,6 +72,7 @@ impl<T: 'static> Op<T> {
 
             Op::Observe(cpl, data) => Op::Observe(cpl, data.map(f)),
             Op::CRDB(cpl, data) => Op::CRDB(cpl, data.map(f)),
+            Op::Timeout(timeout, data) => Op::Timeout(timeout, data.map(f)),
 
             Op::Boxed(inner) => Op::boxed(inner.map(f)),
         }
@@ -78,19 +89,35 @@ impl<T> Future for Op<T> {
                 .unwrap_or(Err(irc::Error::Other("Op::Nil polled more than once")))
                 .map(Async::Ready),
 
-            // TODO: get rid of expect()s in next arms, replace with returning Err
-
             Op::Observe(ref mut inner, ref mut data) => {
-                if inner.poll().expect("completion failed unexpectedly").is_ready() {
-                    Ok(Async::Ready(data.take().expect("Op::Observe polled more than once")))
+                let ready = try!(inner.poll()
+                    .map_err(|_| irc::Error::Other("observe completion failed unexpectedly")));
+                if ready.is_ready() {
+                    data.take()
+                        .ok_or(irc::Error::Other("Op::Observe polled more than once"))
+                        .map(Async::Ready)
                 } else {
                     Ok(Async::NotReady)
                 }
             },
 
             Op::CRDB(ref mut inner, ref mut data) => {
-                if inner.poll().expect("completion failed unexpectedly").is_ready() {
-                    Ok(Async::Ready(data.take().expect("Op::CRDB polled more than once")))
+                let ready = try!(inner.poll()
+                    .map_err(|_| irc::Error::Other("CRDB completion failed unexpectedly")));
+                if ready.is_ready() {
+                    data.take()
+                        .ok_or(irc::Error::Other("Op::CRDB polled more than once"))
+                        .map(Async::Ready)
+                } else {
+                    Ok(Async::NotReady)
+                }
+            },
+
+            Op::Timeout(ref mut inner, ref mut data) => {
+                if try!(inner.poll()).is_ready() {
+                    data.take()
+                        .ok_or(irc::Error::Other("Op::Timeout polled more than once"))
+                        .map(Async::Ready)
                 } else {
                     Ok(Async::NotReady)
                 }
@@ -100,3 +127,42 @@ impl<T> Future for Op<T> {
         }
     }
 }
+
+#[test]
+fn op_after_fires() {
+    use std::time::Duration;
+    use std::time::Instant;
+    use tokio_core::reactor::Core;
+
+    let mut core = Core::new().unwrap();
+    let start = Instant::now();
+    let timeout = Timeout::new(Duration::from_millis(20), &core.handle()).unwrap();
+    let op = Op::after(timeout, 5).map(|n| n * 2);
+
+    assert_eq!(core.run(op).unwrap(), 10);
+    assert!(start.elapsed() >= Duration::from_millis(20));
+}
+
+#[test]
+fn op_polled_past_completion() {
+    use futures::future;
+    use common::observe::Observable;
+    use crdb::schema::Text;
+
+    let mut db = crdb::CRDB::new();
+    let mut table = db.create_table("t", Text);
+    let mut updates: Observable<u32> = Observable::new();
+
+    let mut ops = [Op::crdb(db.commit(table.open()), 1), Op::observe(updates.put(2), 2)];
+
+    future::lazy(|| {
+        for op in ops.iter_mut() {
+            assert!(op.poll().unwrap().is_ready());
+            match op.poll() {
+                Err(irc::Error::Other(_)) => { },
+                _ => panic!("expected an error polling a finished op"),
+            }
+        }
+        Ok::<(), ()>(())
+    }).wait().unwrap();
+}
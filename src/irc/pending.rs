@@ -4,48 +4,609 @@ use futures::Future;
 
 use irc;
 use irc::active::Active;
+use irc::cap::CapCommand;
+use irc::cap::CapNegotiation;
+use irc::cap::ClientCaps;
 use irc::driver::Client;
+use irc::numeric::*;
+use irc::pool::Pool;
+use irc::sasl;
+use irc::sasl::Authenticator;
+use irc::sasl::SaslSession;
+use irc::sasl::Step;
 use irc::send::Sender;
+use irc::Message;
 
 use world::World;
 
+/// A client that has not finished registering.
+///
+/// Registration completes once the client has sent both `NICK` and `USER`, has finished any
+/// capability negotiation and SASL exchange it started, and has successfully claimed its
+/// nickname.
 pub struct Pending {
     world: World,
     out: Sender,
-    nick: Option<String>
+    nick: Option<String>,
+    user: Option<String>,
+    caps: CapNegotiation,
+    sasl: SaslSession,
+    account: Option<String>,
+    pool: Option<Pool>,
 }
 
 impl Pending {
     pub fn new(world: World, out: Sender) -> Pending {
+        Pending::with_sasl(world, out, None)
+    }
+
+    /// Creates a `Pending` that lets clients log in with SASL `PLAIN`, checking their
+    /// credentials with `auth`.
+    pub fn with_authenticator(world: World, out: Sender, auth: Authenticator) -> Pending {
+        let mut pending = Pending::with_sasl(world, out, Some(auth));
+        pending.caps.set_values("sasl", sasl::MECHANISMS);
+        pending
+    }
+
+    /// Creates a `Pending` that joins `pool` once it registers, so that it can exchange messages
+    /// with the other clients there.
+    pub fn with_pool(world: World, out: Sender, pool: Pool) -> Pending {
+        let mut pending = Pending::with_sasl(world, out, None);
+        pending.pool = Some(pool);
+        pending
+    }
+
+    fn with_sasl(world: World, out: Sender, auth: Option<Authenticator>) -> Pending {
         Pending {
             world: world,
             out: out,
             nick: None,
+            user: None,
+            caps: CapNegotiation::new(implemented_caps(auth.is_some())),
+            sasl: SaslSession::new(auth),
+            account: None,
+            pool: None,
         }
     }
 
+    /// The account the client has logged in to with SASL, if any
+    pub fn account(&self) -> Option<&str> {
+        self.account.as_ref().map(|a| &a[..])
+    }
+
     pub fn handle(mut self, m: irc::Message) -> irc::Op<Client> {
         debug!(" -> {:?}", m);
 
-        if b"NICK" == &m.verb[..] && m.args.len() > 0 {
-            if let Ok(nick) = String::from_utf8(m.args[0].to_vec()) {
-                self.nick = Some(nick);
+        match &m.verb[..] {
+            b"NICK" => match m.arg(0) {
+                Some(nick) if is_valid_nick(nick) => {
+                    self.nick = Some(String::from_utf8_lossy(nick).into_owned());
+                },
+                Some(nick) if !nick.is_empty() => {
+                    // the nick is echoed as a middle argument, so only up to the first space
+                    let shown = nick.split(|&c| c == b' ').next().unwrap_or(b"");
+                    let shown = if shown.is_empty() || shown[0] == b':' { b"*" } else { shown };
+                    self.numeric(ERR_ERRONEUSNICKNAME, &[shown, b"Erroneous nickname"]);
+                    return irc::Op::ok(Client::Pending(self));
+                },
+                _ => {
+                    self.numeric(ERR_NONICKNAMEGIVEN, &[b"No nickname given"]);
+                    return irc::Op::ok(Client::Pending(self));
+                },
+            },
+
+            b"USER" => match m.arg(0).and_then(|a| String::from_utf8(a.to_vec()).ok()) {
+                Some(user) if m.args.len() >= 4 => self.user = Some(user),
+                _ => {
+                    self.numeric(ERR_NEEDMOREPARAMS, &[b"USER", b"Not enough parameters"]);
+                    return irc::Op::ok(Client::Pending(self));
+                },
+            },
+
+            b"CAP" => self.handle_cap(&m),
+
+            b"AUTHENTICATE" => self.handle_authenticate(&m),
+
+            b"PING" => {
+                match m.arg(0) {
+                    Some(token) => {
+                        let pong = Message::pong(irc::SERVER_NAME, token);
+                        let _ = self.out.send(&pong.to_bytes());
+                    },
+                    None => self.numeric(ERR_NEEDMOREPARAMS, &[b"PING", b"Not enough parameters"]),
+                }
+                return irc::Op::ok(Client::Pending(self));
+            },
+
+            b"PONG" => { },
+
+            _ => {
+                self.numeric(ERR_NOTREGISTERED, &[b"You have not registered"]);
+                return irc::Op::ok(Client::Pending(self));
+            },
+        }
+
+        self.try_register()
+    }
+
+    /// Handles the client closing its end of the connection before registering, which needs no
+    /// goodbye.
+    pub fn handle_eof(mut self) {
+        self.out.close_soft();
+    }
+
+    fn handle_cap(&mut self, m: &irc::Message) {
+        let reply = match CapCommand::parse(m) {
+            Some(CapCommand::Ls(version)) => ("LS", self.caps.ls(version)),
+            Some(CapCommand::List) => ("LIST", self.caps.enabled().names().join(" ")),
+            Some(CapCommand::Req(list)) => {
+                let reply = self.caps.req(&list).message(irc::SERVER_NAME, self.target());
+                let _ = self.out.send(&reply.to_bytes());
+                return;
+            },
+            Some(CapCommand::End) => {
+                self.caps.end();
+                return;
+            },
+            None => return,
+        };
+
+        let target = self.target().to_vec();
+        let reply = Message {
+            tags: Vec::new(),
+            prefix: Some(irc::SERVER_NAME.into()),
+            verb: "CAP".into(),
+            args: vec![target.into(), reply.0.into(), reply.1.into()],
+        };
+        let _ = self.out.send(&reply.to_bytes());
+    }
+
+    fn handle_authenticate(&mut self, m: &irc::Message) {
+        let arg = match m.arg(0) {
+            Some(arg) if self.caps.enabled().sasl() => arg.clone(),
+            Some(_) => {
+                self.numeric(ERR_SASLFAIL, &[b"SASL authentication failed"]);
+                return;
+            },
+            None => {
+                self.numeric(ERR_NEEDMOREPARAMS, &[b"AUTHENTICATE", b"Not enough parameters"]);
+                return;
+            },
+        };
+
+        match self.sasl.authenticate(&arg) {
+            Step::Challenge => { let _ = self.out.send(b"AUTHENTICATE +\r\n"); },
+            Step::Continue => { },
+            Step::Success(account) => {
+                let mask = self.target().to_vec();
+                let text = format!("You are now logged in as {}", account);
+                self.numeric(RPL_LOGGEDIN, &[&mask, account.as_bytes(), text.as_bytes()]);
+                self.numeric(RPL_SASLSUCCESS, &[b"SASL authentication successful"]);
+                self.account = Some(account);
+            },
+            Step::UnknownMechanism => {
+                let mechs = sasl::MECHANISMS.join(",");
+                self.numeric(RPL_SASLMECHS, &[mechs.as_bytes(), b"are available SASL mechanisms"]);
+                self.numeric(ERR_SASLFAIL, &[b"SASL authentication failed"]);
+            },
+            Step::Fail => self.numeric(ERR_SASLFAIL, &[b"SASL authentication failed"]),
+            Step::TooLong => self.numeric(ERR_SASLTOOLONG, &[b"SASL message too long"]),
+            Step::Aborted => self.numeric(ERR_SASLABORTED, &[b"SASL authentication aborted"]),
+            Step::Already => {
+                self.numeric(ERR_SASLALREADY, &[b"You have already authenticated using SASL"]);
+            },
+        }
+    }
+
+    fn try_register(mut self) -> irc::Op<Client> {
+        if self.user.is_none() || self.caps.negotiating() || self.sasl.in_progress() {
+            return irc::Op::ok(Client::Pending(self));
+        }
+
+        let nick = match self.nick.take() {
+            Some(nick) => nick,
+            None => return irc::Op::ok(Client::Pending(self)),
+        };
+
+        // a full pool would refuse the user, so don't leave them in the world
+        if let Some(ref pool) = self.pool {
+            if let Err(e) = pool.check_room(&mut self.out) {
+                return irc::Op::err(e);
             }
         }
 
-        if let Some(nick) = self.nick.as_ref().cloned() {
-            self.out.send(&b"auth successful\r\n"[..]);
+        let user = self.world.new_user_id();
+        let claimed = match self.world.use_nick(user.clone(), nick.clone()) {
+            Some(claimed) => claimed,
+            None => {
+                self.numeric(ERR_NICKNAMEINUSE, &[nick.as_bytes(), b"Nickname is already in use"]);
+                return irc::Op::ok(Client::Pending(self));
+            },
+        };
+
+        let added = self.world.add_user(user.clone());
+
+        let op = claimed.and(added).map_err(|_| irc::Error::Other("register error"));
+        let op = op.and_then(move |_| {
+            if let Some(ref mut pool) = self.pool {
+                // the pool may have filled up while the world caught up
+                if let Err(e) = pool.add_user(user.clone(), self.out.clone()) {
+                    let _ = self.world.remove_user(user);
+                    return Err(e);
+                }
+            }
+
+            self.nick = Some(nick);
+            self.welcome();
+            let active = match self.pool {
+                Some(pool) => Active::with_pool(self.world, self.out, user, pool),
+                None => Active::new(self.world, self.out, user),
+            };
+            Ok(Client::Active(active))
+        });
+
+        irc::Op::boxed(op)
+    }
+
+    fn welcome(&mut self) {
+        let nick = self.target().to_vec();
+        let welcome = format!("Welcome to the Internet Relay Network {}", self.target_str());
+        let host = format!("Your host is {}, running version {}",
+            String::from_utf8_lossy(irc::SERVER_NAME), irc::VERSION);
+
+        self.numeric(RPL_WELCOME, &[welcome.as_bytes()]);
+        self.numeric(RPL_YOURHOST, &[host.as_bytes()]);
+        self.numeric(RPL_CREATED, &[b"This server has no creation date"]);
+
+        let myinfo = Message::numeric(irc::SERVER_NAME, RPL_MYINFO, &nick,
+            &[irc::SERVER_NAME, irc::VERSION.as_bytes(), b"i", b"nt"]);
+        let _ = self.out.send(&myinfo.to_bytes());
+    }
+
+    fn numeric(&mut self, code: u16, args: &[&[u8]]) {
+        let target = self.target().to_vec();
+        let m = Message::numeric(irc::SERVER_NAME, code, &target, args);
+        let _ = self.out.send(&m.to_bytes());
+    }
+
+    /// The name to address replies to, which is `*` until the client has picked a nickname
+    fn target(&self) -> &[u8] {
+        self.nick.as_ref().map(|n| n.as_bytes()).unwrap_or(b"*")
+    }
+
+    fn target_str(&self) -> &str {
+        self.nick.as_ref().map(|n| &n[..]).unwrap_or("*")
+    }
+}
+
+/// The capabilities this server implements, and so offers to clients. `sasl` is only offered
+/// when there is an authenticator to check credentials with.
+fn implemented_caps(sasl: bool) -> ClientCaps {
+    let mut caps = ClientCaps::of("cap-notify").expect("unknown capability");
+    if sasl {
+        caps.add(&ClientCaps::of("sasl").expect("unknown capability"));
+    }
+    caps
+}
+
+/// Checks a nickname against the RFC 1459 grammar: a letter, followed by any number of letters,
+/// digits, and the special characters ``-[]\`^{}``.
+fn is_valid_nick(nick: &[u8]) -> bool {
+    let special = |c: &u8| b"-[]\\`^{}".contains(c);
+
+    match nick.split_first() {
+        Some((first, rest)) => first.is_ascii_alphabetic() &&
+            rest.iter().all(|c| c.is_ascii_alphanumeric() || special(c)),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+use tokio_core::reactor::Core;
+
+#[cfg(test)]
+fn run_pending(core: &mut Core, world: World, lines: &[&'static str]) -> (Client, String) {
+    run_pending_with(core, world, None, lines)
+}
+
+#[cfg(test)]
+fn run_pending_with(core: &mut Core, world: World, auth: Option<Authenticator>,
+    lines: &[&'static str]) -> (Client, String)
+{
+    use irc::testing::TestClient;
+
+    let mut client = match auth {
+        Some(auth) => TestClient::with_authenticator(world, auth),
+        None => TestClient::new(world),
+    };
+
+    client.feed_lines(core, lines).unwrap();
+    let out = client.take_output();
+    (client.into_client().unwrap(), out)
+}
+
+#[cfg(test)]
+fn is_active(client: &Client) -> bool {
+    if let Client::Active(_) = *client { true } else { false }
+}
+
+#[test]
+fn register_nick_user() {
+    use common::Sid;
+
+    let mut core = Core::new().unwrap();
+    let world = World::new(&core.handle(), Sid::identity());
 
-            let op = self.world.add_user(nick.clone()).and_then(move |_| {
-                self.out.send(&b"welcome!\r\n"[..]);
-                let active = Active::new(self.world, self.out, nick);
-                Ok(Client::Active(active))
-            }).map_err(|_| irc::Error::Other("register error"));
+    let (client, out) = run_pending(&mut core, world.clone(), &[
+        "NICK alice",
+        "USER alice 0 * :Alice",
+    ]);
 
-            irc::Op::boxed(op)
+    assert!(is_active(&client));
+    assert!(out.starts_with(":ircd-oxide 001 alice :Welcome"));
+    assert!(out.contains(" 002 alice "));
+    assert!(out.contains(" 003 alice "));
+    assert!(out.contains(" 004 alice ircd-oxide "));
 
+    // the user has an identity of their own, which holds the nick
+    let user = world.nick_owner("alice").unwrap();
+    assert!(user != "alice");
+    assert_eq!(world.nick(&user), Some("alice".to_string()));
+}
+
+#[test]
+fn register_waits_for_cap_end() {
+    use common::Sid;
+
+    let mut core = Core::new().unwrap();
+    let world = World::new(&core.handle(), Sid::identity());
+
+    let (client, out) = run_pending(&mut core, world.clone(), &[
+        "CAP LS 302",
+        "NICK alice",
+        "USER alice 0 * :Alice",
+    ]);
+
+    assert!(!is_active(&client));
+    assert!(out.starts_with(":ircd-oxide CAP * LS "));
+    assert!(!out.contains(" 001 "));
+
+    let (client, _) = run_pending(&mut core, world.clone(), &[
+        "CAP LS",
+        "NICK bob",
+        "USER bob 0 * :Bob",
+        "CAP END",
+    ]);
+
+    assert!(is_active(&client));
+}
+
+#[test]
+fn register_into_full_pool() {
+    use common::Sid;
+    use irc::testing::TestClient;
+
+    let mut core = Core::new().unwrap();
+    let mut world = World::new(&core.handle(), Sid::identity());
+    let pool = Pool::with_limit(1);
+    pool.bind(&core.handle(), &mut world);
+
+    let mut alice = TestClient::with_pool(world.clone(), pool.clone());
+    alice.feed_lines(&mut core, &["NICK alice", "USER alice 0 * :Alice"]).unwrap();
+    assert!(alice.is_active());
+
+    let mut bob = TestClient::with_pool(world.clone(), pool.clone());
+    assert!(bob.feed_lines(&mut core, &["NICK bob", "USER bob 0 * :Bob"]).is_err());
+    assert_eq!(bob.take_output(), "ERROR :Too many connections\r\n");
+
+    // bob was turned away before taking up a nick or appearing in the world
+    assert_eq!(world.nick_owner("bob"), None);
+    assert_eq!(world.stats().users, 1);
+}
+
+#[test]
+fn register_nick_taken() {
+    use common::Sid;
+
+    let mut core = Core::new().unwrap();
+    let world = World::new(&core.handle(), Sid::identity());
+
+    let (_, _) = run_pending(&mut core, world.clone(), &[
+        "NICK alice",
+        "USER alice 0 * :Alice",
+    ]);
+
+    let (client, out) = run_pending(&mut core, world.clone(), &[
+        "NICK alice",
+        "USER alice 0 * :Alice 2",
+    ]);
+
+    assert!(!is_active(&client));
+    assert_eq!(out, ":ircd-oxide 433 * alice :Nickname is already in use\r\n");
+}
+
+#[test]
+fn register_erroneous_nick() {
+    use common::Sid;
+
+    let mut core = Core::new().unwrap();
+    let world = World::new(&core.handle(), Sid::identity());
+
+    let (client, out) = run_pending(&mut core, world.clone(), &[
+        "NICK :a b",
+        "NICK :: b",
+        "NICK #oxide",
+        "NICK a,b",
+        "NICK 1alice",
+        "NICK al!ce",
+        "USER alice 0 * :Alice",
+    ]);
+
+    assert!(!is_active(&client));
+    assert_eq!(out, concat!(
+        ":ircd-oxide 432 * a :Erroneous nickname\r\n",
+        ":ircd-oxide 432 * * :Erroneous nickname\r\n",
+        ":ircd-oxide 432 * #oxide :Erroneous nickname\r\n",
+        ":ircd-oxide 432 * a,b :Erroneous nickname\r\n",
+        ":ircd-oxide 432 * 1alice :Erroneous nickname\r\n",
+        ":ircd-oxide 432 * al!ce :Erroneous nickname\r\n",
+    ));
+
+    let (client, _) = run_pending(&mut core, world.clone(), &[
+        "NICK Al-ice[`^]{}\\9",
+        "USER alice 0 * :Alice",
+    ]);
+    assert!(is_active(&client));
+}
+
+#[test]
+fn ping_before_registering() {
+    use common::Sid;
+
+    let mut core = Core::new().unwrap();
+    let world = World::new(&core.handle(), Sid::identity());
+
+    let (client, out) = run_pending(&mut core, world, &["PING :abc", "PING"]);
+
+    assert!(!is_active(&client));
+    assert_eq!(out, concat!(
+        ":ircd-oxide PONG ircd-oxide abc\r\n",
+        ":ircd-oxide 461 * PING :Not enough parameters\r\n",
+    ));
+}
+
+#[test]
+fn unregistered_command() {
+    use common::Sid;
+
+    let mut core = Core::new().unwrap();
+    let world = World::new(&core.handle(), Sid::identity());
+
+    let (client, out) = run_pending(&mut core, world, &["JOIN #oxide"]);
+
+    assert!(!is_active(&client));
+    assert_eq!(out, ":ircd-oxide 451 * :You have not registered\r\n");
+}
+
+#[cfg(test)]
+fn test_authenticator() -> Authenticator {
+    use std::rc::Rc;
+    use irc::sasl::Credentials;
+
+    Rc::new(|creds: &Credentials| {
+        if creds.authcid == "alice" && creds.passwd == "secret" {
+            Some(creds.authcid.clone())
         } else {
-            irc::Op::ok(Client::Pending(self))
+            None
         }
-    }
+    })
+}
+
+#[test]
+fn sasl_plain_success() {
+    use common::Sid;
+
+    let mut core = Core::new().unwrap();
+    let world = World::new(&core.handle(), Sid::identity());
+
+    let (client, out) = run_pending_with(&mut core, world, Some(test_authenticator()), &[
+        "CAP LS 302",
+        "NICK alice",
+        "USER alice 0 * :Alice",
+        "CAP REQ :sasl",
+        "AUTHENTICATE PLAIN",
+        "AUTHENTICATE AGFsaWNlAHNlY3JldA==",
+        "CAP END",
+    ]);
+
+    assert!(is_active(&client));
+    assert!(out.starts_with(":ircd-oxide CAP * LS :sasl=PLAIN cap-notify\r\n"));
+    assert!(out.contains("\r\nAUTHENTICATE +\r\n"));
+    assert!(out.contains(":ircd-oxide 900 alice alice alice :You are now logged in as alice\r\n"));
+    assert!(out.contains(":ircd-oxide 903 alice :SASL authentication successful\r\n"));
+    assert!(out.contains(" 001 alice "));
+}
+
+#[test]
+fn sasl_plain_bad_password() {
+    use common::Sid;
+
+    let mut core = Core::new().unwrap();
+    let world = World::new(&core.handle(), Sid::identity());
+
+    let (client, out) = run_pending_with(&mut core, world, Some(test_authenticator()), &[
+        "CAP REQ :sasl",
+        "NICK alice",
+        "USER alice 0 * :Alice",
+        "AUTHENTICATE PLAIN",
+        "AUTHENTICATE YWxpY2UAYWxpY2UAd3Jvbmc=",
+    ]);
+
+    assert!(!is_active(&client));
+    assert!(out.ends_with(":ircd-oxide 904 alice :SASL authentication failed\r\n"));
+    assert!(!out.contains(" 900 "));
+}
+
+#[test]
+fn sasl_not_offered_without_authenticator() {
+    use common::Sid;
+
+    let mut core = Core::new().unwrap();
+    let world = World::new(&core.handle(), Sid::identity());
+
+    let (client, out) = run_pending(&mut core, world, &[
+        "CAP LS 302",
+        "CAP REQ :sasl",
+        "NICK alice",
+        "AUTHENTICATE PLAIN",
+    ]);
+
+    assert!(!is_active(&client));
+    assert_eq!(out, concat!(
+        ":ircd-oxide CAP * LS cap-notify\r\n",
+        ":ircd-oxide CAP * NAK sasl\r\n",
+        ":ircd-oxide 904 alice :SASL authentication failed\r\n",
+    ));
+}
+
+#[test]
+fn sasl_unknown_mechanism() {
+    use common::Sid;
+
+    let mut core = Core::new().unwrap();
+    let world = World::new(&core.handle(), Sid::identity());
+
+    let (client, out) = run_pending_with(&mut core, world, Some(test_authenticator()), &[
+        "CAP REQ :sasl",
+        "NICK alice",
+        "AUTHENTICATE EXTERNAL",
+    ]);
+
+    assert!(!is_active(&client));
+    assert!(out.ends_with(concat!(
+        ":ircd-oxide 908 alice PLAIN :are available SASL mechanisms\r\n",
+        ":ircd-oxide 904 alice :SASL authentication failed\r\n",
+    )));
+}
+
+#[test]
+fn sasl_aborted() {
+    use common::Sid;
+
+    let mut core = Core::new().unwrap();
+    let world = World::new(&core.handle(), Sid::identity());
+
+    // registration waits for the exchange, and goes ahead once it is aborted
+    let (client, out) = run_pending_with(&mut core, world, Some(test_authenticator()), &[
+        "CAP REQ :sasl",
+        "CAP END",
+        "NICK alice",
+        "AUTHENTICATE PLAIN",
+        "USER alice 0 * :Alice",
+        "AUTHENTICATE *",
+    ]);
+
+    assert!(is_active(&client));
+    let aborted = out.find(" 906 alice :SASL authentication aborted\r\n").unwrap();
+    assert!(aborted < out.find(" 001 alice ").unwrap());
 }
@@ -3,67 +3,127 @@ use std::collections::HashMap;
 use std::collections::HashSet;
 use std::rc::Rc;
 
+use futures::Future;
 use futures::Stream;
+use futures::future;
 
 use tokio_core::reactor::Handle;
 
+use common::casemap::irc_lower;
+use irc;
+use irc::driver::Disconnect;
 use irc::send::Sender;
 use world::World;
 use world::WorldEvent;
 
 struct PoolInner {
     users: HashMap<String, Sender>,
+    // keyed by the casefolded channel name, since events carry the channel's display name
     chans: HashMap<String, HashSet<String>>,
+    limit: Option<usize>,
+    world: Option<World>,
 }
 
 impl PoolInner {
-    fn new() -> PoolInner {
+    fn new(limit: Option<usize>) -> PoolInner {
         PoolInner {
             users: HashMap::new(),
             chans: HashMap::new(),
+            limit: limit,
+            world: None,
         }
     }
 
+    /// Returns the nickname to show for a user, falling back to their identity if the pool
+    /// hasn't been bound to a world or they have no nickname.
+    fn display(&self, user: &str) -> String {
+        let nick = self.world.as_ref().and_then(|world| world.nick(user));
+        nick.unwrap_or_else(|| user.to_string())
+    }
+
+    /// Forgets users whose connections have finished.
+    fn prune(&mut self) {
+        self.users.retain(|_, out| !out.is_finished());
+    }
+
+    fn is_full(&mut self) -> bool {
+        self.prune();
+        self.limit.map(|limit| self.users.len() >= limit).unwrap_or(false)
+    }
+
+    /// Refuses `out` if there is no room for it, unless `name` is already in the pool.
+    fn check_room(&mut self, name: Option<&str>, out: &mut Sender) -> irc::Result<()> {
+        let known = name.map(|name| self.users.contains_key(name)).unwrap_or(false);
+        if self.is_full() && !known {
+            info!("refusing {}: too many connections", name.unwrap_or("connection"));
+            let _ = out.send(b"ERROR :Too many connections\r\n");
+            out.close_soft();
+            return Err(irc::Error::Other("too many connections"));
+        }
+        Ok(())
+    }
+
     fn dispatch(&mut self, event: &WorldEvent) {
         info!("event: {:?}", event);
 
         match *event {
+            WorldEvent::UserAdded(_) | WorldEvent::ChanAdded(_) => { },
+            WorldEvent::NickChange(_, _) | WorldEvent::AwayChange(_, _) => { },
+            WorldEvent::ModeChange(_, _, _) => { },
+
             WorldEvent::UserJoin(ref chan, ref user) => {
                 self.chans
-                    .entry(chan.clone())
+                    .entry(irc_lower(chan))
                     .or_insert_with(|| HashSet::new())
                     .insert(user.clone());
-                self.send_to_chan(chan, None,
-                    format!(":{} JOIN {}", user, chan));
+                let line = format!(":{} JOIN {}", self.display(user), chan);
+                self.send_to_chan(chan, None, line);
             },
 
+            // users that quit have already left every channel, and their peers were told so
             WorldEvent::UserPart(ref chan, ref user) => {
-                self.send_to_chan(chan, None,
-                    format!(":{} PART {}", user, chan));
-                self.chans.get_mut(chan).map(|c| c.remove(user));
+                let key = irc_lower(chan);
+                if self.chans.get(&key).map(|c| c.contains(user)).unwrap_or(false) {
+                    let line = format!(":{} PART {}", self.display(user), chan);
+                    self.send_to_chan(chan, None, line);
+                    self.chans.get_mut(&key).map(|c| c.remove(user));
+                }
             },
 
-            WorldEvent::Message(ref chan, ref user, ref message) => {
-                self.send_to_chan(chan, Some(user),
-                    format!(":{} PRIVMSG {} :{}", user, chan, message));
+            // the world doesn't record who set a topic, so it comes from the server
+            WorldEvent::TopicChange(ref chan, ref topic) => {
+                let server = String::from_utf8_lossy(irc::SERVER_NAME);
+                self.send_to_chan(chan, None,
+                    format!(":{} TOPIC {} :{}", server, chan, topic));
             },
+
+            // messages are delivered to local members by the sender's connection. members on
+            // other servers don't get them, since no server link forwards the event yet
+            WorldEvent::Message(_, _, _) => { },
         }
     }
 
-    fn send_to_chan(&mut self, chan: &String, omit: Option<&String>, line: String) {
-        let users = match self.chans.get(chan) {
-            Some(users) => users,
+    fn send_to_chan(&mut self, chan: &str, omit: Option<&String>, line: String) {
+        let users: Vec<String> = match self.chans.get(&irc_lower(chan)) {
+            Some(users) => users.iter().filter(|&u| Some(u) != omit).cloned().collect(),
             None => return,
         };
 
-        for user in users.iter() {
-            if Some(user) == omit {
-                continue;
-            }
+        let mut line = line.into_bytes();
+        line.extend_from_slice(b"\r\n");
+
+        self.send_to(users.iter().map(|u| &u[..]), &line);
+    }
 
-            if let Some(mut out) = self.users.get_mut(user) {
-                out.send(line.as_bytes());
-                out.send(b"\r\n");
+    fn send_to<'a, I>(&mut self, users: I, data: &[u8]) where I: Iterator<Item=&'a str> {
+        for user in users {
+            if let Some(out) = self.users.get_mut(user) {
+                if let Err(e) = out.send(data) {
+                    // a client that can't keep up is disconnected rather than sent a garbled
+                    // stream of messages
+                    info!("dropping {}: {}", user, e);
+                    out.close_hard();
+                }
             }
         }
     }
@@ -76,11 +136,17 @@ pub struct Pool {
 
 impl Pool {
     pub fn new() -> Pool {
-        Pool { inner: Rc::new(RefCell::new(PoolInner::new())) }
+        Pool { inner: Rc::new(RefCell::new(PoolInner::new(None))) }
+    }
+
+    /// Creates a pool that holds at most `limit` connections at a time.
+    pub fn with_limit(limit: usize) -> Pool {
+        Pool { inner: Rc::new(RefCell::new(PoolInner::new(Some(limit)))) }
     }
 
     pub fn bind(&self, handle: &Handle, world: &mut World) {
         let inner = self.inner.clone();
+        inner.borrow_mut().world = Some(world.clone());
 
         handle.spawn(world.events().for_each(move |event| {
             inner.borrow_mut().dispatch(&*event);
@@ -88,7 +154,247 @@ impl Pool {
         }));
     }
 
-    pub fn add_user(&mut self, name: String, out: Sender) {
-        self.inner.borrow_mut().users.insert(name, out);
+    /// Adds a connection to the pool. If the pool is already full, the connection is sent an
+    /// `ERROR` and closed once that has been written, and an error is returned.
+    pub fn add_user(&mut self, name: String, mut out: Sender) -> irc::Result<()> {
+        let mut inner = self.inner.borrow_mut();
+        try!(inner.check_room(Some(&name), &mut out));
+        inner.users.insert(name, out);
+        Ok(())
+    }
+
+    /// Checks that there is room in the pool for another connection, refusing `out` as
+    /// `add_user` would if there isn't. This lets a connection be turned away before anything is
+    /// done on its behalf.
+    pub fn check_room(&self, out: &mut Sender) -> irc::Result<()> {
+        self.inner.borrow_mut().check_room(None, out)
     }
+
+    /// Returns whether the pool already holds as many connections as it is allowed.
+    pub fn is_full(&self) -> bool {
+        self.inner.borrow_mut().is_full()
+    }
+
+    /// Returns the number of connections currently in the pool.
+    pub fn user_count(&self) -> usize {
+        let mut inner = self.inner.borrow_mut();
+        inner.prune();
+        inner.users.len()
+    }
+
+    /// Tells every user connected to this pool that the server is shutting down and closes
+    /// their connections once their pending output has been written. The returned future
+    /// resolves when all of the connections have finished.
+    pub fn shutdown(&mut self) -> Box<Future<Item=(), Error=()>> {
+        let mut inner = self.inner.borrow_mut();
+        let mut finished = Vec::new();
+
+        for (_, mut out) in inner.users.drain() {
+            let _ = out.send(b"ERROR :Server shutting down\r\n");
+            out.close_soft();
+            finished.push(out.finished());
+        }
+
+        Box::new(future::join_all(finished).map(|_| ()))
+    }
+
+    /// Forgets a user whose connection has ended, sending a `QUIT` with the reason to every user
+    /// that shared a channel with it.
+    pub fn quit(&self, user: &str, reason: &Disconnect) {
+        let mut inner = self.inner.borrow_mut();
+        inner.users.remove(user);
+
+        let mut peers = HashSet::new();
+        for members in inner.chans.values_mut() {
+            if members.remove(user) {
+                peers.extend(members.iter().cloned());
+            }
+        }
+
+        let line = format!(":{} QUIT :{}\r\n", inner.display(user), reason);
+        inner.send_to(peers.iter().map(|u| &u[..]), line.as_bytes());
+    }
+
+    /// Sends the data to each of the named users that is connected to this pool.
+    pub fn send_to(&self, users: &[&str], data: &[u8]) {
+        self.inner.borrow_mut().send_to(users.iter().cloned(), data);
+    }
+
+    /// Sends the data to every user connected to this pool except the named one.
+    pub fn send_except(&self, user: &str, data: &[u8]) {
+        let mut inner = self.inner.borrow_mut();
+        let users: Vec<String> = inner.users.keys().filter(|u| *u != user).cloned().collect();
+        inner.send_to(users.iter().map(|u| &u[..]), data);
+    }
+}
+
+#[test]
+fn targeted_delivery() {
+    use futures::Async;
+    use futures::Future;
+    use futures::future;
+    use irc::driver::test_io::Output;
+    use irc::send::SendDriver;
+    use tokio_core::reactor::Core;
+
+    let mut core = Core::new().unwrap();
+    let mut pool = Pool::new();
+
+    let mut drivers = Vec::new();
+    let mut outputs = Vec::new();
+    for name in ["alice", "bob", "carol"].iter() {
+        let output = Output::new();
+        let mut driver = SendDriver::new(output.clone());
+        pool.add_user(name.to_string(), driver.sender()).unwrap();
+        drivers.push(driver);
+        outputs.push(output);
+    }
+
+    pool.send_to(&["alice", "carol", "dave"], b"one\r\n");
+    pool.send_except("alice", b"two\r\n");
+
+    core.run(future::poll_fn(|| {
+        for driver in drivers.iter_mut() {
+            let _ = driver.poll();
+        }
+        Ok::<_, ()>(Async::Ready(()))
+    })).unwrap();
+
+    let received: Vec<Vec<u8>> = outputs.iter().map(|o| o.0.borrow().clone()).collect();
+    assert_eq!(received[0], b"one\r\n".to_vec());
+    assert_eq!(received[1], b"two\r\n".to_vec());
+    assert_eq!(received[2], b"one\r\ntwo\r\n".to_vec());
+}
+
+#[test]
+fn shutdown_drains_output() {
+    use irc::driver::test_io::Output;
+    use irc::send::SendDriver;
+    use tokio_core::reactor::Core;
+
+    let mut core = Core::new().unwrap();
+    let mut pool = Pool::new();
+
+    let mut outputs = Vec::new();
+    for name in ["alice", "bob"].iter() {
+        let output = Output::new();
+        let mut driver = SendDriver::new(output.clone());
+        pool.add_user(name.to_string(), driver.sender()).unwrap();
+        core.handle().spawn(driver.map_err(|_| ()));
+        outputs.push(output);
+    }
+
+    pool.send_to(&["alice", "bob"], b"queued\r\n");
+    core.run(pool.shutdown()).unwrap();
+
+    for output in outputs.iter() {
+        assert_eq!(&output.0.borrow()[..], &b"queued\r\nERROR :Server shutting down\r\n"[..]);
+    }
+}
+
+#[test]
+fn connection_limit() {
+    use futures::future;
+    use irc::driver::test_io::Output;
+    use irc::send::SendDriver;
+    use tokio_core::reactor::Core;
+
+    let mut core = Core::new().unwrap();
+    let mut pool = Pool::with_limit(2);
+
+    let mut alice = SendDriver::new(Output::new());
+    let mut bob = SendDriver::new(Output::new());
+    assert!(pool.add_user("alice".to_string(), alice.sender()).is_ok());
+    assert!(pool.add_user("bob".to_string(), bob.sender()).is_ok());
+    assert_eq!(pool.user_count(), 2);
+
+    let output = Output::new();
+    let mut carol = SendDriver::new(output.clone());
+    assert!(pool.add_user("carol".to_string(), carol.sender()).is_err());
+    core.run(future::poll_fn(|| carol.poll())).unwrap();
+    assert_eq!(&output.0.borrow()[..], &b"ERROR :Too many connections\r\n"[..]);
+
+    // bob's connection is forgotten once its driver finishes
+    drop(bob);
+    assert_eq!(pool.user_count(), 1);
+
+    let mut carol = SendDriver::new(Output::new());
+    assert!(pool.add_user("carol".to_string(), carol.sender()).is_ok());
+    assert_eq!(pool.user_count(), 2);
+}
+
+#[test]
+fn quit_reaches_shared_channels() {
+    use futures::Async;
+    use futures::future;
+    use irc::driver::test_io::Output;
+    use irc::send::SendDriver;
+    use tokio_core::reactor::Core;
+
+    let mut core = Core::new().unwrap();
+    let mut pool = Pool::new();
+
+    let mut drivers = Vec::new();
+    let mut outputs = Vec::new();
+    for name in ["alice", "bob", "carol", "dave"].iter() {
+        let output = Output::new();
+        let mut driver = SendDriver::new(output.clone());
+        pool.add_user(name.to_string(), driver.sender()).unwrap();
+        drivers.push(driver);
+        outputs.push(output);
+    }
+
+    for &(chan, user) in [("#a", "alice"), ("#a", "bob"), ("#b", "alice"), ("#b", "carol"),
+                          ("#b", "bob")].iter() {
+        let join = WorldEvent::UserJoin(chan.to_string(), user.to_string());
+        pool.inner.borrow_mut().dispatch(&join);
+    }
+
+    pool.quit("alice", &Disconnect::Quit(Some("bye".to_string())));
+    pool.send_to(&["alice"], b"too late\r\n");
+
+    core.run(future::poll_fn(|| {
+        for driver in drivers.iter_mut() {
+            let _ = driver.poll();
+        }
+        Ok::<_, ()>(Async::Ready(()))
+    })).unwrap();
+
+    let received: Vec<Vec<u8>> = outputs.iter().map(|o| o.0.borrow().clone()).collect();
+    let quit = b":alice QUIT :Quit: bye\r\n";
+    assert!(received[0].ends_with(b":bob JOIN #b\r\n"));
+    assert!(received[1].ends_with(quit));
+    assert!(received[2].ends_with(quit));
+    assert_eq!(received[3], b"".to_vec());
+    assert!(pool.inner.borrow().chans.values().all(|c| !c.contains("alice")));
+}
+
+#[test]
+fn topic_change_has_source() {
+    use futures::Async;
+    use futures::future;
+    use irc::driver::test_io::Output;
+    use irc::send::SendDriver;
+    use tokio_core::reactor::Core;
+
+    let mut core = Core::new().unwrap();
+    let mut pool = Pool::new();
+
+    let output = Output::new();
+    let mut driver = SendDriver::new(output.clone());
+    pool.add_user("alice".to_string(), driver.sender()).unwrap();
+
+    {
+        let mut inner = pool.inner.borrow_mut();
+        inner.dispatch(&WorldEvent::UserJoin("#a".to_string(), "alice".to_string()));
+        inner.dispatch(&WorldEvent::TopicChange("#a".to_string(), "hello".to_string()));
+    }
+
+    core.run(future::poll_fn(|| {
+        let _ = driver.poll();
+        Ok::<_, ()>(Async::Ready(()))
+    })).unwrap();
+
+    let expected = b":alice JOIN #a\r\n:ircd-oxide TOPIC #a :hello\r\n";
+    assert_eq!(&output.0.borrow()[..], &expected[..]);
 }
@@ -0,0 +1,251 @@
+//! SASL authentication during registration
+//!
+//! Clients that enable the `sasl` capability can log in to an account with `AUTHENTICATE`
+//! before registration completes. Only the `PLAIN` mechanism is supported: the client names
+//! the mechanism, the server replies with `AUTHENTICATE +`, and the client sends the base64
+//! encoding of `authzid\0authcid\0passwd`, split into 400 byte chunks if needed. Checking the
+//! credentials is left to an `Authenticator` supplied by whoever creates the connection.
+
+use std::rc::Rc;
+
+/// The longest chunk of a client response. A chunk of exactly this length means more follow.
+pub const CHUNK_LEN: usize = 400;
+
+/// The longest response a client can send in total, after base64 encoding
+pub const MAX_RESPONSE_LEN: usize = 4 * CHUNK_LEN;
+
+/// The mechanisms a client can use, as advertised with the `sasl` capability
+pub const MECHANISMS: &[&str] = &["PLAIN"];
+
+/// The credentials sent by a client using `PLAIN`
+#[derive(Debug, PartialEq)]
+pub struct Credentials {
+    /// The identity to act as, which is often empty to mean the same as `authcid`
+    pub authzid: String,
+    /// The identity whose password is given
+    pub authcid: String,
+    pub passwd: String,
+}
+
+/// Checks a set of credentials, returning the name of the account to log in to if they are
+/// valid.
+pub type Authenticator = Rc<Fn(&Credentials) -> Option<String>>;
+
+/// What the connection should do in response to an `AUTHENTICATE` message
+#[derive(Debug, PartialEq)]
+pub enum Step {
+    /// Send `AUTHENTICATE +` to ask for the client's response
+    Challenge,
+    /// Wait for the rest of a response that was split into chunks
+    Continue,
+    /// The client is logged in to the named account
+    Success(String),
+    /// The client named a mechanism other than those in `MECHANISMS`
+    UnknownMechanism,
+    /// The credentials were bad, or there is nothing to check them with
+    Fail,
+    /// The response was longer than `MAX_RESPONSE_LEN`
+    TooLong,
+    /// The client gave up with `AUTHENTICATE *`
+    Aborted,
+    /// The client is already logged in
+    Already,
+}
+
+#[derive(PartialEq)]
+enum State {
+    Idle,
+    Plain,
+    Done,
+}
+
+/// The state of a client's SASL exchange
+pub struct SaslSession {
+    check: Option<Authenticator>,
+    state: State,
+    response: Vec<u8>,
+}
+
+impl SaslSession {
+    /// Creates a session that checks credentials with `check`. Without an `Authenticator`,
+    /// every attempt fails.
+    pub fn new(check: Option<Authenticator>) -> SaslSession {
+        SaslSession { check: check, state: State::Idle, response: Vec::new() }
+    }
+
+    /// Indicates whether an exchange has been started and not yet finished.
+    pub fn in_progress(&self) -> bool {
+        self.state == State::Plain
+    }
+
+    /// Handles the argument to an `AUTHENTICATE` message.
+    pub fn authenticate(&mut self, arg: &[u8]) -> Step {
+        if self.state == State::Done {
+            return Step::Already;
+        }
+
+        if arg == b"*" {
+            self.reset();
+            return Step::Aborted;
+        }
+
+        if self.state == State::Idle {
+            if !arg.eq_ignore_ascii_case(b"PLAIN") {
+                return Step::UnknownMechanism;
+            }
+            if self.check.is_none() {
+                return Step::Fail;
+            }
+            self.state = State::Plain;
+            return Step::Challenge;
+        }
+
+        if arg != b"+" {
+            if self.response.len() + arg.len() > MAX_RESPONSE_LEN {
+                self.reset();
+                return Step::TooLong;
+            }
+            self.response.extend_from_slice(arg);
+            if arg.len() == CHUNK_LEN {
+                return Step::Continue;
+            }
+        }
+
+        let account = decode_base64(&self.response)
+            .and_then(|plain| parse_plain(&plain))
+            .and_then(|creds| self.check.as_ref().and_then(|check| check(&creds)));
+
+        match account {
+            Some(account) => {
+                self.state = State::Done;
+                self.response.clear();
+                Step::Success(account)
+            },
+            None => {
+                self.reset();
+                Step::Fail
+            },
+        }
+    }
+
+    fn reset(&mut self) {
+        self.state = State::Idle;
+        self.response.clear();
+    }
+}
+
+/// Splits a decoded `PLAIN` response into its three fields.
+fn parse_plain(data: &[u8]) -> Option<Credentials> {
+    let fields: Vec<&[u8]> = data.split(|b| *b == 0).collect();
+    if fields.len() != 3 {
+        return None;
+    }
+
+    let field = |i: usize| String::from_utf8(fields[i].to_vec()).ok();
+
+    match (field(0), field(1), field(2)) {
+        (Some(authzid), Some(authcid), Some(passwd)) => Some(Credentials {
+            authzid: authzid,
+            authcid: authcid,
+            passwd: passwd,
+        }),
+        _ => None,
+    }
+}
+
+/// Decodes standard, padded base64, returning `None` if the input is malformed.
+fn decode_base64(data: &[u8]) -> Option<Vec<u8>> {
+    fn value(c: u8) -> Option<u32> {
+        match c {
+            b'A'..=b'Z' => Some((c - b'A') as u32),
+            b'a'..=b'z' => Some((c - b'a') as u32 + 26),
+            b'0'..=b'9' => Some((c - b'0') as u32 + 52),
+            b'+' => Some(62),
+            b'/' => Some(63),
+            _ => None,
+        }
+    }
+
+    let mut out = Vec::with_capacity(data.len() / 4 * 3);
+
+    for (i, quad) in data.chunks(4).enumerate() {
+        if quad.len() != 4 {
+            return None;
+        }
+
+        let last = (i + 1) * 4 == data.len();
+        let padding = quad.iter().rev().take_while(|c| **c == b'=').count();
+        if padding > 2 || (padding > 0 && !last) {
+            return None;
+        }
+
+        let mut n = 0;
+        for c in quad[..4 - padding].iter() {
+            match value(*c) {
+                Some(v) => n = (n << 6) | v,
+                None => return None,
+            }
+        }
+        n <<= 6 * padding as u32;
+
+        let bytes = [(n >> 16) as u8, (n >> 8) as u8, n as u8];
+        out.extend_from_slice(&bytes[..3 - padding]);
+    }
+
+    Some(out)
+}
+
+#[test]
+fn test_decode_base64() {
+    assert_eq!(decode_base64(b""), Some(Vec::new()));
+    assert_eq!(decode_base64(b"Zg=="), Some(b"f".to_vec()));
+    assert_eq!(decode_base64(b"Zm8="), Some(b"fo".to_vec()));
+    assert_eq!(decode_base64(b"Zm9v"), Some(b"foo".to_vec()));
+    assert_eq!(decode_base64(b"AGFsaWNlAHNlY3JldA=="), Some(b"\0alice\0secret".to_vec()));
+
+    assert_eq!(decode_base64(b"Zm9"), None);
+    assert_eq!(decode_base64(b"Zg==Zm9v"), None);
+    assert_eq!(decode_base64(b"Z==="), None);
+    assert_eq!(decode_base64(b"Zm9!"), None);
+}
+
+#[test]
+fn test_unknown_mechanism() {
+    let check: Authenticator = Rc::new(|creds: &Credentials| Some(creds.authcid.clone()));
+    let mut session = SaslSession::new(Some(check));
+
+    assert_eq!(session.authenticate(b"EXTERNAL"), Step::UnknownMechanism);
+    assert!(!session.in_progress());
+    assert_eq!(session.authenticate(b"plain"), Step::Challenge);
+}
+
+#[test]
+fn test_plain_chunks() {
+    let check: Authenticator = Rc::new(|creds: &Credentials| {
+        if creds.passwd.len() == 299 { Some(creds.authcid.clone()) } else { None }
+    });
+    let mut session = SaslSession::new(Some(check));
+
+    // a 306 byte response encodes to 408 bytes, which must be split
+    let encoded = {
+        let table = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+        let mut plain = b"\0alice\0".to_vec();
+        plain.extend_from_slice(&[b'x'; 299]);
+        let mut out = Vec::new();
+        for chunk in plain.chunks(3) {
+            let n = (chunk[0] as usize) << 16 | (chunk[1] as usize) << 8 | chunk[2] as usize;
+            for shift in [18, 12, 6, 0].iter() {
+                out.push(table[(n >> shift) & 63]);
+            }
+        }
+        out
+    };
+    assert_eq!(encoded.len(), 408);
+
+    assert_eq!(session.authenticate(b"PLAIN"), Step::Challenge);
+    assert_eq!(session.authenticate(&encoded[..CHUNK_LEN]), Step::Continue);
+    assert!(session.in_progress());
+    assert_eq!(session.authenticate(&encoded[CHUNK_LEN..]), Step::Success("alice".to_string()));
+    assert!(!session.in_progress());
+    assert_eq!(session.authenticate(b"PLAIN"), Step::Already);
+}
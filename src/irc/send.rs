@@ -13,16 +13,25 @@ use futures::Future;
 use futures::Poll;
 use futures::Async;
 use futures::task;
+use futures::unsync::oneshot;
 
 use tokio_io::AsyncWrite;
 
 use common::byte_ring::ByteRing;
 use irc;
 
+/// The size a client's send buffer starts out at
+pub const SEND_INITIAL: usize = 4096;
+
+/// The size a client's send buffer is allowed to grow to before sends start failing
+pub const SEND_LIMIT: usize = 65536;
+
 struct SendInner {
     buf: ByteRing,
     status: SendStatus,
     blocked_send: Option<task::Task>,
+    blocked_on_write: bool,
+    finished: Vec<oneshot::Sender<()>>,
 }
 
 #[derive(Eq, PartialEq)]
@@ -35,37 +44,89 @@ enum SendStatus {
 impl Drop for SendInner {
     fn drop(&mut self) {
         info!("(SendInner) I am forgotten...");
+
+        for tx in self.finished.drain(..) {
+            let _ = tx.send(());
+        }
     }
 }
 
 /// A handle to a byte buffer, which is drained to an `AsyncWrite` in a background task.
 ///
 /// This struct can be cheaply cloned and moved around in a single thread to make it easier to
-/// push bytes to an `AsyncWrite` for asynchronous delivery. Unfortunately there is currently
-/// nothing resembling backpressure, and no way to react to driver-level events such as errors
-/// or termination.
+/// push bytes to an `AsyncWrite` for asynchronous delivery. The buffer grows as needed up to a
+/// limit, past which sends fail instead of queueing more data, and `is_congested` can be used to
+/// notice a slow reader before that happens. `finished` can be used to wait for the driver to
+/// go away, but there is currently no way to learn why it did.
 #[derive(Clone)]
 pub struct Sender {
     inner: Weak<RefCell<SendInner>>,
 }
 
 impl Sender {
-    /// Queues some bytes up to be sent to the associated socket.
-    pub fn send(&mut self, buf: &[u8]) {
+    /// Queues some bytes up to be sent to the associated socket. The bytes are queued either
+    /// entirely or not at all. Fails if the sender has been closed, or if queueing the bytes
+    /// would grow the buffer past its limit.
+    pub fn send(&mut self, buf: &[u8]) -> irc::Result<()> {
         if let Some(r) = self.inner.upgrade() {
             let mut inner = r.borrow_mut();
 
-            if inner.status == SendStatus::Writable {
-                // TODO: don't panic if the buffer is full. either make room or kill the sender
-                inner.buf.put(buf);
-            } else {
-                warn!("silently discarding write of {} bytes", buf.len());
+            if inner.status != SendStatus::Writable {
+                warn!("discarding write of {} bytes to closed sender", buf.len());
+                return Err(irc::Error::Other("sender is closed"));
             }
 
-            // TODO: awake the thread even on discarded writes?
-            inner.blocked_send.take().map(|t| t.unpark());
+            if !inner.buf.reserve(buf.len()) {
+                warn!("discarding write of {} bytes to full sender", buf.len());
+                return Err(irc::Error::Other("send buffer full"));
+            }
+
+            inner.buf.put(buf);
+
+            // if the driver is waiting for the socket, the socket will wake it, and waking it
+            // now would only have it find the socket still not ready
+            if !inner.blocked_on_write {
+                inner.blocked_send.take().map(|t| t.unpark());
+            }
+            Ok(())
         } else {
             warn!("send() on completed Sender");
+            Err(irc::Error::Other("sender is closed"))
+        }
+    }
+
+    /// Returns a future that resolves once the driver for this sender has finished, whether
+    /// because it was closed and drained or because of an error.
+    pub fn finished(&self) -> Finished {
+        match self.inner.upgrade() {
+            Some(r) => {
+                let (tx, rx) = oneshot::channel();
+                r.borrow_mut().finished.push(tx);
+                Finished { rx: Some(rx) }
+            },
+            None => Finished { rx: None },
+        }
+    }
+
+    /// Indicates whether the driver for this sender has finished and dropped its buffer.
+    pub fn is_finished(&self) -> bool {
+        self.inner.upgrade().is_none()
+    }
+
+    /// Returns the number of bytes waiting to be written to the socket.
+    pub fn buffered(&self) -> usize {
+        self.inner.upgrade().map(|r| r.borrow().buf.remaining()).unwrap_or(0)
+    }
+
+    /// Indicates whether the buffer is more than three quarters of the way to its limit, which
+    /// usually means the other end is not keeping up.
+    pub fn is_congested(&self) -> bool {
+        match self.inner.upgrade() {
+            Some(r) => {
+                let inner = r.borrow();
+                inner.buf.remaining() > inner.buf.limit() / 4 * 3
+            },
+            None => false,
         }
     }
 
@@ -96,6 +157,24 @@ impl Sender {
     }
 }
 
+/// A future returned by `Sender::finished`
+pub struct Finished {
+    rx: Option<oneshot::Receiver<()>>,
+}
+
+impl Future for Finished {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        match self.rx.as_mut().map(|rx| rx.poll()) {
+            Some(Ok(Async::NotReady)) => Ok(Async::NotReady),
+            // either the driver finished or the notification was lost along with it
+            _ => Ok(Async::Ready(())),
+        }
+    }
+}
+
 pub struct SendDriver<W> {
     send: W,
     inner: Rc<RefCell<SendInner>>
@@ -103,13 +182,19 @@ pub struct SendDriver<W> {
 
 impl<W: AsyncWrite> SendDriver<W> {
     pub fn new(send: W) -> SendDriver<W> {
-        // TODO: revisit 4096
-        let buf = ByteRing::with_capacity(4096);
+        SendDriver::with_limit(send, SEND_INITIAL, SEND_LIMIT)
+    }
+
+    /// Creates a `SendDriver` whose buffer starts at `initial` bytes and can grow to `limit`.
+    pub fn with_limit(send: W, initial: usize, limit: usize) -> SendDriver<W> {
+        let buf = ByteRing::with_limit(initial, limit);
 
         let inner = SendInner {
             buf: buf,
             status: SendStatus::Writable,
             blocked_send: None,
+            blocked_on_write: false,
+            finished: Vec::new(),
         };
 
         SendDriver {
@@ -134,11 +219,17 @@ impl<W: AsyncWrite> Future for SendDriver<W> {
             return Ok(Async::Ready(()));
         }
 
+        // partial writes advance the buffer, so keep going until either the buffer is empty or
+        // the socket stops accepting bytes
+        inner.blocked_on_write = false;
         while inner.buf.remaining() > 0 {
             match try!(self.send.write_buf(&mut inner.buf)) {
                 Async::Ready(0) => return Err(irc::Error::Other("unexpected EOF on writer")),
-                Async::Ready(_) => (), // do nothing, we can probably write more!
-                Async::NotReady => break
+                Async::Ready(_) => (),
+                Async::NotReady => {
+                    inner.blocked_on_write = true;
+                    break;
+                },
             }
         }
 
@@ -150,3 +241,198 @@ impl<W: AsyncWrite> Future for SendDriver<W> {
         Ok(Async::NotReady)
     }
 }
+
+#[test]
+fn send_grows_then_fails() {
+    use futures::future;
+    use irc::driver::test_io::Output;
+    use tokio_core::reactor::Core;
+
+    let mut core = Core::new().unwrap();
+    let output = Output::new();
+    let mut driver = SendDriver::with_limit(output.clone(), 16, 64);
+    let mut sender = driver.sender();
+
+    let line = [b'x'; 20];
+    assert!(sender.send(&line).is_ok());
+    assert!(sender.send(&line).is_ok());
+    assert!(!sender.is_congested());
+    assert!(sender.send(&line).is_ok());
+    assert!(sender.is_congested());
+    assert!(sender.send(&line).is_err());
+    assert_eq!(sender.buffered(), 60);
+
+    core.run(future::poll_fn(|| {
+        let _ = driver.poll();
+        Ok::<_, ()>(Async::Ready(()))
+    })).unwrap();
+
+    assert_eq!(output.0.borrow().len(), 60);
+    assert_eq!(sender.buffered(), 0);
+    assert!(sender.send(&line).is_ok());
+}
+
+#[test]
+fn send_large_bursts() {
+    use std::io;
+    use futures::future;
+    use irc::driver::test_io::Output;
+    use tokio_core::reactor::Core;
+
+    /// Accepts at most 1000 bytes at a time, and blocks on every other write
+    struct Slow(Output, bool);
+
+    impl io::Write for Slow {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.1 = !self.1;
+            if self.1 {
+                let n = if buf.len() < 1000 { buf.len() } else { 1000 };
+                self.0.write(&buf[..n])
+            } else {
+                Err(io::Error::new(io::ErrorKind::WouldBlock, "slow"))
+            }
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl AsyncWrite for Slow {
+        fn shutdown(&mut self) -> Poll<(), io::Error> {
+            Ok(Async::Ready(()))
+        }
+    }
+
+    let mut core = Core::new().unwrap();
+    let output = Output::new();
+    let mut driver = SendDriver::new(Slow(output.clone(), false));
+    let mut sender = driver.sender();
+
+    let first: Vec<u8> = (0..5000).map(|i| (i % 251) as u8).collect();
+    let second: Vec<u8> = (0..6000).map(|i| (i % 241) as u8).collect();
+
+    let mut poll = |driver: &mut SendDriver<Slow>| core.run(future::poll_fn(|| {
+        let _ = driver.poll();
+        Ok::<_, ()>(Async::Ready(()))
+    })).unwrap();
+
+    assert!(sender.send(&first).is_ok());
+    poll(&mut driver);
+    assert!(sender.send(&second).is_ok());
+
+    while sender.buffered() > 0 {
+        poll(&mut driver);
+    }
+
+    let mut expected = first.clone();
+    expected.extend_from_slice(&second);
+    assert!(*output.0.borrow() == expected);
+}
+
+#[test]
+fn partial_writes_without_wakeups() {
+    use std::cell::Cell;
+    use std::io;
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering;
+    use futures::executor;
+    use futures::executor::Notify;
+    use futures::executor::NotifyHandle;
+    use irc::driver::test_io::Output;
+
+    /// Accepts one byte per call, or none at all while blocked
+    struct Trickle(Output, Rc<Cell<bool>>);
+
+    impl io::Write for Trickle {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.1.get() {
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "blocked"));
+            }
+            self.0.write(&buf[..1])
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl AsyncWrite for Trickle {
+        fn shutdown(&mut self) -> Poll<(), io::Error> {
+            Ok(Async::Ready(()))
+        }
+    }
+
+    struct Count(AtomicUsize);
+
+    impl Notify for Count {
+        fn notify(&self, _id: usize) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    let output = Output::new();
+    let blocked = Rc::new(Cell::new(false));
+    let mut driver = SendDriver::new(Trickle(output.clone(), blocked.clone()));
+    let mut sender = driver.sender();
+    let mut driver = executor::spawn(driver);
+
+    let count = Arc::new(Count(AtomicUsize::new(0)));
+    let notify = NotifyHandle::from(count.clone());
+    let wakeups = || count.0.load(Ordering::SeqCst);
+
+    // a socket that only takes a byte at a time is still drained in one poll
+    assert!(sender.send(b"hello, world").is_ok());
+    assert_eq!(driver.poll_future_notify(&notify, 0).unwrap(), Async::NotReady);
+    assert_eq!(&output.0.borrow()[..], b"hello, world");
+    assert_eq!(wakeups(), 0);
+
+    // while waiting for data, a send wakes the driver
+    assert!(sender.send(b"!").is_ok());
+    assert_eq!(wakeups(), 1);
+
+    // while waiting for the socket, it doesn't
+    blocked.set(true);
+    assert_eq!(driver.poll_future_notify(&notify, 0).unwrap(), Async::NotReady);
+    assert!(sender.send(b" bye").is_ok());
+    assert_eq!(wakeups(), 1);
+
+    // but closing does, since the driver may need to stop
+    sender.close_soft();
+    assert_eq!(wakeups(), 2);
+
+    blocked.set(false);
+    assert_eq!(driver.poll_future_notify(&notify, 0).unwrap(), Async::Ready(()));
+    assert_eq!(&output.0.borrow()[..], b"hello, world! bye");
+}
+
+#[test]
+fn zero_byte_write_is_eof() {
+    use std::io;
+    use futures::future;
+
+    struct Closed;
+
+    impl io::Write for Closed {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            Ok(0)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl AsyncWrite for Closed {
+        fn shutdown(&mut self) -> Poll<(), io::Error> {
+            Ok(Async::Ready(()))
+        }
+    }
+
+    let mut driver = SendDriver::new(Closed);
+    let mut sender = driver.sender();
+
+    assert!(sender.send(b"hello").is_ok());
+    assert!(future::lazy(|| driver.poll()).wait().is_err());
+}
@@ -0,0 +1,128 @@
+//! Helpers for testing client state machines
+//!
+//! `TestClient` feeds messages to a `Client` one at a time, running each resulting `Op` to
+//! completion before moving on, and captures everything the client writes. No sockets or framed
+//! streams are involved. The `World` still needs a reactor to deliver its updates, so a `Core`
+//! is passed in to drive each `Op`. Clients sharing a `World` must share its `Core` too.
+//!
+//! Like `driver::test_io`, which it builds on, this module only exists in test builds.
+
+use futures::Async;
+use futures::Future;
+use futures::future;
+
+use tokio_core::reactor::Core;
+
+use irc;
+use irc::driver::Client;
+use irc::driver::test_io::Output;
+use irc::pending::Pending;
+use irc::pool::Pool;
+use irc::sasl::Authenticator;
+use irc::send::SendDriver;
+use irc::Message;
+use world::World;
+
+/// A client connection driven directly by a test.
+pub struct TestClient {
+    client: Option<Client>,
+    send: SendDriver<Output>,
+    output: Output,
+}
+
+impl TestClient {
+    /// Creates a client that has just connected.
+    pub fn new(world: World) -> TestClient {
+        TestClient::with_pending(|out| Pending::new(world, out))
+    }
+
+    /// Creates a client that has just connected, which can log in with SASL using `auth`.
+    pub fn with_authenticator(world: World, auth: Authenticator) -> TestClient {
+        TestClient::with_pending(|out| Pending::with_authenticator(world, out, auth))
+    }
+
+    /// Creates a client that has just connected, which joins `pool` once it registers.
+    pub fn with_pool(world: World, pool: Pool) -> TestClient {
+        TestClient::with_pending(|out| Pending::with_pool(world, out, pool))
+    }
+
+    fn with_pending<F>(pending: F) -> TestClient where F: FnOnce(irc::send::Sender) -> Pending {
+        let output = Output::new();
+        let mut send = SendDriver::new(output.clone());
+        let client = Client::Pending(pending(send.sender()));
+
+        TestClient { client: Some(client), send: send, output: output }
+    }
+
+    /// Handles a message, running the resulting operation to completion.
+    pub fn feed(&mut self, core: &mut Core, m: Message) -> irc::Result<()> {
+        let op = match self.client.take() {
+            Some(Client::Pending(pending)) => pending.handle(m),
+            Some(Client::Active(active)) => active.handle(m),
+            None => return Err(irc::Error::Other("client failed earlier")),
+        };
+
+        self.client = Some(try!(core.run(op)));
+        Ok(())
+    }
+
+    /// Parses and handles each line in turn, stopping at the first failure.
+    pub fn feed_lines(&mut self, core: &mut Core, lines: &[&str]) -> irc::Result<()> {
+        for line in lines {
+            try!(self.feed(core, try!(Message::parse(*line))));
+        }
+        Ok(())
+    }
+
+    /// Returns everything the client has written since the last call.
+    pub fn take_output(&mut self) -> String {
+        let send = &mut self.send;
+        let _ = future::lazy(|| {
+            let _ = send.poll();
+            Ok::<_, ()>(Async::Ready(()))
+        }).wait();
+
+        let bytes: Vec<u8> = self.output.0.borrow_mut().drain(..).collect();
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+
+    /// Returns the client's current state, unless an earlier message made it fail.
+    pub fn client(&self) -> Option<&Client> {
+        self.client.as_ref()
+    }
+
+    /// Indicates whether the client has finished registering.
+    pub fn is_active(&self) -> bool {
+        match self.client {
+            Some(Client::Active(_)) => true,
+            _ => false,
+        }
+    }
+
+    /// Consumes the `TestClient`, returning the client's current state.
+    pub fn into_client(self) -> Option<Client> {
+        self.client
+    }
+}
+
+#[test]
+fn registration_writes_welcome() {
+    use common::Sid;
+
+    let mut core = Core::new().unwrap();
+    let world = World::new(&core.handle(), Sid::identity());
+    let mut client = TestClient::new(world.clone());
+
+    client.feed_lines(&mut core, &["NICK alice"]).unwrap();
+    assert!(!client.is_active());
+    assert_eq!(client.take_output(), "");
+
+    client.feed_lines(&mut core, &["USER alice 0 * :Alice"]).unwrap();
+    assert!(client.is_active());
+
+    let out = client.take_output();
+    let codes: Vec<&str> = out.lines().filter_map(|l| l.split(' ').nth(1)).collect();
+    assert_eq!(&codes[..4], &["001", "002", "003", "004"]);
+    assert!(out.starts_with(":ircd-oxide 001 alice :Welcome"));
+    assert_eq!(client.take_output(), "");
+}
@@ -0,0 +1,112 @@
+//! Per-connection command rate limiting
+//!
+//! Each connection gets a token bucket. Every command a client sends costs one token, and
+//! tokens are refilled at a fixed rate up to a maximum burst size. When the bucket runs dry, the
+//! driver stops reading from the client until another token is available, so excess commands
+//! are delayed rather than dropped. As with `irc::liveness`, the caller supplies the time.
+
+use std::cmp;
+use std::time::Duration;
+use std::time::Instant;
+
+/// How often a client earns a new command token, in milliseconds
+pub const FLOOD_INTERVAL: u64 = 1000;
+
+/// How many commands a client can send at once before being throttled
+pub const FLOOD_BURST: u32 = 10;
+
+/// A token bucket
+pub struct TokenBucket {
+    interval: Duration,
+    burst: u32,
+    tokens: u32,
+    last: Instant,
+}
+
+impl TokenBucket {
+    /// Creates a full bucket that gains a token every `interval`, holding at most `burst`.
+    pub fn new(now: Instant, interval: Duration, burst: u32) -> TokenBucket {
+        TokenBucket {
+            interval: interval,
+            burst: burst,
+            tokens: burst,
+            last: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        if now <= self.last {
+            return;
+        }
+
+        if self.tokens >= self.burst {
+            self.last = now;
+            return;
+        }
+
+        let elapsed = nanos(now - self.last);
+        let earned = elapsed / cmp::max(nanos(self.interval), 1);
+        let earned = cmp::min(earned, (self.burst - self.tokens) as u64) as u32;
+
+        self.tokens += earned;
+        self.last = if self.tokens >= self.burst {
+            now
+        } else {
+            self.last + self.interval * earned
+        };
+    }
+
+    /// Checks for an available token without taking it. Returns `Err` with the time the next
+    /// token becomes available if the bucket is empty.
+    pub fn check(&mut self, now: Instant) -> Result<(), Instant> {
+        self.refill(now);
+
+        if self.tokens > 0 {
+            Ok(())
+        } else {
+            Err(self.last + self.interval)
+        }
+    }
+
+    /// Takes a token if one is available. Returns `Err` as in `check` otherwise.
+    pub fn take(&mut self, now: Instant) -> Result<(), Instant> {
+        try!(self.check(now));
+        self.tokens -= 1;
+        Ok(())
+    }
+}
+
+fn nanos(d: Duration) -> u64 {
+    d.as_secs() * 1_000_000_000 + d.subsec_nanos() as u64
+}
+
+#[test]
+fn burst_then_throttle() {
+    let start = Instant::now();
+    let second = Duration::from_secs(1);
+    let mut bucket = TokenBucket::new(start, second, 5);
+
+    let accepted = (0..8).filter(|_| bucket.take(start).is_ok()).count();
+    assert_eq!(accepted, 5);
+    assert_eq!(bucket.take(start), Err(start + second));
+
+    assert_eq!(bucket.take(start + second / 2), Err(start + second));
+    assert_eq!(bucket.take(start + second), Ok(()));
+    assert_eq!(bucket.take(start + second), Err(start + second * 2));
+}
+
+#[test]
+fn refill_caps_at_burst() {
+    let start = Instant::now();
+    let second = Duration::from_secs(1);
+    let mut bucket = TokenBucket::new(start, second, 3);
+
+    for _ in 0..3 {
+        bucket.take(start).unwrap();
+    }
+
+    let later = start + second * 60;
+    let accepted = (0..10).filter(|_| bucket.take(later).is_ok()).count();
+    assert_eq!(accepted, 3);
+    assert_eq!(bucket.check(later), Err(later + second));
+}
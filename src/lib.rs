@@ -31,4 +31,5 @@ mod macros;
 pub mod common;
 pub mod crdb;
 pub mod irc;
+pub mod state;
 pub mod world;
@@ -17,6 +17,7 @@ use state::id::Id;
 use state::identity::Identity;
 
 /// An IRC channel.
+#[derive(Clone)]
 pub struct Channel {
     id: Id<Channel>,
 }
@@ -35,6 +36,7 @@ impl Atomic for Channel {
     fn into_atom(self) -> Atom { Atom::Channel(self) }
 }
 
+#[derive(Clone)]
 pub struct ChanUser {
     chan: Id<Channel>,
     user: Id<Identity>,
@@ -9,15 +9,24 @@
 use state::atom::Atom;
 use state::atom::AtomId;
 use state::atom::Atomic;
+use state::channel::Channel;
+use state::claim::Claim;
+use state::identity::Identity;
+use state::world::Channame;
+use state::world::Nickname;
 
 pub struct Changes {
     changes: Vec<Change>
 }
 
 pub enum Change {
-    Add(AtomId),
+    Add(Atom),
     Delete(Atom),
     Update(Atom, AtomId),
+    /// The claim over a nickname, as it stood after the change
+    Nickname(Nickname, Claim<Identity, Nickname>),
+    /// The claim over a channel name, as it stood after the change
+    Channame(Channame, Claim<Channel, Channame>),
 }
 
 impl Changes {
@@ -25,8 +34,16 @@ impl Changes {
         Changes { changes: Vec::new() }
     }
 
-    pub fn added<A: Atomic>(&mut self, added: &A) {
-        self.changes.push(Change::Add(added.atom_id()));
+    pub fn added<A: Atomic + Clone>(&mut self, added: &A) {
+        self.changes.push(Change::Add(added.clone().into_atom()));
+    }
+
+    pub fn claimed_nickname(&mut self, nick: Nickname, claim: Claim<Identity, Nickname>) {
+        self.changes.push(Change::Nickname(nick, claim));
+    }
+
+    pub fn claimed_channame(&mut self, name: Channame, claim: Claim<Channel, Channame>) {
+        self.changes.push(Change::Channame(name, claim));
     }
 
     pub fn finish(self) -> Vec<Change> {
@@ -24,6 +24,10 @@ use time;
 
 use common::Sid;
 
+/// How far, in seconds, a clock from another server may be ahead of our own
+/// before we stop trusting it.
+pub const MAX_SKEW: i64 = 60;
+
 /// A basic clock implementation. Ties on timestamps are resolved by using the
 /// `sid` field.
 #[derive(Copy, Clone, PartialEq, Eq)]
@@ -41,6 +45,50 @@ impl Clock {
         }
     }
 
+    /// Constructs a `Clock` for the given moment in time.
+    pub fn with_time(time: time::Timespec, sid: Sid) -> Clock {
+        Clock {
+            time: time,
+            sid:  sid,
+        }
+    }
+
+    /// Encodes the clock as a sequence of bytes. The encoding is always 15
+    /// bytes long.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut v = Vec::with_capacity(15);
+        for i in (0..8).rev() {
+            v.push((self.time.sec >> (i * 8)) as u8);
+        }
+        for i in (0..4).rev() {
+            v.push((self.time.nsec >> (i * 8)) as u8);
+        }
+        v.extend(Vec::from(self.sid));
+        v
+    }
+
+    /// Decodes a clock encoded with `to_bytes`, returning `None` if the input
+    /// is not a valid encoding.
+    pub fn from_bytes(v: &[u8]) -> Option<Clock> {
+        if v.len() != 15 {
+            return None;
+        }
+
+        let sec = v[0..8].iter().fold(0, |x, b| (x << 8) | (*b as i64));
+        let nsec = v[8..12].iter().fold(0, |x, b| (x << 8) | (*b as i32));
+
+        Sid::try_from(&v[12..15]).map(|sid| Clock {
+            time: time::Timespec { sec: sec, nsec: nsec },
+            sid:  sid,
+        })
+    }
+
+    /// Indicates whether this clock is more than `secs` seconds ahead of
+    /// `other`. Used to spot clocks from servers whose time is badly off.
+    pub fn is_ahead_of(&self, other: &Clock, secs: i64) -> bool {
+        self.time.sec.saturating_sub(other.time.sec) > secs
+    }
+
     /// Constructs a `Clock` that is older than every other clock.
     pub fn neg_infty() -> Clock {
         Clock {
@@ -60,6 +60,18 @@ impl<Namespace> cmp::PartialEq for Id<Namespace> {
 
 impl<Namespace> cmp::Eq for Id<Namespace> { }
 
+impl<Namespace> cmp::PartialOrd for Id<Namespace> {
+    fn partial_cmp(&self, other: &Id<Namespace>) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<Namespace> cmp::Ord for Id<Namespace> {
+    fn cmp(&self, other: &Id<Namespace>) -> cmp::Ordering {
+        self.id.cmp(&other.id)
+    }
+}
+
 impl<Namespace> Hash for Id<Namespace> {
     fn hash<H>(&self, state: &mut H) where H: hash::Hasher {
         self.id.hash(state)
@@ -19,7 +19,6 @@
 //! identity, whether by registration, identification, asynchronous methods,
 //! etc.
 
-use std::collections::HashMap;
 
 use state::atom::Atom;
 use state::atom::AtomId;
@@ -7,12 +7,13 @@
 //! The top level state object
 
 use std::borrow::Borrow;
-use std::collections::HashMap;
+use std::hash::Hash;
+use std::hash::Hasher;
 
 use common::Sid;
-use state::atom::Atomic;
+use common::casemap::irc_lower;
+use state::atom::Atom;
 use state::channel::Channel;
-use state::channel::ChanUser;
 use state::channel::ChanUserSet;
 use state::checkpoint::Changes;
 use state::checkpoint::Change;
@@ -94,22 +95,85 @@ impl World {
     pub fn editor<'w>(&'w mut self) -> WorldGuard<'w> {
         WorldGuard::new(self)
     }
+
+    /// Merges in changes made to another `World`, as returned by `WorldGuard::finish`. Claims
+    /// are merged the same way no matter the order they arrive in, so worlds that have applied
+    /// each other's changes agree on who owns which names.
+    pub fn apply_changes(&mut self, changes: Vec<Change>) {
+        for change in changes {
+            match change {
+                Change::Add(atom) | Change::Update(atom, _) => self.insert_atom(atom),
+
+                // nothing is ever deleted yet
+                Change::Delete(_) => { },
+
+                Change::Nickname(nick, claim) => {
+                    self.nicknames.merge(nick, &claim);
+                },
+
+                Change::Channame(name, claim) => {
+                    self.channames.merge(name, &claim);
+                },
+            }
+        }
+    }
+
+    fn insert_atom(&mut self, atom: Atom) {
+        match atom {
+            Atom::Identity(identity) => {
+                self.identities.insert(identity.id().clone(), identity);
+            },
+            Atom::Channel(channel) => {
+                self.channels.insert(channel.id().clone(), channel);
+            },
+            Atom::ChanUser(cu) => {
+                if self.chanusers.get(cu.channel(), cu.user()).is_none() {
+                    self.chanusers.join(cu.channel().clone(), cu.user().clone());
+                }
+            },
+        }
+    }
+}
+
+/// A name that compares under the `rfc1459` case mapping, but remembers how it was written.
+/// Claims are keyed by the folded form, which is also what it borrows as, so lookups must fold
+/// the name first.
+#[derive(Clone)]
+struct FoldedName {
+    folded: String,
+    display: String,
+}
+
+impl FoldedName {
+    fn new(display: String) -> FoldedName {
+        FoldedName { folded: irc_lower(&display), display: display }
+    }
+}
+
+impl PartialEq for FoldedName {
+    fn eq(&self, other: &FoldedName) -> bool { self.folded == other.folded }
+}
+
+impl Eq for FoldedName { }
+
+impl Hash for FoldedName {
+    fn hash<H: Hasher>(&self, state: &mut H) { self.folded.hash(state) }
 }
 
 /// A nickname
 #[derive(Clone, Hash, PartialEq, Eq)]
-struct Nickname(String);
+pub struct Nickname(FoldedName);
 
 impl Borrow<String> for Nickname {
-    fn borrow(&self) -> &String { &self.0 }
+    fn borrow(&self) -> &String { &self.0.folded }
 }
 
 /// A channel name
 #[derive(Clone, Hash, PartialEq, Eq)]
-struct Channame(String);
+pub struct Channame(FoldedName);
 
 impl Borrow<String> for Channame {
-    fn borrow(&self) -> &String { &self.0 }
+    fn borrow(&self) -> &String { &self.0.folded }
 }
 
 /// A struct for making changes to a World. Changes are tracked
@@ -141,11 +205,18 @@ impl<'w> WorldView for WorldGuard<'w> {
     }
 
     fn nick_claim(&mut self, owner: Id<Identity>, nick: String) -> bool {
-        self.world.nicknames.claim(owner, Nickname(nick))
+        let nick = Nickname(FoldedName::new(nick));
+        if !self.world.nicknames.claim(owner, nick.clone()) {
+            return false;
+        }
+        if let Some(claim) = self.world.nicknames.get(&nick.0.folded) {
+            self.changes.claimed_nickname(nick.clone(), claim.clone());
+        }
+        true
     }
 
     fn nick_use(&mut self, owner: Id<Identity>, nick: String) -> bool {
-        self.world.nicknames.set_active(owner, Nickname(nick))
+        self.world.nicknames.set_active(owner, Nickname(FoldedName::new(nick)))
     }
 
     fn create_channel(&mut self) -> Id<Channel> {
@@ -158,12 +229,19 @@ impl<'w> WorldView for WorldGuard<'w> {
 
     /// Claims a name for a channel. Returns whether the claim was successful.
     fn channel_claim(&mut self, owner: Id<Channel>, name: String) -> bool {
-        self.world.channames.claim(owner, Channame(name))
+        let name = Channame(FoldedName::new(name));
+        if !self.world.channames.claim(owner, name.clone()) {
+            return false;
+        }
+        if let Some(claim) = self.world.channames.get(&name.0.folded) {
+            self.changes.claimed_channame(name.clone(), claim.clone());
+        }
+        true
     }
 
     /// Changes a channel's active name. Returns whether the operation was successful.
     fn channel_use(&mut self, owner: Id<Channel>, name: String) -> bool {
-        self.world.channames.set_active(owner, Channame(name))
+        self.world.channames.set_active(owner, Channame(FoldedName::new(name)))
     }
 
     fn channel_user_add(&mut self, chan: Id<Channel>, user: Id<Identity>) {
@@ -174,18 +252,87 @@ impl<'w> WorldView for WorldGuard<'w> {
     }
 
     fn nickname_owner(&self, nick: &String) -> Option<&Id<Identity>> {
-        self.world.nicknames.owner(nick)
+        self.world.nicknames.owner(&irc_lower(nick))
     }
 
     fn nickname(&self, owner: &Id<Identity>) -> Option<&String> {
-        self.world.nicknames.active(owner).map(|n| &n.0)
+        self.world.nicknames.active(owner).map(|n| &n.0.display)
     }
 
     fn channel_name_owner(&self, name: &String) -> Option<&Id<Channel>> {
-        self.world.channames.owner(name)
+        self.world.channames.owner(&irc_lower(name))
     }
 
     fn channel_name(&self, owner: &Id<Channel>) -> Option<&String> {
-        self.world.channames.active(owner).map(|c| &c.0)
+        self.world.channames.active(owner).map(|c| &c.0.display)
+    }
+}
+
+#[test]
+fn claims_are_casefolded() {
+    let mut world = World::new(Sid::new("001"));
+    let mut editor = world.editor();
+
+    let alice = editor.create_temp_identity();
+    let bob = editor.create_temp_identity();
+
+    assert!(editor.nick_claim(alice.clone(), "Nick".to_string()));
+    assert!(editor.nick_claim(alice.clone(), "nick".to_string()));
+    assert!(!editor.nick_claim(bob.clone(), "NICK".to_string()));
+    assert_eq!(editor.nickname_owner(&"nICK".to_string()), Some(&alice));
+
+    // the name is shown the way it was last used
+    assert!(editor.nick_use(alice.clone(), "NiCk".to_string()));
+    assert_eq!(editor.nickname(&alice), Some(&"NiCk".to_string()));
+    assert!(!editor.nick_use(bob.clone(), "nick".to_string()));
+
+    let foo = editor.create_channel();
+    let other = editor.create_channel();
+    assert!(editor.channel_claim(foo.clone(), "#Foo[]".to_string()));
+    assert!(!editor.channel_claim(other.clone(), "#foo{}".to_string()));
+    assert!(editor.channel_use(foo.clone(), "#Foo[]".to_string()));
+    assert_eq!(editor.channel_name_owner(&"#FOO{}".to_string()), Some(&foo));
+    assert_eq!(editor.channel_name(&foo), Some(&"#Foo[]".to_string()));
+}
+
+#[test]
+fn worlds_converge() {
+    let mut a = World::new(Sid::new("001"));
+    let mut b = World::new(Sid::new("002"));
+
+    let (a_changes, alice, oxide) = {
+        let mut editor = a.editor();
+        let alice = editor.create_temp_identity();
+        let oxide = editor.create_channel();
+        assert!(editor.nick_claim(alice.clone(), "alice".to_string()));
+        assert!(editor.channel_claim(oxide.clone(), "#oxide".to_string()));
+        editor.channel_user_add(oxide.clone(), alice.clone());
+        (editor.finish(), alice, oxide)
+    };
+
+    let (b_changes, bob, rust) = {
+        let mut editor = b.editor();
+        let bob = editor.create_temp_identity();
+        let rust = editor.create_channel();
+        let other = editor.create_channel();
+        assert!(editor.nick_claim(bob.clone(), "bob".to_string()));
+        assert!(editor.channel_claim(rust.clone(), "#rust".to_string()));
+        // a later claim over a name the other world already has
+        assert!(editor.nick_claim(bob.clone(), "Alice".to_string()));
+        assert!(editor.channel_claim(other.clone(), "#OXIDE".to_string()));
+        (editor.finish(), bob, rust)
+    };
+
+    b.apply_changes(a_changes);
+    a.apply_changes(b_changes);
+
+    for world in [&mut a, &mut b].iter_mut() {
+        let editor = world.editor();
+        assert_eq!(editor.nickname_owner(&"ALICE".to_string()), Some(&alice));
+        assert_eq!(editor.nickname_owner(&"bob".to_string()), Some(&bob));
+        assert_eq!(editor.channel_name_owner(&"#oxide".to_string()), Some(&oxide));
+        assert_eq!(editor.channel_name_owner(&"#rust".to_string()), Some(&rust));
     }
+    assert!(a.identities.get(&bob).is_some());
+    assert!(b.chanusers.get(&oxide, &alice).is_some());
 }
@@ -1,20 +1,26 @@
 use std::cell::RefCell;
+use std::collections::BTreeMap;
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::rc::Rc;
 
 use futures::Stream;
 
-use time;
+use rand::random;
 
 use tokio_core::reactor::Handle;
 
+use common::Sid;
+use common::casemap::irc_lower;
 use crdb;
 use common::observe::Completion;
 use common::observe::Observable;
 use common::observe::Observer;
+use state::clock::Clock;
+use state::clock::MAX_SKEW;
 
 struct WorldInner {
+    sid: Sid,
     db: crdb::CRDB, // TODO: move this out of World
 
     u_table: crdb::Table<UserSchema>,
@@ -27,18 +33,29 @@ struct WorldInner {
     users_for_chan: HashMap<String, HashSet<String>>,
     chans_for_user: HashMap<String, HashSet<String>>,
 
+    n_table: crdb::Table<NickSchema>,
+    nicks: HashMap<String, String>,
+
+    unreachable: HashSet<Sid>,
+    // chosen at random when the server starts, so that identities handed out before a restart
+    // are never handed out again
+    epoch: u32,
+    next_user: u64,
+
     events: Observable<WorldEvent>,
 }
 
 impl WorldInner {
-    fn new() -> WorldInner {
+    fn new(sid: Sid) -> WorldInner {
         let mut db = crdb::CRDB::new();
 
         let u_table = db.create_table("u", UserSchema);
         let c_table = db.create_table("c", ChannelSchema);
         let m_table = db.create_table("m", MembershipSchema);
+        let n_table = db.create_table("n", NickSchema);
 
         WorldInner {
+            sid: sid,
             db: db,
 
             u_table: u_table,
@@ -51,56 +68,211 @@ impl WorldInner {
             users_for_chan: HashMap::new(),
             chans_for_user: HashMap::new(),
 
+            n_table: n_table,
+            nicks: HashMap::new(),
+
+            unreachable: HashSet::new(),
+            epoch: random(),
+            next_user: 0,
+
             events: Observable::new(),
         }
     }
 
     fn add_user(&mut self, user: String) -> crdb::Completion {
         let mut tx = self.u_table.open();
-        tx.add(user, UserRecord);
+        tx.add(user, UserRecord::new());
+        self.db.commit(tx)
+    }
+
+    fn set_away(&mut self, user: String, away: Option<String>) -> crdb::Completion {
+        // adding a record would create the user, so unknown users get an empty transaction
+        if self.u_table.get(&user).is_none() {
+            return self.db.commit(self.u_table.open());
+        }
+
+        let mut tx = self.u_table.open();
+        tx.add(user, UserRecord::with_away(self.sid, away));
         self.db.commit(tx)
     }
 
     fn add_chan(&mut self, chan: String) -> crdb::Completion {
         let mut tx = self.c_table.open();
-        tx.add(chan, ChannelRecord);
+        tx.add(irc_lower(&chan), ChannelRecord::named(self.sid, chan));
+        self.db.commit(tx)
+    }
+
+    fn set_topic(&mut self, chan: String, topic: String) -> crdb::Completion {
+        // as with set_away, adding a record would create the channel
+        let key = irc_lower(&chan);
+        if self.c_table.get(&key).is_none() {
+            return self.db.commit(self.c_table.open());
+        }
+
+        let mut tx = self.c_table.open();
+        tx.add(key, ChannelRecord::with_topic(self.sid, topic));
+        self.db.commit(tx)
+    }
+
+    fn set_mode(&mut self, chan: String, mode: char, enabled: bool) -> crdb::Completion {
+        // as with set_away, adding a record would create the channel
+        let key = irc_lower(&chan);
+        if self.c_table.get(&key).is_none() {
+            return self.db.commit(self.c_table.open());
+        }
+
+        let mut tx = self.c_table.open();
+        tx.add(key, ChannelRecord::with_mode(self.sid, mode, enabled));
         self.db.commit(tx)
     }
 
     fn join_user(&mut self, chan: String, user: String) -> crdb::Completion {
         let mut tx = self.m_table.open();
-        tx.add(format!("{}:{}", user, chan), MembershipRecord::present());
+        tx.add(membership_key(&user, &irc_lower(&chan)), MembershipRecord::present(self.sid));
         self.db.commit(tx)
     }
 
     fn part_user(&mut self, chan: String, user: String) -> crdb::Completion {
         let mut tx = self.m_table.open();
-        tx.add(format!("{}:{}", user, chan), MembershipRecord::left());
+        tx.add(membership_key(&user, &irc_lower(&chan)), MembershipRecord::left(self.sid));
         self.db.commit(tx)
     }
+
+    /// Returns the name to show for the channel with the casefolded name `key`.
+    fn chan_name(&self, key: &str) -> String {
+        match self.c_table.get(key) {
+            Some(rec) => rec.display_name(key),
+            None => key.to_string(),
+        }
+    }
+
+    fn use_nick(&mut self, user: String, nick: String) -> Option<crdb::Completion> {
+        // the nick is stored behind a one byte length in its record
+        if nick.len() > 255 {
+            return None;
+        }
+
+        let now = Clock::now(self.sid);
+        let key = irc_lower(&nick);
+        let expired = match self.n_table.get(&key) {
+            Some(rec) => match rec.holder() {
+                // changing only the case of a nick is a fresh claim, made after the old one
+                // expires, so that the new form replaces the old one everywhere
+                Some(holder) if *holder == user && rec.nick != nick => {
+                    let mut tx = self.n_table.open();
+                    let expired = rec.claimed;
+                    tx.add(key, NickRecord { nick: nick, claimed: now, expired: expired, .. rec });
+                    return Some(self.db.commit(tx));
+                },
+                Some(holder) if *holder == user => return Some(self.db.commit(self.n_table.open())),
+                Some(_) => return None,
+                None => rec.expired,
+            },
+            None => Clock::neg_infty(),
+        };
+
+        let old = self.nicks.get(&user).and_then(|old| {
+            let old = irc_lower(old);
+            self.n_table.get(&old).map(|rec| (old, rec))
+        });
+
+        let mut tx = self.n_table.open();
+        let rec = NickRecord { nick: nick, owner: Some(user), claimed: now, expired: expired };
+        tx.add(key, rec);
+        if let Some((old, rec)) = old {
+            tx.add(old, NickRecord { expired: now, .. rec });
+        }
+        Some(self.db.commit(tx))
+    }
+
+    fn remove_user(&mut self, user: String) -> crdb::Completion {
+        let chans: Vec<String> = match self.chans_for_user.get(&user) {
+            Some(chans) => chans.iter().cloned().collect(),
+            None => Vec::new(),
+        };
+
+        let parted = {
+            let mut tx = self.m_table.open();
+            for chan in chans.iter() {
+                tx.add(membership_key(&user, chan), MembershipRecord::left(self.sid));
+            }
+            self.db.commit(tx)
+        };
+
+        // expire the user's claim on their nick, so that someone else can use it
+        let released = {
+            let held = self.nicks.get(&user).and_then(|nick| {
+                let key = irc_lower(nick);
+                self.n_table.get(&key).map(|rec| (key, rec))
+            });
+
+            let mut tx = self.n_table.open();
+            if let Some((key, rec)) = held {
+                if rec.holder() == Some(&user) {
+                    tx.add(key, NickRecord { expired: Clock::now(self.sid), .. rec });
+                }
+            }
+            self.db.commit(tx)
+        };
+
+        let removed = {
+            let mut tx = self.u_table.open();
+            tx.remove(user);
+            self.db.commit(tx)
+        };
+
+        parted.and(released).and(removed)
+    }
 }
 
 #[derive(Debug)]
 pub enum WorldEvent {
+    UserAdded(String), // user
+    ChanAdded(String), // chan
     UserJoin(String, String), // chan, user
     UserPart(String, String), // chan, user
+    TopicChange(String, String), // chan, topic
+    ModeChange(String, char, bool), // chan, mode, enabled
+    NickChange(String, String), // old, new
+    AwayChange(String, Option<String>), // user, message
     Message(String, String, String), // chan, user, message
 }
 
+/// The result of `World::names`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Names {
+    /// The members of the channel, in no particular order
+    pub users: Vec<String>,
+    /// Whether every server that might know of other members is reachable
+    pub complete: bool,
+}
+
+/// Totals returned by `World::stats`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stats {
+    pub users: usize,
+    pub chans: usize,
+    /// The number of servers currently believed unreachable
+    pub unreachable: usize,
+    /// The number of replicated rows waiting for a table this server doesn't have yet
+    pub pending: usize,
+}
+
 #[derive(Clone)]
 pub struct World {
     inner: Rc<RefCell<WorldInner>>,
 }
 
 impl World {
-    pub fn new(handle: &Handle) -> World {
-        let inner = WorldInner::new();
+    pub fn new(handle: &Handle, sid: Sid) -> World {
+        let inner = WorldInner::new(sid);
         let mut world = World { inner: Rc::new(RefCell::new(inner)) };
 
         world.bind_raw(handle);
         world.bind_u_table(handle);
         world.bind_c_table(handle);
         world.bind_m_table(handle);
+        world.bind_n_table(handle);
 
         world
     }
@@ -109,6 +281,15 @@ impl World {
         self.inner.borrow_mut().events.observer()
     }
 
+    /// Returns a fresh identity for a user connecting to this server. Identities are unique
+    /// across servers and restarts, and unlike nicknames never change.
+    pub fn new_user_id(&mut self) -> String {
+        let mut inner = self.inner.borrow_mut();
+        let id = format!("{}:{:08x}:{}", inner.sid, inner.epoch, inner.next_user);
+        inner.next_user += 1;
+        id
+    }
+
     pub fn add_user(&mut self, user: String) -> crdb::Completion {
         self.inner.borrow_mut().add_user(user)
     }
@@ -117,6 +298,39 @@ impl World {
         self.inner.borrow_mut().add_chan(chan)
     }
 
+    /// Marks `user` as away with the given message, or as back if `away` is `None`. Does nothing
+    /// if there is no such user.
+    pub fn set_away(&mut self, user: String, away: Option<String>) -> crdb::Completion {
+        self.inner.borrow_mut().set_away(user, away)
+    }
+
+    pub fn away(&self, user: &str) -> Option<String> {
+        self.inner.borrow().u_table.get(user).and_then(|u| u.away)
+    }
+
+    /// Sets the topic of `chan`. Does nothing if there is no such channel.
+    pub fn set_topic(&mut self, chan: String, topic: String) -> crdb::Completion {
+        self.inner.borrow_mut().set_topic(chan, topic)
+    }
+
+    pub fn topic(&self, chan: &str) -> Option<String> {
+        self.inner.borrow().c_table.get(&irc_lower(chan)).and_then(|c| c.topic)
+    }
+
+    /// Sets or clears a channel mode flag. Does nothing if there is no such channel. Panics if
+    /// `mode` is not ASCII.
+    pub fn set_mode(&mut self, chan: String, mode: char, enabled: bool) -> crdb::Completion {
+        self.inner.borrow_mut().set_mode(chan, mode, enabled)
+    }
+
+    /// Returns the mode flags currently set on `chan`, in order, e.g. `"nt"`.
+    pub fn modes(&self, chan: &str) -> String {
+        match self.inner.borrow().c_table.get(&irc_lower(chan)) {
+            Some(c) => c.modes.iter().filter(|&(_, m)| m.enabled).map(|(&c, _)| c).collect(),
+            None => String::new(),
+        }
+    }
+
     pub fn join_user(&mut self, chan: String, user: String) -> crdb::Completion {
         self.inner.borrow_mut().join_user(chan, user)
     }
@@ -125,9 +339,86 @@ impl World {
         self.inner.borrow_mut().part_user(chan, user)
     }
 
+    pub fn use_nick(&mut self, user: String, nick: String) -> Option<crdb::Completion> {
+        self.inner.borrow_mut().use_nick(user, nick)
+    }
+
+    pub fn nick(&self, user: &str) -> Option<String> {
+        self.inner.borrow().nicks.get(user).cloned()
+    }
+
+    /// Returns the user currently holding `nick`, which is compared casefolded.
+    pub fn nick_owner(&self, nick: &str) -> Option<String> {
+        let rec = self.inner.borrow().n_table.get(&irc_lower(nick));
+        rec.and_then(|rec| rec.holder().cloned())
+    }
+
+    /// Returns a snapshot of the users currently in `chan`, in no particular order.
+    pub fn channel_members(&self, chan: &str) -> Vec<String> {
+        match self.inner.borrow().users_for_chan.get(&irc_lower(chan)) {
+            Some(users) => users.iter().cloned().collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Returns the members of `chan` this server knows about, for answering `NAMES` and `WHO`.
+    /// The list is only believed complete if no other server is currently unreachable, since an
+    /// unreachable server may have members we haven't heard about.
+    pub fn names(&self, chan: &str) -> Names {
+        Names {
+            users: self.channel_members(chan),
+            complete: self.inner.borrow().unreachable.is_empty(),
+        }
+    }
+
+    /// Records whether the server `sid` can currently be reached. This only affects whether
+    /// query results are reported as complete.
+    pub fn set_reachable(&mut self, sid: Sid, reachable: bool) {
+        let mut inner = self.inner.borrow_mut();
+        if reachable {
+            inner.unreachable.remove(&sid);
+        } else {
+            inner.unreachable.insert(sid);
+        }
+    }
+
+    /// Returns the number of users, channels, and unreachable servers this server knows of,
+    /// and how many replicated rows are still pending.
+    pub fn stats(&self) -> Stats {
+        let inner = self.inner.borrow();
+        Stats {
+            users: inner.users.len(),
+            chans: inner.chans.len(),
+            unreachable: inner.unreachable.len(),
+            pending: inner.db.pending_rows(),
+        }
+    }
+
+    /// Applies updates received from another server. Rows for tables this server doesn't have
+    /// are held on to until it does.
+    pub fn commit_raw(&mut self, tx: crdb::RawTransaction) -> crdb::Completion {
+        self.inner.borrow_mut().db.commit_raw(tx)
+    }
+
+    /// Returns a snapshot of the channels `user` is currently in, in no particular order.
+    pub fn user_channels(&self, user: &str) -> Vec<String> {
+        match self.inner.borrow().chans_for_user.get(user) {
+            Some(chans) => chans.iter().cloned().collect(),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn remove_user(&mut self, user: String) -> crdb::Completion {
+        self.inner.borrow_mut().remove_user(user)
+    }
+
     pub fn message(&mut self, chan: String, user: String, message: String) -> Completion {
-        let event = WorldEvent::Message(chan, user, message);
-        self.inner.borrow_mut().events.put(event)
+        let mut inner = self.inner.borrow_mut();
+        let chan = match inner.c_table.get(&irc_lower(&chan)) {
+            Some(rec) => rec.display_name(&chan),
+            None => chan,
+        };
+        inner.events.put(WorldEvent::Message(chan, user, message))
     }
 
     fn bind_raw(&mut self, handle: &Handle) {
@@ -145,14 +436,28 @@ impl World {
         debug!("binding u_table updates");
 
         let inner = self.inner.clone();
-        let updates = inner.borrow_mut().c_table.updates();
+        let updates = inner.borrow_mut().u_table.updates();
 
         handle.spawn(updates.for_each(move |updates| {
             info!("u table updates: {:?}", updates);
 
-            let ref mut users = inner.borrow_mut().users;
+            let mut inner_mut = inner.borrow_mut();
+
             for update in updates.updates.iter() {
-                users.insert(update.key.clone());
+                if update.removed {
+                    inner_mut.users.remove(&update.key);
+                    continue;
+                }
+
+                if inner_mut.users.insert(update.key.clone()) {
+                    inner_mut.events.put(WorldEvent::UserAdded(update.key.clone()));
+                }
+
+                let prev_away = update.prev.as_ref().and_then(|u| u.away.as_ref());
+                if prev_away != update.item.away.as_ref() {
+                    let away = update.item.away.clone();
+                    inner_mut.events.put(WorldEvent::AwayChange(update.key.clone(), away));
+                }
             }
 
             Ok(())
@@ -168,9 +473,37 @@ impl World {
         handle.spawn(updates.for_each(move |updates| {
             info!("c table updates: {:?}", updates);
 
-            let ref mut chans = inner.borrow_mut().chans;
+            let mut inner_mut = inner.borrow_mut();
+
             for update in updates.updates.iter() {
-                chans.insert(update.key.clone());
+                if update.removed {
+                    inner_mut.chans.remove(&update.key);
+                    continue;
+                }
+
+                let name = update.item.display_name(&update.key);
+                if inner_mut.chans.insert(update.key.clone()) {
+                    inner_mut.events.put(WorldEvent::ChanAdded(name.clone()));
+                }
+
+                let prev_topic = update.prev.as_ref().and_then(|c| c.topic.as_ref());
+                if let Some(ref topic) = update.item.topic {
+                    if prev_topic != Some(topic) {
+                        let event = WorldEvent::TopicChange(name.clone(), topic.clone());
+                        inner_mut.events.put(event);
+                    }
+                }
+
+                for (&mode, flag) in update.item.modes.iter() {
+                    let prev = update.prev.as_ref()
+                        .and_then(|c| c.modes.get(&mode))
+                        .map(|m| m.enabled)
+                        .unwrap_or(false);
+                    if prev != flag.enabled {
+                        let event = WorldEvent::ModeChange(name.clone(), mode, flag.enabled);
+                        inner_mut.events.put(event);
+                    }
+                }
             }
 
             Ok(())
@@ -192,11 +525,13 @@ impl World {
             let mut inner_mut = inner.borrow_mut();
 
             for update in updates.updates.iter() {
-                let fields: Vec<&str> = update.key.splitn(2, ':').collect();
-                assert_eq!(fields.len(), 2);
-
-                let user = fields[0];
-                let chan = fields[1];
+                let (user, chan) = match parse_membership_key(&update.key) {
+                    Some(fields) => fields,
+                    None => {
+                        warn!("ignoring malformed membership key {:?}", update.key);
+                        continue;
+                    },
+                };
 
                 let prev_status = update.prev.as_ref().map(|m| m.status.clone()).unwrap_or(Left);
                 let curr_status = update.item.status.clone();
@@ -212,18 +547,24 @@ impl World {
                             .or_insert_with(|| HashSet::new())
                             .insert(chan.to_string());
 
-                        inner_mut.events.put(UserJoin(chan.to_string(), user.to_string()));
+                        let name = inner_mut.chan_name(chan);
+                        inner_mut.events.put(UserJoin(name, user.to_string()));
                     },
 
                     (Present, Left) => {
                         inner_mut.users_for_chan
                             .get_mut(chan)
                             .map(|m| m.remove(user));
-                        inner_mut.chans_for_user
+                        let now_empty = inner_mut.chans_for_user
                             .get_mut(user)
-                            .map(|m| m.remove(chan));
-
-                        inner_mut.events.put(UserPart(chan.to_string(), user.to_string()));
+                            .map(|m| { m.remove(chan); m.is_empty() })
+                            .unwrap_or(false);
+                        if now_empty {
+                            inner_mut.chans_for_user.remove(user);
+                        }
+
+                        let name = inner_mut.chan_name(chan);
+                        inner_mut.events.put(UserPart(name, user.to_string()));
                     },
 
                     _ => { }
@@ -233,37 +574,246 @@ impl World {
             Ok(())
         }));
     }
+
+    fn bind_n_table(&mut self, handle: &Handle) {
+        debug!("binding n_table updates");
+
+        let inner = self.inner.clone();
+        let updates = inner.borrow_mut().n_table.updates();
+
+        handle.spawn(updates.for_each(move |updates| {
+            info!("n table updates: {:?}", updates);
+
+            let mut inner_mut = inner.borrow_mut();
+
+            // gains first, so that releasing the old nick in a nick change isn't mistaken for
+            // having the nick taken away
+            for update in updates.updates.iter() {
+                let prev = update.prev.as_ref().and_then(|n| n.holder().map(|u| (u, &n.nick)));
+                if let Some(user) = update.item.holder() {
+                    if prev != Some((user, &update.item.nick)) {
+                        let nick = update.item.nick.clone();
+                        let old = inner_mut.nicks.insert(user.clone(), nick.clone());
+                        let old = old.unwrap_or_else(|| user.clone());
+                        inner_mut.events.put(WorldEvent::NickChange(old, nick));
+                    }
+                }
+            }
+
+            // a user that lost their nick without taking another goes back to their identity
+            for update in updates.updates.iter() {
+                let curr = update.item.holder();
+                let prev = update.prev.as_ref().and_then(|n| n.holder().map(|u| (&n.nick, u)));
+                if let Some((nick, user)) = prev {
+                    if curr != Some(user) && inner_mut.nicks.get(user) == Some(nick) {
+                        inner_mut.nicks.remove(user);
+                        let event = WorldEvent::NickChange(nick.clone(), user.clone());
+                        inner_mut.events.put(event);
+                    }
+                }
+            }
+
+            Ok(())
+        }));
+    }
 }
 
-#[derive(Debug, Clone)]
-struct UserRecord;
+#[derive(Debug, Clone, Eq, PartialEq)]
+struct UserRecord {
+    away: Option<String>,
+    away_set: Clock,
+}
+
+impl UserRecord {
+    fn new() -> UserRecord {
+        UserRecord { away: None, away_set: Clock::neg_infty() }
+    }
+
+    fn with_away(sid: Sid, away: Option<String>) -> UserRecord {
+        UserRecord { away: away, away_set: Clock::now(sid) }
+    }
+}
 
 struct UserSchema;
 
 impl crdb::Schema for UserSchema {
     type Item = UserRecord;
 
-    fn decode(&self, _: &crdb::Record) -> UserRecord { UserRecord }
-    fn encode(&self, _: &UserRecord) -> crdb::Record { crdb::Record(Vec::new()) }
-    fn merge(&self, a: UserRecord, _: UserRecord) -> UserRecord { a }
+    fn decode(&self, data: &crdb::Record) -> UserRecord {
+        let (away_set, away) = data.0.split_at(15);
+
+        UserRecord {
+            away: match away.split_first() {
+                Some((&1, away)) => Some(String::from_utf8_lossy(away).into_owned()),
+                _ => None,
+            },
+            away_set: Clock::from_bytes(away_set).expect("bad user away clock"),
+        }
+    }
+
+    fn encode(&self, rec: &UserRecord) -> crdb::Record {
+        let mut data = rec.away_set.to_bytes();
+
+        if let Some(ref away) = rec.away {
+            data.push(1);
+            data.extend_from_slice(away.as_bytes());
+        }
+
+        crdb::Record(data)
+    }
+
+    fn merge(&self, a: UserRecord, b: UserRecord) -> UserRecord {
+        if a.away_set > b.away_set { a } else { b }
+    }
+}
+
+/// A channel's name, topic, and mode flags. Records are keyed by the casefolded name, and `name`
+/// is the form the channel was first created with, so the oldest name wins. The topic and each
+/// mode flag are separately last writer wins, so that concurrent changes to different flags all
+/// take effect.
+#[derive(Debug, Clone, Eq, PartialEq)]
+struct ChannelRecord {
+    name: String,
+    named: Clock,
+    topic: Option<String>,
+    topic_set: Clock,
+    modes: BTreeMap<char, ModeFlag>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+struct ModeFlag {
+    enabled: bool,
+    set: Clock,
 }
 
-#[derive(Debug, Clone)]
-struct ChannelRecord;
+impl ChannelRecord {
+    fn new() -> ChannelRecord {
+        ChannelRecord {
+            name: String::new(),
+            named: Clock::pos_infty(),
+            topic: None,
+            topic_set: Clock::neg_infty(),
+            modes: BTreeMap::new(),
+        }
+    }
+
+    fn named(sid: Sid, name: String) -> ChannelRecord {
+        // the name is stored behind a one byte length, and the casefolded key will do otherwise
+        if name.len() > 255 {
+            return ChannelRecord::new();
+        }
+        ChannelRecord { name: name, named: Clock::now(sid), .. ChannelRecord::new() }
+    }
+
+    /// Returns the name to show for the channel stored under `key`
+    fn display_name(&self, key: &str) -> String {
+        if self.name.is_empty() { key.to_string() } else { self.name.clone() }
+    }
+
+    fn with_topic(sid: Sid, topic: String) -> ChannelRecord {
+        ChannelRecord { topic: Some(topic), topic_set: Clock::now(sid), .. ChannelRecord::new() }
+    }
+
+    fn with_mode(sid: Sid, mode: char, enabled: bool) -> ChannelRecord {
+        assert!(mode.is_ascii(), "channel modes must be ASCII");
+        let mut rec = ChannelRecord::new();
+        rec.modes.insert(mode, ModeFlag { enabled: enabled, set: Clock::now(sid) });
+        rec
+    }
+}
 
 struct ChannelSchema;
 
 impl crdb::Schema for ChannelSchema {
     type Item = ChannelRecord;
 
-    fn decode(&self, _: &crdb::Record) -> ChannelRecord { ChannelRecord }
-    fn encode(&self, _: &ChannelRecord) -> crdb::Record { crdb::Record(Vec::new()) }
-    fn merge(&self, a: ChannelRecord, _: ChannelRecord) -> ChannelRecord { a }
+    fn decode(&self, data: &crdb::Record) -> ChannelRecord {
+        let (named, rest) = data.0.split_at(15);
+        let (len, rest) = rest.split_first().expect("missing channel name length");
+        let (name, rest) = rest.split_at(*len as usize);
+        let (topic_set, rest) = rest.split_at(15);
+        let (count, mut rest) = rest.split_first().expect("missing channel mode count");
+
+        let mut modes = BTreeMap::new();
+        for _ in 0..*count {
+            let (flag, more) = rest.split_at(17);
+            modes.insert(flag[0] as char, ModeFlag {
+                enabled: flag[1] == 1,
+                set: Clock::from_bytes(&flag[2..]).expect("bad channel mode clock"),
+            });
+            rest = more;
+        }
+
+        ChannelRecord {
+            name: String::from_utf8_lossy(name).into_owned(),
+            named: Clock::from_bytes(named).expect("bad channel name clock"),
+            topic: match rest.split_first() {
+                Some((&1, topic)) => Some(String::from_utf8_lossy(topic).into_owned()),
+                _ => None,
+            },
+            topic_set: Clock::from_bytes(topic_set).expect("bad channel topic clock"),
+            modes: modes,
+        }
+    }
+
+    fn encode(&self, rec: &ChannelRecord) -> crdb::Record {
+        let mut data = rec.named.to_bytes();
+        data.push(rec.name.len() as u8);
+        data.extend_from_slice(rec.name.as_bytes());
+        data.extend(rec.topic_set.to_bytes());
+
+        data.push(rec.modes.len() as u8);
+        for (&mode, flag) in rec.modes.iter() {
+            data.push(mode as u8);
+            data.push(if flag.enabled { 1 } else { 0 });
+            data.extend(flag.set.to_bytes());
+        }
+
+        if let Some(ref topic) = rec.topic {
+            data.push(1);
+            data.extend_from_slice(topic.as_bytes());
+        }
+
+        crdb::Record(data)
+    }
+
+    fn merge(&self, a: ChannelRecord, b: ChannelRecord) -> ChannelRecord {
+        let mut modes = a.modes;
+        for (mode, flag) in b.modes.into_iter() {
+            let newer = match modes.get(&mode) {
+                Some(ours) => flag.set > ours.set,
+                None => true,
+            };
+            if newer {
+                modes.insert(mode, flag);
+            }
+        }
+
+        let (topic, topic_set) = if a.topic_set > b.topic_set {
+            (a.topic, a.topic_set)
+        } else {
+            (b.topic, b.topic_set)
+        };
+
+        let (name, named) = if (a.named, &a.name) <= (b.named, &b.name) {
+            (a.name, a.named)
+        } else {
+            (b.name, b.named)
+        };
+
+        ChannelRecord {
+            name: name,
+            named: named,
+            topic: topic,
+            topic_set: topic_set,
+            modes: modes,
+        }
+    }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Eq, PartialEq)]
 struct MembershipRecord {
-    since: Timestamp,
+    since: Clock,
     status: MembershipStatus,
 }
 
@@ -274,48 +824,90 @@ enum MembershipStatus {
 }
 
 impl MembershipRecord {
-    fn with_status(status: MembershipStatus) -> MembershipRecord {
-        MembershipRecord { since: Timestamp::now(), status: status }
+    fn with_status(sid: Sid, status: MembershipStatus) -> MembershipRecord {
+        MembershipRecord { since: Clock::now(sid), status: status }
+    }
+
+    fn present(sid: Sid) -> MembershipRecord {
+        MembershipRecord::with_status(sid, MembershipStatus::Present)
     }
 
-    fn present() -> MembershipRecord {
-        MembershipRecord::with_status(MembershipStatus::Present)
+    fn left(sid: Sid) -> MembershipRecord {
+        MembershipRecord::with_status(sid, MembershipStatus::Left)
     }
+}
+
+/// Builds the key for a user's membership in a channel. The user is prefixed with its length,
+/// so that either name can contain any character, including the `:` separators.
+fn membership_key(user: &str, chan: &str) -> String {
+    format!("{}:{}:{}", user.len(), user, chan)
+}
+
+/// Splits a key built by `membership_key` back into the user and channel.
+fn parse_membership_key(key: &str) -> Option<(&str, &str)> {
+    let (len, rest) = match key.find(':').map(|i| (key[..i].parse::<usize>(), &key[i + 1..])) {
+        Some((Ok(len), rest)) => (len, rest),
+        _ => return None,
+    };
 
-    fn left() -> MembershipRecord {
-        MembershipRecord::with_status(MembershipStatus::Left)
+    if len >= rest.len() || !rest.is_char_boundary(len) || rest.as_bytes()[len] != b':' {
+        return None;
     }
+
+    Some((&rest[..len], &rest[len + 1..]))
 }
 
+/// The current version of the membership record format. Version 1 records are the version byte
+/// followed by the unversioned format, which is a status byte of `P` or `L` and the clock.
+const MEMBERSHIP_VERSION: u8 = 1;
+
 struct MembershipSchema;
 
+impl MembershipSchema {
+    fn decode_v0(data: &[u8]) -> Option<MembershipRecord> {
+        let (status, since) = match data.split_first() {
+            Some((&b'P', since)) => (MembershipStatus::Present, since),
+            Some((&b'L', since)) => (MembershipStatus::Left, since),
+            _ => return None,
+        };
+
+        Clock::from_bytes(since).map(|since| MembershipRecord { since: since, status: status })
+    }
+}
+
 impl crdb::Schema for MembershipSchema {
     type Item = MembershipRecord;
 
     fn decode(&self, data: &crdb::Record) -> MembershipRecord {
-        let spec = String::from_utf8_lossy(&data.0[..]).into_owned();
-        let (status, since) = spec.split_at(1);
-
-        MembershipRecord {
-            status: match status {
-                "P" => MembershipStatus::Present,
-                "L" => MembershipStatus::Left,
-                _ => panic!("unknown membership status"),
-            },
-            since: Timestamp::parse(since),
-        }
+        let rec = match data.split_version(MEMBERSHIP_VERSION) {
+            Ok((MEMBERSHIP_VERSION, rest)) => MembershipSchema::decode_v0(rest),
+
+            // records from before versioning start with the status byte, which can't be
+            // mistaken for a version this early on
+            Err(crdb::RecordError::SchemaVersion(b'P')) |
+            Err(crdb::RecordError::SchemaVersion(b'L')) => MembershipSchema::decode_v0(&data.0),
+
+            // anything else is malformed or from a newer replica
+            _ => None,
+        };
+
+        // give what we can't read the oldest possible clock, so that any real record wins
+        rec.unwrap_or_else(|| {
+            warn!("unreadable membership record: {:?}", data);
+            MembershipRecord { since: Clock::neg_infty(), status: MembershipStatus::Left }
+        })
     }
 
     fn encode(&self, rec: &MembershipRecord) -> crdb::Record {
-        let s = format!("{}{}",
+        let mut data = vec![
             match rec.status {
-                MembershipStatus::Present => "P",
-                MembershipStatus::Left => "L"
-            },
-            rec.since.format()
-        );
+                MembershipStatus::Present => b'P',
+                MembershipStatus::Left => b'L'
+            }
+        ];
+        data.extend(rec.since.to_bytes());
 
-        crdb::Record(s.into_bytes())
+        crdb::Record::versioned(MEMBERSHIP_VERSION, &data)
     }
 
     fn merge(&self, a: MembershipRecord, b: MembershipRecord) -> MembershipRecord {
@@ -323,21 +915,814 @@ impl crdb::Schema for MembershipSchema {
     }
 }
 
-const TIME_FORMAT: &'static str = "%y%m%d%H%M%S";
+/// A claim on a nickname. The nick is held by the owner of the oldest claim made after the newest
+/// expiration, on a first come first served basis. See `state::claim` for the details. Records
+/// are keyed by the casefolded nick, and `nick` is the form the owner asked for.
+#[derive(Debug, Clone, Eq, PartialEq)]
+struct NickRecord {
+    nick: String,
+    owner: Option<String>,
+    claimed: Clock,
+    expired: Clock,
+}
+
+impl NickRecord {
+    fn holder(&self) -> Option<&String> {
+        if self.claimed > self.expired { self.owner.as_ref() } else { None }
+    }
+
+    fn is_skewed(&self, now: &Clock) -> bool {
+        self.claimed.is_ahead_of(now, MAX_SKEW) || self.expired.is_ahead_of(now, MAX_SKEW)
+    }
+}
+
+struct NickSchema;
+
+impl NickSchema {
+    fn read(data: &[u8]) -> Option<NickRecord> {
+        if data.len() < 31 || data.len() < 31 + data[30] as usize {
+            return None;
+        }
 
-#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd)]
-struct Timestamp(String);
+        let (claimed, rest) = data.split_at(15);
+        let (expired, rest) = rest.split_at(15);
+        let (nick, owner) = rest[1..].split_at(rest[0] as usize);
 
-impl Timestamp {
-    fn now() -> Timestamp {
-        Timestamp(time::strftime(TIME_FORMAT, &time::now_utc()).unwrap())
+        let (claimed, expired) = match (Clock::from_bytes(claimed), Clock::from_bytes(expired)) {
+            (Some(claimed), Some(expired)) => (claimed, expired),
+            _ => return None,
+        };
+
+        Some(NickRecord {
+            nick: String::from_utf8_lossy(nick).into_owned(),
+            owner: match owner.split_first() {
+                Some((&1, owner)) => Some(String::from_utf8_lossy(owner).into_owned()),
+                _ => None,
+            },
+            claimed: claimed,
+            expired: expired,
+        })
     }
+}
 
-    fn format(&self) -> &str {
-        &self.0[..]
+impl crdb::Schema for NickSchema {
+    type Item = NickRecord;
+
+    fn decode(&self, data: &crdb::Record) -> NickRecord {
+        // a record we can't read is treated as a claim that has long since expired
+        NickSchema::read(&data.0).unwrap_or_else(|| {
+            warn!("unreadable nick record: {:?}", data);
+            NickRecord {
+                nick: String::new(),
+                owner: None,
+                claimed: Clock::neg_infty(),
+                expired: Clock::neg_infty(),
+            }
+        })
     }
 
-    fn parse(s: &str) -> Timestamp {
-        Timestamp(s.to_string())
+    fn encode(&self, rec: &NickRecord) -> crdb::Record {
+        let mut data = rec.claimed.to_bytes();
+        data.extend(rec.expired.to_bytes());
+        data.push(rec.nick.len() as u8);
+        data.extend_from_slice(rec.nick.as_bytes());
+
+        if let Some(ref owner) = rec.owner {
+            data.push(1);
+            data.extend_from_slice(owner.as_bytes());
+        }
+
+        crdb::Record(data)
     }
+
+    fn merge(&self, a: NickRecord, b: NickRecord) -> NickRecord {
+        let expired = if a.expired > b.expired { a.expired } else { b.expired };
+
+        // prefer a claim that survives the expiration, and then the older claim
+        let a_first = match (a.claimed > expired, b.claimed > expired) {
+            (true, false) => true,
+            (false, true) => false,
+            _ => (a.claimed, &a.owner) <= (b.claimed, &b.owner),
+        };
+
+        let winner = if a_first { a } else { b };
+        if winner.claimed > expired {
+            return NickRecord { expired: expired, .. winner };
+        }
+
+        // nobody holds the nick, so which losing claim got here first must not matter
+        NickRecord {
+            nick: String::new(),
+            owner: None,
+            claimed: Clock::neg_infty(),
+            expired: expired,
+        }
+    }
+
+    fn accept(&self, rec: &NickRecord) -> bool {
+        // as with `ClaimSet::merge`, a server with a badly skewed clock must not be able to take
+        // over nicks by claiming them in the future
+        !rec.is_skewed(&Clock::now(Sid::identity()))
+    }
+}
+
+#[cfg(test)]
+use tokio_core::reactor::Core;
+
+#[test]
+fn add_user_is_observed() {
+    let mut core = Core::new().unwrap();
+    let mut world = World::new(&core.handle(), Sid::identity());
+
+    core.run(world.add_user("alice".to_string())).unwrap();
+    core.run(world.add_chan("#oxide".to_string())).unwrap();
+
+    let inner = world.inner.borrow();
+    assert!(inner.users.contains("alice"));
+    assert!(!inner.users.contains("#oxide"));
+    assert!(inner.chans.contains("#oxide"));
+    assert!(!inner.chans.contains("alice"));
+}
+
+#[test]
+fn add_events_fire_once() {
+    use futures::Stream;
+
+    let mut core = Core::new().unwrap();
+    let mut world = World::new(&core.handle(), Sid::identity());
+    let events = world.events();
+
+    core.run(world.add_user("alice".to_string())).unwrap();
+    core.run(world.add_user("alice".to_string())).unwrap();
+    core.run(world.add_chan("#oxide".to_string())).unwrap();
+    core.run(world.add_chan("#oxide".to_string())).unwrap();
+    core.run(world.add_user("bob".to_string())).unwrap();
+
+    // a message marks the end of the events we care about
+    world.message("#oxide".to_string(), "bob".to_string(), "done".to_string());
+
+    let events = core.run(events.take(4).collect()).unwrap();
+    let events: Vec<String> = events.iter().map(|ev| format!("{:?}", **ev)).collect();
+    assert_eq!(events, vec![
+        "UserAdded(\"alice\")",
+        "ChanAdded(\"#oxide\")",
+        "UserAdded(\"bob\")",
+        "Message(\"#oxide\", \"bob\", \"done\")",
+    ]);
+}
+
+#[cfg(test)]
+fn nick_changes(core: &mut Core, events: Observer<WorldEvent>, n: u64) -> Vec<(String, String)> {
+    use futures::Stream;
+
+    let events = events.filter(|ev| match **ev {
+        WorldEvent::NickChange(_, _) => true,
+        _ => false,
+    });
+
+    core.run(events.take(n).collect()).unwrap().iter().map(|ev| match **ev {
+        WorldEvent::NickChange(ref old, ref new) => (old.clone(), new.clone()),
+        _ => unreachable!(),
+    }).collect()
+}
+
+#[test]
+fn use_nick() {
+    let mut core = Core::new().unwrap();
+    let mut world = World::new(&core.handle(), Sid::identity());
+    let events = world.events();
+
+    core.run(world.use_nick("u1".to_string(), "alice".to_string()).unwrap()).unwrap();
+    core.run(world.use_nick("u1".to_string(), "al".to_string()).unwrap()).unwrap();
+    assert!(world.use_nick("u2".to_string(), "al".to_string()).is_none());
+    core.run(world.use_nick("u2".to_string(), "alice".to_string()).unwrap()).unwrap();
+
+    assert_eq!(world.nick("u1"), Some("al".to_string()));
+    assert_eq!(world.nick("u2"), Some("alice".to_string()));
+
+    assert_eq!(nick_changes(&mut core, events, 3), vec![
+        ("u1".to_string(), "alice".to_string()),
+        ("alice".to_string(), "al".to_string()),
+        ("u2".to_string(), "alice".to_string()),
+    ]);
+}
+
+#[test]
+fn change_nick_case() {
+    let mut core = Core::new().unwrap();
+    let mut world = World::new(&core.handle(), Sid::identity());
+    let events = world.events();
+
+    core.run(world.use_nick("u1".to_string(), "alice".to_string()).unwrap()).unwrap();
+    core.run(world.use_nick("u1".to_string(), "Alice".to_string()).unwrap()).unwrap();
+    core.run(world.use_nick("u1".to_string(), "Alice".to_string()).unwrap()).unwrap();
+    assert!(world.use_nick("u2".to_string(), "ALICE".to_string()).is_none());
+
+    assert_eq!(world.nick("u1"), Some("Alice".to_string()));
+    assert_eq!(world.nick_owner("alice"), Some("u1".to_string()));
+
+    assert_eq!(nick_changes(&mut core, events, 2), vec![
+        ("u1".to_string(), "alice".to_string()),
+        ("alice".to_string(), "Alice".to_string()),
+    ]);
+}
+
+#[test]
+fn user_ids_are_unique() {
+    let core = Core::new().unwrap();
+    let mut world = World::new(&core.handle(), Sid::new("0AA"));
+    let first = world.new_user_id();
+    assert!(first.starts_with("0AA:"));
+    assert!(first != world.new_user_id());
+
+    // a restarted server doesn't start over
+    let mut restarted = World::new(&core.handle(), Sid::new("0AA"));
+    assert!(first != restarted.new_user_id());
+}
+
+#[test]
+fn names_are_casefolded() {
+    let mut core = Core::new().unwrap();
+    let mut world = World::new(&core.handle(), Sid::identity());
+
+    core.run(world.add_chan("#FOO".to_string())).unwrap();
+    core.run(world.add_chan("#foo".to_string())).unwrap();
+    core.run(world.set_topic("#Foo".to_string(), "hello".to_string())).unwrap();
+    assert_eq!(world.topic("#fOO"), Some("hello".to_string()));
+
+    core.run(world.join_user("#FOO".to_string(), "u1".to_string())).unwrap();
+    core.run(world.join_user("#foo".to_string(), "u2".to_string())).unwrap();
+
+    core.run(world.use_nick("u1".to_string(), "Alice[away]".to_string()).unwrap()).unwrap();
+    assert!(world.use_nick("u2".to_string(), "alice{AWAY}".to_string()).is_none());
+
+    let inner = world.inner.borrow();
+    assert_eq!(inner.chans.len(), 1);
+    assert_eq!(inner.users_for_chan.get("#foo").map(|u| u.len()), Some(2));
+    assert_eq!(inner.nicks.get("u1"), Some(&"Alice[away]".to_string()));
+}
+
+#[test]
+fn concurrent_nick_claims() {
+    use std::thread;
+    use std::time::Duration;
+
+    fn sync(core: &mut Core, from: &World, to: &mut World) {
+        use crdb::Schema;
+
+        let mut tx = crdb::RawTransaction::new();
+        for (nick, rec) in from.inner.borrow().n_table.iter() {
+            tx.add("n".to_string(), nick, NickSchema.encode(&rec));
+        }
+        let completion = to.inner.borrow_mut().db.commit_raw(tx);
+        core.run(completion).unwrap();
+    }
+
+    let mut core = Core::new().unwrap();
+    let mut a = World::new(&core.handle(), Sid::new("0AA"));
+    let mut b = World::new(&core.handle(), Sid::new("0BB"));
+    let b_events = b.events();
+
+    core.run(a.use_nick("ua".to_string(), "nick".to_string()).unwrap()).unwrap();
+    thread::sleep(Duration::from_millis(1));
+    core.run(b.use_nick("ub".to_string(), "nick".to_string()).unwrap()).unwrap();
+
+    sync(&mut core, &a, &mut b);
+    sync(&mut core, &b, &mut a);
+
+    // the older claim wins on both sides
+    assert_eq!(a.nick("ua"), Some("nick".to_string()));
+    assert_eq!(b.nick("ua"), Some("nick".to_string()));
+    assert_eq!(a.nick("ub"), None);
+    assert_eq!(b.nick("ub"), None);
+    assert_eq!(a.inner.borrow().n_table.get("nick"), b.inner.borrow().n_table.get("nick"));
+
+    // b hands the nick over, and ub goes back to their identity
+    assert_eq!(nick_changes(&mut core, b_events, 3), vec![
+        ("ub".to_string(), "nick".to_string()),
+        ("ua".to_string(), "nick".to_string()),
+        ("nick".to_string(), "ub".to_string()),
+    ]);
+}
+
+#[test]
+fn nick_records_obey_crdt_laws() {
+    let at = |sec, sid| Clock::with_time(::time::Timespec::new(sec, 0), Sid::new(sid));
+    let claim = |nick: &str, owner: &str, claimed, expired| NickRecord {
+        nick: nick.to_string(),
+        owner: Some(owner.to_string()),
+        claimed: claimed,
+        expired: expired,
+    };
+    let released = NickRecord {
+        nick: String::new(),
+        owner: None,
+        claimed: Clock::neg_infty(),
+        expired: at(15, "0AA"),
+    };
+
+    crdb::testing::assert_crdt_laws(&NickSchema, &[
+        claim("Nick", "u1", at(10, "0AA"), Clock::neg_infty()),
+        claim("nick", "u2", at(12, "0BB"), Clock::neg_infty()),
+        claim("NICK", "u3", at(10, "0BB"), Clock::neg_infty()),
+        released,
+        claim("nick", "u2", at(20, "0BB"), at(15, "0AA")),
+        claim("nIcK", "u4", at(30, "0CC"), at(15, "0AA")),
+    ]);
+}
+
+#[test]
+fn unreadable_nick_records_have_expired() {
+    use crdb::Schema;
+
+    let rec = NickRecord {
+        nick: "Nick".to_string(),
+        owner: Some("u1".to_string()),
+        claimed: Clock::at(100),
+        expired: Clock::neg_infty(),
+    };
+    let data = NickSchema.encode(&rec).0;
+    assert_eq!(NickSchema.decode(&crdb::Record(data.clone())), rec);
+
+    let mut garbled = data.clone();
+    garbled[12] = 0xff;
+    let mut samples = vec![garbled];
+    samples.extend((0..data.len() - 3).map(|n| data[..n].to_vec()));
+
+    for sample in samples.into_iter() {
+        let decoded = NickSchema.decode(&crdb::Record(sample));
+        assert_eq!(decoded.holder(), None);
+        assert_eq!(decoded.claimed, Clock::neg_infty());
+        assert_eq!(NickSchema.merge(decoded, rec.clone()), rec);
+    }
+}
+
+#[test]
+fn nick_claims_from_the_future_are_rejected() {
+    use crdb::Schema;
+
+    let mut core = Core::new().unwrap();
+    let mut world = World::new(&core.handle(), Sid::new("0AA"));
+
+    core.run(world.use_nick("ua".to_string(), "nick".to_string()).unwrap()).unwrap();
+
+    let mut future = ::time::get_time();
+    future.sec += 3600;
+    let rec = NickRecord {
+        nick: "nick".to_string(),
+        owner: Some("evil".to_string()),
+        claimed: Clock::with_time(future, Sid::new("0EV")),
+        expired: Clock::with_time(future - ::time::Duration::seconds(1), Sid::new("0EV")),
+    };
+
+    let mut tx = crdb::RawTransaction::new();
+    tx.add("n".to_string(), "nick".to_string(), NickSchema.encode(&rec));
+    tx.add("n".to_string(), "other".to_string(), NickSchema.encode(&rec));
+    let completion = world.inner.borrow_mut().db.commit_raw(tx);
+    core.run(completion).unwrap();
+
+    assert_eq!(world.nick("ua"), Some("nick".to_string()));
+    let owner = world.inner.borrow().n_table.get("nick").and_then(|rec| rec.owner);
+    assert_eq!(owner, Some("ua".to_string()));
+
+    // a nick first seen with a future claim isn't taken either
+    assert_eq!(world.nick_owner("other"), None);
+    assert_eq!(world.nick("evil"), None);
+    core.run(world.use_nick("ub".to_string(), "other".to_string()).unwrap()).unwrap();
+    assert_eq!(world.nick("ub"), Some("other".to_string()));
+}
+
+#[test]
+fn remove_user_parts_channels() {
+    use futures::Stream;
+
+    let mut core = Core::new().unwrap();
+    let mut world = World::new(&core.handle(), Sid::identity());
+    let events = world.events();
+
+    core.run(world.add_user("alice".to_string())).unwrap();
+    core.run(world.use_nick("alice".to_string(), "Alice".to_string()).unwrap()).unwrap();
+    core.run(world.join_user("#a".to_string(), "alice".to_string())).unwrap();
+    core.run(world.join_user("#b".to_string(), "alice".to_string())).unwrap();
+    core.run(world.remove_user("alice".to_string())).unwrap();
+
+    // the nick is free for someone else
+    assert_eq!(world.nick_owner("alice"), None);
+    assert!(world.use_nick("bob".to_string(), "ALICE".to_string()).is_some());
+
+    let events = core.run(events.take(7).collect()).unwrap();
+    let mut parts: Vec<String> = events.iter().filter_map(|ev| match **ev {
+        WorldEvent::UserPart(ref chan, ref user) if user == "alice" => Some(chan.clone()),
+        _ => None,
+    }).collect();
+    parts.sort();
+    assert_eq!(parts, vec!["#a", "#b"]);
+
+    let inner = world.inner.borrow();
+    assert!(!inner.users.contains("alice"));
+    assert!(inner.chans_for_user.get("alice").map(|c| c.is_empty()).unwrap_or(true));
+}
+
+#[test]
+fn membership_lookups() {
+    let mut core = Core::new().unwrap();
+    let mut world = World::new(&core.handle(), Sid::identity());
+
+    core.run(world.join_user("#a".to_string(), "alice".to_string())).unwrap();
+    core.run(world.join_user("#b".to_string(), "alice".to_string())).unwrap();
+    core.run(world.join_user("#a".to_string(), "bob".to_string())).unwrap();
+
+    let mut members = world.channel_members("#A");
+    members.sort();
+    assert_eq!(members, vec!["alice", "bob"]);
+
+    let mut chans = world.user_channels("alice");
+    chans.sort();
+    assert_eq!(chans, vec!["#a", "#b"]);
+
+    core.run(world.part_user("#a".to_string(), "alice".to_string())).unwrap();
+    assert_eq!(world.channel_members("#a"), vec!["bob"]);
+    assert_eq!(world.user_channels("alice"), vec!["#b"]);
+    assert!(world.user_channels("carol").is_empty());
+    assert!(world.channel_members("#c").is_empty());
+}
+
+#[test]
+fn names_under_partition() {
+    let mut core = Core::new().unwrap();
+    let mut world = World::new(&core.handle(), Sid::identity());
+
+    core.run(world.join_user("#a".to_string(), "alice".to_string())).unwrap();
+    core.run(world.join_user("#a".to_string(), "bob".to_string())).unwrap();
+
+    let mut names = world.names("#a");
+    names.users.sort();
+    assert_eq!(names.users, vec!["alice", "bob"]);
+    assert!(names.complete);
+
+    world.set_reachable(Sid::new("0BB"), false);
+    assert!(!world.names("#a").complete);
+    assert_eq!(world.names("#a").users.len(), 2);
+
+    world.set_reachable(Sid::new("0BB"), true);
+    assert!(world.names("#a").complete);
+    assert_eq!(world.names("#nowhere"), Names { users: Vec::new(), complete: true });
+}
+
+#[test]
+fn membership_keys() {
+    assert_eq!(membership_key("alice", "#oxide"), "5:alice:#oxide");
+    assert_eq!(parse_membership_key("5:alice:#oxide"), Some(("alice", "#oxide")));
+    assert_eq!(parse_membership_key("3:a:b:#a:b"), Some(("a:b", "#a:b")));
+    assert_eq!(parse_membership_key("0::#x"), Some(("", "#x")));
+    assert_eq!(parse_membership_key("2:\u{e9}:#x"), Some(("\u{e9}", "#x")));
+
+    assert_eq!(parse_membership_key("alice:#oxide"), None);
+    assert_eq!(parse_membership_key("9:alice:#oxide"), None);
+    assert_eq!(parse_membership_key("4:alice:#oxide"), None);
+    assert_eq!(parse_membership_key("1:\u{e9}:#x"), None);
+    assert_eq!(parse_membership_key("5:alice"), None);
+}
+
+#[test]
+fn membership_record_versions() {
+    use crdb::Schema;
+
+    let rec = MembershipRecord { since: Clock::at(100), status: MembershipStatus::Present };
+    let encoded = MembershipSchema.encode(&rec);
+    assert_eq!(encoded.0[0], MEMBERSHIP_VERSION);
+    assert_eq!(MembershipSchema.decode(&encoded), rec);
+
+    // records written before versioning
+    let mut old = vec![b'L'];
+    old.extend(Clock::at(100).to_bytes());
+    let decoded = MembershipSchema.decode(&crdb::Record(old));
+    assert_eq!(decoded.since, Clock::at(100));
+    assert_eq!(decoded.status, MembershipStatus::Left);
+
+    // records from a newer format lose to anything
+    let newer = crdb::Record::versioned(MEMBERSHIP_VERSION + 1, b"???");
+    let decoded = MembershipSchema.decode(&newer);
+    assert_eq!(decoded.since, Clock::neg_infty());
+    assert_eq!(MembershipSchema.merge(decoded, rec.clone()), rec);
+
+    // as do malformed records, whatever their version
+    let mut bad_status = vec![b'X'];
+    bad_status.extend(Clock::at(100).to_bytes());
+    for data in &[b"".to_vec(), bad_status, vec![b'P', 1, 2, 3]] {
+        for version in 0..MEMBERSHIP_VERSION + 1 {
+            let decoded = MembershipSchema.decode(&crdb::Record::versioned(version, data));
+            assert_eq!(decoded.since, Clock::neg_infty());
+            assert_eq!(decoded.status, MembershipStatus::Left);
+        }
+    }
+    let mut v0 = vec![0, b'P'];
+    v0.extend(Clock::at(100).to_bytes());
+    assert_eq!(MembershipSchema.decode(&crdb::Record(v0)).since, Clock::neg_infty());
+    assert_eq!(MembershipSchema.decode(&crdb::Record(Vec::new())).since, Clock::neg_infty());
+}
+
+#[test]
+fn membership_with_colons() {
+    let mut core = Core::new().unwrap();
+    let mut world = World::new(&core.handle(), Sid::identity());
+
+    core.run(world.join_user("#a:b".to_string(), "x:y".to_string())).unwrap();
+    core.run(world.join_user("#a:b".to_string(), "z".to_string())).unwrap();
+
+    let mut members = world.channel_members("#a:b");
+    members.sort();
+    assert_eq!(members, vec!["x:y", "z"]);
+    assert_eq!(world.user_channels("x:y"), vec!["#a:b"]);
+
+    // a malformed key is skipped rather than bringing down the reactor
+    {
+        use crdb::Schema;
+        let mut tx = crdb::RawTransaction::new();
+        let rec = MembershipSchema.encode(&MembershipRecord::present(Sid::identity()));
+        tx.add("m".to_string(), "bogus".to_string(), rec);
+        let completion = world.inner.borrow_mut().db.commit_raw(tx);
+        core.run(completion).unwrap();
+    }
+
+    core.run(world.part_user("#a:b".to_string(), "x:y".to_string())).unwrap();
+    assert_eq!(world.channel_members("#a:b"), vec!["z"]);
+}
+
+#[test]
+fn events_carry_display_names() {
+    use crdb::Schema;
+    use futures::Stream;
+
+    let mut core = Core::new().unwrap();
+    let mut world = World::new(&core.handle(), Sid::identity());
+    let events = world.events();
+
+    core.run(world.add_chan("#Oxide".to_string())).unwrap();
+    core.run(world.add_chan("#OXIDE".to_string())).unwrap();
+    world.message("#oxide".to_string(), "bob".to_string(), "hi".to_string());
+
+    let events = core.run(events.take(2).collect()).unwrap();
+    let events: Vec<String> = events.iter().map(|ev| format!("{:?}", **ev)).collect();
+    assert_eq!(events, vec![
+        "ChanAdded(\"#Oxide\")",
+        "Message(\"#Oxide\", \"bob\", \"hi\")",
+    ]);
+
+    let rec = world.inner.borrow().c_table.get("#oxide").unwrap();
+    assert_eq!(rec.name, "#Oxide");
+    assert_eq!(ChannelSchema.decode(&ChannelSchema.encode(&rec)), rec);
+}
+
+#[test]
+fn channel_events_carry_display_names() {
+    use futures::Stream;
+
+    let mut core = Core::new().unwrap();
+    let mut world = World::new(&core.handle(), Sid::identity());
+    let events = world.events();
+
+    core.run(world.add_chan("#Foo".to_string())).unwrap();
+    core.run(world.join_user("#foo".to_string(), "alice".to_string())).unwrap();
+    core.run(world.set_topic("#FOO".to_string(), "hello".to_string())).unwrap();
+    core.run(world.set_mode("#foo".to_string(), 'n', true)).unwrap();
+    core.run(world.part_user("#foo".to_string(), "alice".to_string())).unwrap();
+
+    let events = core.run(events.take(5).collect()).unwrap();
+    let events: Vec<String> = events.iter().map(|ev| format!("{:?}", **ev)).collect();
+    assert_eq!(events, vec![
+        "ChanAdded(\"#Foo\")",
+        "UserJoin(\"#Foo\", \"alice\")",
+        "TopicChange(\"#Foo\", \"hello\")",
+        "ModeChange(\"#Foo\", 'n', true)",
+        "UserPart(\"#Foo\", \"alice\")",
+    ]);
+}
+
+#[test]
+fn removed_channels_are_forgotten() {
+    let mut core = Core::new().unwrap();
+    let mut world = World::new(&core.handle(), Sid::identity());
+
+    core.run(world.add_chan("#oxide".to_string())).unwrap();
+    assert_eq!(world.stats().chans, 1);
+
+    let completion = {
+        let inner = &mut *world.inner.borrow_mut();
+        let mut tx = inner.c_table.open();
+        tx.remove("#oxide".to_string());
+        inner.db.commit(tx)
+    };
+    core.run(completion).unwrap();
+    assert_eq!(world.stats().chans, 0);
+
+    core.run(world.add_chan("#oxide".to_string())).unwrap();
+    assert_eq!(world.stats().chans, 1);
+}
+
+#[test]
+fn concurrent_topics_converge() {
+    use crdb::Schema;
+    use time::Timespec;
+
+    let now = Timespec { sec: 1500000000, nsec: 0 };
+    let older = ChannelRecord {
+        topic: Some("older".to_string()),
+        topic_set: Clock::with_time(now, Sid::new("0ZZ")),
+        .. ChannelRecord::new()
+    };
+    let newer = ChannelRecord {
+        topic: Some("newer".to_string()),
+        topic_set: Clock::with_time(Timespec { sec: now.sec, nsec: 1 }, Sid::new("0AA")),
+        .. ChannelRecord::new()
+    };
+
+    let on_a = ChannelSchema.merge(older.clone(), newer.clone());
+    let on_b = ChannelSchema.merge(newer.clone(), older.clone());
+    assert_eq!(on_a, newer);
+    assert_eq!(on_b, newer);
+
+    // creating the channel again doesn't clobber the topic
+    assert_eq!(ChannelSchema.merge(ChannelRecord::new(), newer.clone()), newer);
+    assert_eq!(ChannelSchema.merge(newer.clone(), ChannelRecord::new()), newer);
+
+    for rec in &[ChannelRecord::new(), newer] {
+        assert_eq!(ChannelSchema.decode(&ChannelSchema.encode(rec)), *rec);
+    }
+}
+
+#[test]
+fn set_away_fires_events() {
+    use futures::Stream;
+
+    let mut core = Core::new().unwrap();
+    let mut world = World::new(&core.handle(), Sid::identity());
+    let events = world.events();
+
+    // unknown users aren't created by going away
+    core.run(world.set_away("bob".to_string(), Some("gone".to_string()))).unwrap();
+    assert_eq!(world.away("bob"), None);
+
+    core.run(world.add_user("alice".to_string())).unwrap();
+    core.run(world.set_away("alice".to_string(), Some("lunch".to_string()))).unwrap();
+    assert_eq!(world.away("alice"), Some("lunch".to_string()));
+    core.run(world.set_away("alice".to_string(), None)).unwrap();
+    assert_eq!(world.away("alice"), None);
+
+    let events = core.run(events.take(3).collect()).unwrap();
+    let events: Vec<String> = events.iter().map(|ev| format!("{:?}", **ev)).collect();
+    assert_eq!(events, vec![
+        "UserAdded(\"alice\")",
+        "AwayChange(\"alice\", Some(\"lunch\"))",
+        "AwayChange(\"alice\", None)",
+    ]);
+}
+
+#[test]
+fn concurrent_away_converges() {
+    use crdb::Schema;
+    use time::Timespec;
+
+    let now = Timespec { sec: 1500000000, nsec: 0 };
+    let older = UserRecord {
+        away: Some("older".to_string()),
+        away_set: Clock::with_time(now, Sid::new("0ZZ")),
+    };
+    let newer = UserRecord {
+        away: None,
+        away_set: Clock::with_time(Timespec { sec: now.sec, nsec: 1 }, Sid::new("0AA")),
+    };
+
+    assert_eq!(UserSchema.merge(older.clone(), newer.clone()), newer);
+    assert_eq!(UserSchema.merge(newer.clone(), older.clone()), newer);
+
+    // adding the user again doesn't clobber the away message
+    assert_eq!(UserSchema.merge(UserRecord::new(), older.clone()), older);
+    assert_eq!(UserSchema.merge(older.clone(), UserRecord::new()), older);
+
+    for rec in &[UserRecord::new(), older, newer] {
+        assert_eq!(UserSchema.decode(&UserSchema.encode(rec)), *rec);
+    }
+}
+
+#[test]
+fn concurrent_modes_converge() {
+    use crdb::Schema;
+    use time::Timespec;
+
+    let now = Timespec { sec: 1500000000, nsec: 0 };
+    let later = Timespec { sec: now.sec, nsec: 1 };
+    let flag = |enabled, time, sid| {
+        ModeFlag { enabled: enabled, set: Clock::with_time(time, sid) }
+    };
+
+    // distinct modes set on two servers both take effect
+    let mut on_a = ChannelRecord::new();
+    on_a.modes.insert('t', flag(true, now, Sid::new("0AA")));
+    let mut on_b = ChannelRecord::new();
+    on_b.modes.insert('n', flag(true, now, Sid::new("0BB")));
+
+    let merged = ChannelSchema.merge(on_a.clone(), on_b.clone());
+    assert_eq!(merged, ChannelSchema.merge(on_b.clone(), on_a.clone()));
+    assert_eq!(merged.modes.len(), 2);
+    assert!(merged.modes.values().all(|m| m.enabled));
+
+    // the same mode raced on two servers resolves by clock
+    let mut set = ChannelRecord::new();
+    set.modes.insert('i', flag(true, now, Sid::new("0ZZ")));
+    let mut cleared = ChannelRecord::new();
+    cleared.modes.insert('i', flag(false, later, Sid::new("0AA")));
+
+    assert_eq!(ChannelSchema.merge(set.clone(), cleared.clone()), cleared);
+    assert_eq!(ChannelSchema.merge(cleared.clone(), set.clone()), cleared);
+
+    // setting a topic doesn't clobber modes, and modes survive encoding
+    let topic = ChannelRecord::with_topic(Sid::identity(), "hi".to_string());
+    let merged = ChannelSchema.merge(merged, topic);
+    assert_eq!(merged.topic, Some("hi".to_string()));
+    assert_eq!(merged.modes.len(), 2);
+    assert_eq!(ChannelSchema.decode(&ChannelSchema.encode(&merged)), merged);
+}
+
+#[test]
+fn set_mode_fires_events() {
+    use futures::Stream;
+
+    let mut core = Core::new().unwrap();
+    let mut world = World::new(&core.handle(), Sid::identity());
+    let events = world.events();
+
+    // unknown channels aren't created by setting modes on them
+    core.run(world.set_mode("#nowhere".to_string(), 't', true)).unwrap();
+    assert_eq!(world.stats().chans, 0);
+    assert_eq!(world.modes("#nowhere"), "");
+
+    core.run(world.add_chan("#oxide".to_string())).unwrap();
+    core.run(world.set_mode("#oxide".to_string(), 't', true)).unwrap();
+    core.run(world.set_mode("#Oxide".to_string(), 'n', true)).unwrap();
+    core.run(world.set_mode("#oxide".to_string(), 't', false)).unwrap();
+    assert_eq!(world.modes("#oxide"), "n");
+
+    let events = core.run(events.take(4).collect()).unwrap();
+    let events: Vec<String> = events.iter().map(|ev| format!("{:?}", **ev)).collect();
+    assert_eq!(events, vec![
+        "ChanAdded(\"#oxide\")",
+        "ModeChange(\"#oxide\", 't', true)",
+        "ModeChange(\"#oxide\", 'n', true)",
+        "ModeChange(\"#oxide\", 't', false)",
+    ]);
+}
+
+#[test]
+fn set_topic_fires_event() {
+    use futures::Stream;
+
+    let mut core = Core::new().unwrap();
+    let mut world = World::new(&core.handle(), Sid::identity());
+    let events = world.events();
+
+    // unknown channels aren't created by setting their topic
+    core.run(world.set_topic("#nowhere".to_string(), "hello".to_string())).unwrap();
+    assert_eq!(world.topic("#nowhere"), None);
+    assert_eq!(world.stats().chans, 0);
+
+    core.run(world.add_chan("#oxide".to_string())).unwrap();
+    assert_eq!(world.topic("#oxide"), None);
+
+    core.run(world.set_topic("#oxide".to_string(), "hello".to_string())).unwrap();
+    assert_eq!(world.topic("#oxide"), Some("hello".to_string()));
+
+    let events = core.run(events.take(2).collect()).unwrap();
+    match *events[1] {
+        WorldEvent::TopicChange(ref chan, ref topic) => {
+            assert_eq!(chan, "#oxide");
+            assert_eq!(topic, "hello");
+        },
+        ref ev => panic!("unexpected event {:?}", ev),
+    }
+}
+
+#[test]
+fn membership_merge_is_deterministic() {
+    use crdb::Schema;
+    use time::Timespec;
+
+    // two servers record a join and a part in the same instant
+    let now = Timespec { sec: 1500000000, nsec: 0 };
+    let join = MembershipRecord {
+        since: Clock::with_time(now, Sid::new("0AA")),
+        status: MembershipStatus::Present,
+    };
+    let part = MembershipRecord {
+        since: Clock::with_time(now, Sid::new("0BB")),
+        status: MembershipStatus::Left,
+    };
+
+    let on_a = MembershipSchema.merge(join.clone(), part.clone());
+    let on_b = MembershipSchema.merge(part.clone(), join.clone());
+    assert_eq!(on_a, on_b);
+    assert_eq!(on_a, part);
+
+    let round_trip = MembershipSchema.decode(&MembershipSchema.encode(&on_a));
+    assert_eq!(round_trip, on_a);
 }